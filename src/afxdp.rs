@@ -0,0 +1,566 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// PacketIo backend using an AF_XDP socket.
+//
+// An XDP program on the interface redirects GRE over IPv4 frames into the socket's umem, and
+// passes everything else up to the kernel - so, as with AF_PACKET, the host side is
+// KernelPassthrough. The umem is split in two: the first half of the frames circulate through
+// the fill and RX rings, the second half through the TX and completion rings, so the receive and
+// transmit sides can be driven independently.
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use libc;
+
+use super::afpacket;
+use super::bpf;
+use super::bpf::{Asm, Insn, R0, R1, R2, R3, R4, R5, R6, B, H, JEQ, JGT, JNE, W};
+use super::error;
+use super::packetio::{PacketIo, TxSlot};
+
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+
+const XDP_COPY: u16 = 1 << 1;
+
+const FRAME_SIZE: u32 = 2048;
+// Frames per direction; also the size of every ring.
+const RING_SIZE: u32 = 2048;
+
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+/// The XDP program: redirect GRE in IPv4 or IPv6 to the socket for the receiving queue, pass
+/// anything else (or anything arriving on a queue with no socket) to the kernel.
+pub fn gre_redirect_program(xsks: RawFd) -> Vec<Insn> {
+    let mut asm = Asm::new();
+    asm.extend(&[bpf::mov64_reg(R6, R1),
+                 bpf::ldx(W, R2, R6, bpf::XDP_MD_DATA),
+                 bpf::ldx(W, R3, R6, bpf::XDP_MD_DATA_END),
+                 // Ethernet and a minimal IPv4 header must be present.
+                 bpf::mov64_reg(R4, R2),
+                 bpf::add64_imm(R4, 14 + 20)]);
+    asm.jump(bpf::jmp_reg(JGT, R4, R3, 0), "pass");
+    asm.push(bpf::ldx(H, R5, R2, 12));
+    asm.jump(bpf::jmp_imm(JEQ, R5, 0x86DDu16.to_be() as i32, 0), "ipv6");
+    asm.jump(bpf::jmp_imm(JNE, R5, 0x0800u16.to_be() as i32, 0), "pass");
+    // IPv4 protocol field.
+    asm.push(bpf::ldx(B, R5, R2, 14 + 9));
+    asm.jump(bpf::jmp_imm(JNE, R5, 47, 0), "pass");
+    asm.jump(bpf::ja(0), "redirect");
+    // IPv6 next header field: GRE after extension headers is left to the kernel.
+    asm.label("ipv6");
+    asm.extend(&[bpf::mov64_reg(R4, R2), bpf::add64_imm(R4, 14 + 40)]);
+    asm.jump(bpf::jmp_reg(JGT, R4, R3, 0), "pass");
+    asm.push(bpf::ldx(B, R5, R2, 14 + 6));
+    asm.jump(bpf::jmp_imm(JNE, R5, 47, 0), "pass");
+    asm.label("redirect");
+    asm.push(bpf::ldx(W, R2, R6, bpf::XDP_MD_RX_QUEUE_INDEX));
+    asm.extend(&bpf::ld_map_fd(R1, xsks));
+    asm.extend(&[bpf::mov64_imm(R3, bpf::XDP_PASS as i32),
+                 bpf::call(bpf::BPF_FUNC_REDIRECT_MAP),
+                 bpf::exit()]);
+    asm.label("pass");
+    asm.extend(&[bpf::mov64_imm(R0, bpf::XDP_PASS as i32), bpf::exit()]);
+    asm.finish()
+}
+
+/// The socket, its umem, and the XDP program feeding it.
+struct Xsk {
+    fd: RawFd,
+    umem: *mut u8,
+    // Kept alive for as long as the socket: dropping the link detaches the program.
+    _link: bpf::Fd,
+    _program: bpf::Program,
+    _xsks: bpf::Map,
+}
+
+impl Drop for Xsk {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+            libc::munmap(self.umem as *mut libc::c_void,
+                         (FRAME_SIZE * RING_SIZE * 2) as usize);
+        }
+    }
+}
+
+/// One of the four single producer / single consumer rings shared with the kernel.
+struct XskRing {
+    map: *mut u8,
+    map_len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: *mut u8,
+    // Local copies: ours is published at sync, theirs is refreshed when we run out.
+    cached_prod: u32,
+    cached_cons: u32,
+}
+
+impl XskRing {
+    fn new(fd: RawFd,
+           offsets: &XdpRingOffset,
+           pgoff: libc::off_t,
+           entry_size: usize)
+           -> Result<XskRing, error::BrokenRail> {
+        let map_len = offsets.desc as usize + RING_SIZE as usize * entry_size;
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       map_len,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_POPULATE,
+                       fd,
+                       pgoff)
+        };
+        if map == libc::MAP_FAILED {
+            return Err(error::BrokenRail::IO(io::Error::last_os_error()));
+        }
+        let map = map as *mut u8;
+        unsafe {
+            let producer = map.offset(offsets.producer as isize) as *const AtomicU32;
+            let consumer = map.offset(offsets.consumer as isize) as *const AtomicU32;
+            Ok(XskRing {
+                map: map,
+                map_len: map_len,
+                producer: producer,
+                consumer: consumer,
+                desc: map.offset(offsets.desc as isize),
+                cached_prod: (*producer).load(Ordering::Relaxed),
+                cached_cons: (*consumer).load(Ordering::Relaxed),
+            })
+        }
+    }
+
+    fn entry<T>(&self, idx: u32) -> *mut T {
+        unsafe { (self.desc as *mut T).offset((idx & (RING_SIZE - 1)) as isize) }
+    }
+
+    /// Consumer side: are there entries left to read?
+    fn available(&mut self) -> bool {
+        if self.cached_cons == self.cached_prod {
+            self.cached_prod = unsafe { (*self.producer).load(Ordering::Acquire) };
+        }
+        self.cached_cons != self.cached_prod
+    }
+
+    /// Producer side: is there room to write an entry?
+    fn has_room(&mut self) -> bool {
+        if self.cached_prod.wrapping_sub(self.cached_cons) == RING_SIZE {
+            self.cached_cons = unsafe { (*self.consumer).load(Ordering::Acquire) };
+        }
+        self.cached_prod.wrapping_sub(self.cached_cons) != RING_SIZE
+    }
+
+    fn publish_producer(&self) {
+        unsafe { (*self.producer).store(self.cached_prod, Ordering::Release) }
+    }
+
+    fn publish_consumer(&self) {
+        unsafe { (*self.consumer).store(self.cached_cons, Ordering::Release) }
+    }
+}
+
+impl Drop for XskRing {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.map_len);
+        }
+    }
+}
+
+fn setsockopt<T>(fd: RawFd, option: libc::c_int, value: &T) -> Result<(), error::BrokenRail> {
+    let rv = unsafe {
+        libc::setsockopt(fd,
+                         SOL_XDP,
+                         option,
+                         value as *const T as *const libc::c_void,
+                         mem::size_of::<T>() as libc::socklen_t)
+    };
+    if rv < 0 {
+        Err(error::BrokenRail::IO(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+/// The number of receive queues device has, from sysfs.
+fn rx_queues(device: &str) -> Result<usize, error::BrokenRail> {
+    let queues = try!(fs::read_dir(format!("/sys/class/net/{}/queues", device)));
+    let mut count = 0;
+    for queue in queues {
+        if try!(queue).file_name().to_string_lossy().starts_with("rx-") {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Open an AF_XDP socket on queue 0 of device, attach the GRE redirect program in the given mode
+/// (bpf::XDP_FLAGS_SKB_MODE or bpf::XDP_FLAGS_DRV_MODE) and return the wire RX and TX sides.
+/// Only one queue is serviced, so devices with more are refused rather than have GRE arriving on
+/// the others silently reach the kernel.
+///
+/// Generic (SKB) mode copies packets into the umem; it works on any device, including veth.
+pub fn open(device: &str, xdp_flags: u32) -> Result<(AfXdpRx, AfXdpTx), error::BrokenRail> {
+    let queues = try!(rx_queues(device));
+    if queues > 1 {
+        let msg = format!("afxdp services one queue but {} has {}: reduce it to one with \
+                           ethtool -L {} combined 1",
+                          device,
+                          queues,
+                          device);
+        return Err(error::BrokenRail::Configuration(msg));
+    }
+    let queue = 0u32;
+    let ifindex = try!(afpacket::ifindex(device));
+    let xsks = try!(bpf::Map::new(bpf::BPF_MAP_TYPE_XSKMAP, 4, 4, 64));
+    let program = try!(bpf::Program::load_xdp("rr_gre_redirect", &gre_redirect_program(xsks.fd.0)));
+
+    let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW, 0) };
+    if fd < 0 {
+        return Err(error::BrokenRail::IO(io::Error::last_os_error()));
+    }
+    let fd = bpf::Fd(fd);
+    let umem_len = (FRAME_SIZE * RING_SIZE * 2) as usize;
+    let umem = unsafe {
+        libc::mmap(ptr::null_mut(),
+                   umem_len,
+                   libc::PROT_READ | libc::PROT_WRITE,
+                   libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                   -1,
+                   0)
+    };
+    if umem == libc::MAP_FAILED {
+        return Err(error::BrokenRail::IO(io::Error::last_os_error()));
+    }
+    let umem = umem as *mut u8;
+    let reg = XdpUmemReg {
+        addr: umem as u64,
+        len: umem_len as u64,
+        chunk_size: FRAME_SIZE,
+        headroom: 0,
+        flags: 0,
+        tx_metadata_len: 0,
+    };
+    if let Err(err) = setsockopt(fd.0, XDP_UMEM_REG, &reg) {
+        unsafe {
+            libc::munmap(umem as *mut libc::c_void, umem_len);
+        }
+        return Err(err);
+    }
+    // From here the Xsk owns fd and umem; the program is attached once the socket is ready.
+    let mut xsk = Xsk {
+        fd: fd.0,
+        umem: umem,
+        _link: bpf::Fd(-1),
+        _program: program,
+        _xsks: xsks,
+    };
+    mem::forget(fd);
+    for &ring in &[XDP_UMEM_FILL_RING, XDP_UMEM_COMPLETION_RING, XDP_RX_RING, XDP_TX_RING] {
+        try!(setsockopt(xsk.fd, ring, &RING_SIZE));
+    }
+    let mut offsets = XdpMmapOffsets::default();
+    let mut optlen = mem::size_of::<XdpMmapOffsets>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockopt(xsk.fd,
+                         SOL_XDP,
+                         XDP_MMAP_OFFSETS,
+                         &mut offsets as *mut XdpMmapOffsets as *mut libc::c_void,
+                         &mut optlen)
+    } < 0 {
+        return Err(error::BrokenRail::IO(io::Error::last_os_error()));
+    }
+    let mut fill = try!(XskRing::new(xsk.fd, &offsets.fr, XDP_UMEM_PGOFF_FILL_RING, 8));
+    let comp = try!(XskRing::new(xsk.fd, &offsets.cr, XDP_UMEM_PGOFF_COMPLETION_RING, 8));
+    let rx = try!(XskRing::new(xsk.fd, &offsets.rx, XDP_PGOFF_RX_RING, 16));
+    let tx = try!(XskRing::new(xsk.fd, &offsets.tx, XDP_PGOFF_TX_RING, 16));
+    // The receive half of the umem goes straight into the fill ring.
+    for frame in 0..RING_SIZE {
+        unsafe {
+            *fill.entry::<u64>(fill.cached_prod) = (frame * FRAME_SIZE) as u64;
+        }
+        fill.cached_prod = fill.cached_prod.wrapping_add(1);
+    }
+    fill.publish_producer();
+    let addr = SockaddrXdp {
+        sxdp_family: AF_XDP as u16,
+        sxdp_flags: XDP_COPY,
+        sxdp_ifindex: ifindex,
+        sxdp_queue_id: queue,
+        sxdp_shared_umem_fd: 0,
+    };
+    if unsafe {
+        libc::bind(xsk.fd,
+                   &addr as *const SockaddrXdp as *const libc::sockaddr,
+                   mem::size_of::<SockaddrXdp>() as libc::socklen_t)
+    } < 0 {
+        return Err(error::BrokenRail::IO(io::Error::last_os_error()));
+    }
+    let key = queue.to_ne_bytes();
+    let value = (xsk.fd as u32).to_ne_bytes();
+    try!(xsk._xsks.update(&key, &value));
+    xsk._link = try!(xsk._program.attach(ifindex, xdp_flags));
+
+    let xsk = Rc::new(xsk);
+    let free = (RING_SIZE..RING_SIZE * 2).map(|frame| (frame * FRAME_SIZE) as u64).collect();
+    Ok((AfXdpRx {
+        xsk: xsk.clone(),
+        rx: rx,
+        fill: fill,
+        claimed: false,
+    },
+        AfXdpTx {
+        xsk: xsk,
+        tx: tx,
+        comp: comp,
+        free: free,
+        queued: false,
+        slot: AfXdpTxSlot { desc: ptr::null_mut() },
+    }))
+}
+
+/// Receive side: the RX and fill rings.
+pub struct AfXdpRx {
+    xsk: Rc<Xsk>,
+    rx: XskRing,
+    fill: XskRing,
+    // The descriptor at rx.cached_cons has been handed out but not consumed.
+    claimed: bool,
+}
+
+impl AfXdpRx {
+    /// Consume the claimed descriptor, recycling its frame through the fill ring.
+    fn consume(&mut self) {
+        let addr = unsafe { (*self.rx.entry::<XdpDesc>(self.rx.cached_cons)).addr };
+        self.rx.cached_cons = self.rx.cached_cons.wrapping_add(1);
+        // The fill ring holds every receive frame, so there is always room.
+        unsafe {
+            *self.fill.entry::<u64>(self.fill.cached_prod) = addr & !(FRAME_SIZE as u64 - 1);
+        }
+        self.fill.cached_prod = self.fill.cached_prod.wrapping_add(1);
+        self.claimed = false;
+    }
+}
+
+impl PacketIo for AfXdpRx {
+    fn rx_next(&mut self) -> Option<&mut [u8]> {
+        if self.claimed {
+            self.consume();
+        }
+        if !self.rx.available() {
+            return None;
+        }
+        self.claimed = true;
+        unsafe {
+            let desc = self.rx.entry::<XdpDesc>(self.rx.cached_cons);
+            Some(slice::from_raw_parts_mut(self.xsk.umem.offset((*desc).addr as isize),
+                                           (*desc).len as usize))
+        }
+    }
+
+    fn rx_give_back(&mut self) {
+        self.claimed = false;
+    }
+
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])> {
+        None
+    }
+
+    fn tx_give_back(&mut self) {}
+
+    fn sync(&mut self) -> Result<(), error::BrokenRail> {
+        if self.claimed {
+            self.consume();
+        }
+        self.rx.publish_consumer();
+        self.fill.publish_producer();
+        Ok(())
+    }
+
+    fn fd(&self) -> RawFd {
+        self.xsk.fd
+    }
+}
+
+pub struct AfXdpTxSlot {
+    desc: *mut XdpDesc,
+}
+
+impl TxSlot for AfXdpTxSlot {
+    fn set_len(&mut self, len: usize) {
+        unsafe {
+            (*self.desc).len = len as u32;
+        }
+    }
+}
+
+/// Transmit side: the TX and completion rings.
+pub struct AfXdpTx {
+    xsk: Rc<Xsk>,
+    tx: XskRing,
+    comp: XskRing,
+    // Transmit frames not in flight.
+    free: Vec<u64>,
+    queued: bool,
+    slot: AfXdpTxSlot,
+}
+
+impl PacketIo for AfXdpTx {
+    fn rx_next(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn rx_give_back(&mut self) {}
+
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])> {
+        if self.free.is_empty() {
+            while self.comp.available() {
+                self.free.push(unsafe { *self.comp.entry::<u64>(self.comp.cached_cons) });
+                self.comp.cached_cons = self.comp.cached_cons.wrapping_add(1);
+            }
+            self.comp.publish_consumer();
+        }
+        if !self.tx.has_room() {
+            return None;
+        }
+        let addr = match self.free.pop() {
+            None => return None,
+            Some(addr) => addr,
+        };
+        let desc = self.tx.entry::<XdpDesc>(self.tx.cached_prod);
+        unsafe {
+            *desc = XdpDesc {
+                addr: addr,
+                len: 0,
+                options: 0,
+            };
+        }
+        self.tx.cached_prod = self.tx.cached_prod.wrapping_add(1);
+        self.queued = true;
+        self.slot.desc = desc;
+        let buf = unsafe {
+            slice::from_raw_parts_mut(self.xsk.umem.offset(addr as isize), FRAME_SIZE as usize)
+        };
+        Some((&mut self.slot, buf))
+    }
+
+    fn tx_give_back(&mut self) {
+        // The descriptor is not published until sync, so it can simply be taken back.
+        self.tx.cached_prod = self.tx.cached_prod.wrapping_sub(1);
+        self.free.push(unsafe { (*self.slot.desc).addr });
+    }
+
+    fn sync(&mut self) -> Result<(), error::BrokenRail> {
+        if !self.queued {
+            return Ok(());
+        }
+        self.queued = false;
+        self.tx.publish_producer();
+        // Copy mode only transmits when asked to.
+        let rv = unsafe {
+            libc::sendto(self.xsk.fd,
+                         ptr::null(),
+                         0,
+                         libc::MSG_DONTWAIT,
+                         ptr::null(),
+                         0)
+        };
+        if rv < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => (),
+                _ => return Err(error::BrokenRail::IO(err)),
+            }
+        }
+        Ok(())
+    }
+
+    fn fd(&self) -> RawFd {
+        self.xsk.fd
+    }
+}
+
+#[test]
+fn queues() {
+    assert_eq!(rx_queues("lo").unwrap(), 1);
+    assert!(rx_queues("rr-no-such-device").is_err());
+}
+
+#[test]
+fn redirect_program_verifies() {
+    use std::net::Ipv4Addr;
+    // Loading programs needs privileges.
+    if unsafe { libc::geteuid() } != 0 {
+        println!("skipping redirect_program_verifies: loading XDP programs needs root");
+        return;
+    }
+    let xsks = bpf::Map::new(bpf::BPF_MAP_TYPE_XSKMAP, 4, 4, 1).unwrap();
+    let program = bpf::Program::load_xdp("rr_test", &gre_redirect_program(xsks.fd.0)).unwrap();
+    // With no socket registered GRE falls back to the kernel, as does everything else.
+    let frame = ::tests::gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+    assert_eq!(program.test_run(&frame).unwrap().0, bpf::XDP_PASS);
+    assert_eq!(program.test_run(&frame[..20]).unwrap().0, bpf::XDP_PASS);
+    let frame = ::tests::ipv6_gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+    assert_eq!(program.test_run(&frame).unwrap().0, bpf::XDP_PASS);
+}
@@ -1,8 +1,9 @@
 // Copyright (c) 2016 Robert Collins. Licensed under the Apache-2.0 license.
 use std::collections::BTreeMap;
-use std::net::{Ipv4Addr, IpAddr};
+use std::net::IpAddr;
 #[cfg(test)]
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 
 use pnet::util::MacAddr;
@@ -15,13 +16,22 @@ use pnetlink::packet::route::link::Links;
 use pnetlink::packet::route::neighbour::Neighbour;
 use pnetlink::packet::route::neighbour::Neighbours;
 
+/// Resolves next hop addresses to MAC addresses for the forwarding path: ARP results for IPv4,
+/// and neighbour discovery results for IPv6.
+pub trait Resolver {
+    fn lookup(&mut self, addr: &IpAddr) -> Option<MacAddr>;
+
+    /// Pick up any newly available results. Called between batches of packets.
+    fn refresh(&mut self) {}
+}
+
 pub struct CacheEntry {
     pub mac: MacAddr,
     pub expires: SystemTime,
 }
 
 pub struct Cache {
-    pub entries: BTreeMap<Ipv4Addr, CacheEntry>,
+    pub entries: BTreeMap<IpAddr, CacheEntry>,
     pub link: Link,
     pub netlink: NetlinkConnection,
 }
@@ -35,7 +45,7 @@ impl Cache {
         }
     }
 
-    pub fn lookup<'a>(&'a mut self, addr: &Ipv4Addr) -> Option<MacAddr> {
+    pub fn lookup<'a>(&'a mut self, addr: &IpAddr) -> Option<MacAddr> {
         if let Some(result) = self.entries.get(addr).map(|e| e.mac) {
             return Some(result);
         } else {
@@ -47,7 +57,9 @@ impl Cache {
                 self.netlink.iter_neighbours(Some(&self.link)).unwrap().collect::<Vec<_>>();
             for neighbour in neighbours {
                 if let Some(mac) = neighbour.get_ll_addr() {
-                    if let Some(IpAddr::V4(ipaddr)) = neighbour.get_destination() {
+                    // The kernel's neighbour table holds both ARP and neighbour discovery
+                    // results.
+                    if let Some(ipaddr) = neighbour.get_destination() {
                         //           self.add(&ipaddr, &mac);
                         entries.insert(ipaddr,
                                        CacheEntry {
@@ -68,7 +80,7 @@ impl Cache {
         }
     }
 
-    pub fn add(&mut self, ip: &Ipv4Addr, mac: &MacAddr) {
+    pub fn add(&mut self, ip: &IpAddr, mac: &MacAddr) {
         self.entries.insert(*ip,
                             CacheEntry {
                                 mac: *mac,
@@ -78,7 +90,7 @@ impl Cache {
 
     pub fn expire(&mut self) {
         let now = SystemTime::now();
-        let mut expired: Vec<Ipv4Addr> = Vec::new();
+        let mut expired: Vec<IpAddr> = Vec::new();
         for (ip, entry) in self.entries.iter_mut() {
             if entry.expires < now {
                 println!("expiring");
@@ -91,13 +103,73 @@ impl Cache {
     }
 }
 
+impl Resolver for Cache {
+    fn lookup(&mut self, addr: &IpAddr) -> Option<MacAddr> {
+        Cache::lookup(self, addr)
+    }
+}
+
+/// ARP and neighbour discovery results published for forwarding threads.
+///
+/// A Cache owns a netlink socket and mutates itself on every miss, so rather than having threads
+/// contend for one, a single thread resolves the addresses the threads need and publishes the
+/// results here; each thread reads its own snapshot through a View.
+pub struct Published {
+    entries: Mutex<Arc<BTreeMap<IpAddr, MacAddr>>>,
+}
+
+impl Published {
+    pub fn new() -> Published {
+        Published { entries: Mutex::new(Arc::new(BTreeMap::new())) }
+    }
+
+    /// Replace the published results with the cache's current results for addrs.
+    pub fn publish<'a, I>(&self, cache: &mut Cache, addrs: I)
+        where I: Iterator<Item = &'a IpAddr>
+    {
+        let mut entries = BTreeMap::new();
+        for addr in addrs {
+            if let Some(mac) = cache.lookup(addr) {
+                entries.insert(*addr, mac);
+            }
+        }
+        *self.entries.lock().unwrap() = Arc::new(entries);
+    }
+}
+
+/// One thread's snapshot of Published results.
+pub struct View {
+    published: Arc<Published>,
+    entries: Arc<BTreeMap<IpAddr, MacAddr>>,
+}
+
+impl View {
+    pub fn new(published: Arc<Published>) -> View {
+        let entries = published.entries.lock().unwrap().clone();
+        View {
+            published: published,
+            entries: entries,
+        }
+    }
+}
+
+impl Resolver for View {
+    fn lookup(&mut self, addr: &IpAddr) -> Option<MacAddr> {
+        self.entries.get(addr).cloned()
+    }
+
+    fn refresh(&mut self) {
+        self.entries = self.published.entries.lock().unwrap().clone();
+    }
+}
+
 #[test]
 fn add_and_lookup_expire() {
     let mut netlink = NetlinkConnection::new();
     let nl_link = netlink.get_link_by_name("eth0").unwrap().unwrap();
     let mut c = Cache::new(nl_link, netlink);
     let target_mac = MacAddr::from_str("aa:aa:aa:aa:aa:aa").unwrap();
-    let some_ip = Ipv4Addr::from_str("127.0.0.2").unwrap();
+    let some_ip = IpAddr::from_str("127.0.0.2").unwrap();
     let past = SystemTime::now() - Duration::new(1, 0);
     c.add(&some_ip, &target_mac);
     c.lookup(&some_ip).unwrap();
@@ -114,3 +186,24 @@ fn add_and_lookup_expire() {
         panic!("Failed to expire cached entry.");
     }
 }
+
+#[test]
+fn publish_to_view() {
+    let mut netlink = NetlinkConnection::new();
+    let nl_link = netlink.get_link_by_name("lo").unwrap().unwrap();
+    let mut c = Cache::new(nl_link, netlink);
+    let target_mac = MacAddr::from_str("aa:aa:aa:aa:aa:aa").unwrap();
+    let known = IpAddr::from_str("192.0.2.1").unwrap();
+    let known_v6 = IpAddr::from_str("2001:db8::1").unwrap();
+    let unknown = IpAddr::from_str("192.0.2.2").unwrap();
+    let published = Arc::new(Published::new());
+    let mut view = View::new(published.clone());
+    c.add(&known, &target_mac);
+    c.add(&known_v6, &target_mac);
+    published.publish(&mut c, [known, known_v6, unknown].iter());
+    assert_eq!(Resolver::lookup(&mut view, &known), None);
+    view.refresh();
+    assert_eq!(Resolver::lookup(&mut view, &known), Some(target_mac));
+    assert_eq!(Resolver::lookup(&mut view, &known_v6), Some(target_mac));
+    assert_eq!(Resolver::lookup(&mut view, &unknown), None);
+}
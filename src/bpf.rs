@@ -0,0 +1,525 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// Just enough of the bpf(2) syscall to load XDP programs, manage their maps and attach them to
+// interfaces. Programs are assembled in Rust rather than compiled from C so that building rusty
+// rail doesn't need clang.
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use libc;
+
+use super::error;
+
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_LOOKUP_ELEM: libc::c_int = 1;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_int = 3;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_PROG_TEST_RUN: libc::c_int = 10;
+const BPF_LINK_CREATE: libc::c_int = 28;
+
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+
+pub const BPF_MAP_TYPE_HASH: u32 = 1;
+pub const BPF_MAP_TYPE_ARRAY: u32 = 2;
+pub const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+
+pub const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+pub const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+
+pub const XDP_DROP: u32 = 1;
+pub const XDP_PASS: u32 = 2;
+pub const XDP_TX: u32 = 3;
+pub const XDP_REDIRECT: u32 = 4;
+
+// Helper function ids.
+pub const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+pub const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+
+// Registers.
+pub const R0: u8 = 0;
+pub const R1: u8 = 1;
+pub const R2: u8 = 2;
+pub const R3: u8 = 3;
+pub const R4: u8 = 4;
+pub const R5: u8 = 5;
+pub const R6: u8 = 6;
+pub const R7: u8 = 7;
+pub const R8: u8 = 8;
+pub const R9: u8 = 9;
+pub const R10: u8 = 10;
+
+// Load/store sizes.
+pub const W: u8 = 0x00;
+pub const H: u8 = 0x08;
+pub const B: u8 = 0x10;
+pub const DW: u8 = 0x18;
+
+// Offsets into struct xdp_md.
+pub const XDP_MD_DATA: i16 = 0;
+pub const XDP_MD_DATA_END: i16 = 4;
+pub const XDP_MD_RX_QUEUE_INDEX: i16 = 16;
+
+// ALU operations.
+pub const ADD: u8 = 0x00;
+pub const SUB: u8 = 0x10;
+pub const MUL: u8 = 0x20;
+pub const OR: u8 = 0x40;
+pub const AND: u8 = 0x50;
+pub const LSH: u8 = 0x60;
+pub const RSH: u8 = 0x70;
+pub const MOD: u8 = 0x90;
+pub const XOR: u8 = 0xa0;
+
+// Jump conditions.
+pub const JEQ: u8 = 0x10;
+pub const JGT: u8 = 0x20;
+pub const JGE: u8 = 0x30;
+pub const JSET: u8 = 0x40;
+pub const JNE: u8 = 0x50;
+
+/// A single eBPF instruction, as struct bpf_insn.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn {
+        code: code,
+        regs: (src << 4) | dst,
+        off: off,
+        imm: imm,
+    }
+}
+
+/// dst = src
+pub fn mov64_reg(dst: u8, src: u8) -> Insn {
+    insn(0xbf, dst, src, 0, 0)
+}
+
+/// dst = imm
+pub fn mov64_imm(dst: u8, imm: i32) -> Insn {
+    insn(0xb7, dst, 0, 0, imm)
+}
+
+/// dst += imm
+pub fn add64_imm(dst: u8, imm: i32) -> Insn {
+    insn(0x07, dst, 0, 0, imm)
+}
+
+/// dst = *(size *)(src + off)
+pub fn ldx(size: u8, dst: u8, src: u8, off: i16) -> Insn {
+    insn(0x61 | size, dst, src, off, 0)
+}
+
+/// dst = dst op imm
+pub fn alu64_imm(op: u8, dst: u8, imm: i32) -> Insn {
+    insn(0x07 | op, dst, 0, 0, imm)
+}
+
+/// dst = dst op src
+pub fn alu64_reg(op: u8, dst: u8, src: u8) -> Insn {
+    insn(0x0f | op, dst, src, 0, 0)
+}
+
+/// 32 bit dst = dst op imm, zeroing the upper half of dst.
+pub fn alu32_imm(op: u8, dst: u8, imm: i32) -> Insn {
+    insn(0x04 | op, dst, 0, 0, imm)
+}
+
+/// Convert the low 16, 32 or 64 bits of dst between host and network byte order.
+pub fn be(dst: u8, bits: i32) -> Insn {
+    insn(0xdc, dst, 0, 0, bits)
+}
+
+/// Convert the low 16, 32 or 64 bits of dst between host and little endian byte order.
+pub fn le(dst: u8, bits: i32) -> Insn {
+    insn(0xd4, dst, 0, 0, bits)
+}
+
+/// *(size *)(dst + off) = src
+pub fn stx(size: u8, dst: u8, off: i16, src: u8) -> Insn {
+    insn(0x63 | size, dst, src, off, 0)
+}
+
+/// *(size *)(dst + off) = imm
+pub fn st_imm(size: u8, dst: u8, off: i16, imm: i32) -> Insn {
+    insn(0x62 | size, dst, 0, off, imm)
+}
+
+/// if dst op imm goto pc + off
+pub fn jmp_imm(op: u8, dst: u8, imm: i32, off: i16) -> Insn {
+    insn(0x05 | op, dst, 0, off, imm)
+}
+
+/// if dst op src goto pc + off
+pub fn jmp_reg(op: u8, dst: u8, src: u8, off: i16) -> Insn {
+    insn(0x0d | op, dst, src, off, 0)
+}
+
+/// goto pc + off
+pub fn ja(off: i16) -> Insn {
+    insn(0x05, 0, 0, off, 0)
+}
+
+/// dst = imm, all 64 bits. This is a two slot instruction.
+pub fn ld_imm64(dst: u8, imm: u64) -> [Insn; 2] {
+    [insn(0x18, dst, 0, 0, imm as u32 as i32), insn(0, 0, 0, 0, (imm >> 32) as u32 as i32)]
+}
+
+/// dst = map, by fd. This is a two slot instruction.
+pub fn ld_map_fd(dst: u8, fd: RawFd) -> [Insn; 2] {
+    // src 1 is BPF_PSEUDO_MAP_FD.
+    [insn(0x18, dst, 1, 0, fd), insn(0, 0, 0, 0, 0)]
+}
+
+/// Call a helper function; arguments in r1-r5, result in r0.
+pub fn call(helper: i32) -> Insn {
+    insn(0x85, 0, 0, 0, helper)
+}
+
+pub fn exit() -> Insn {
+    insn(0x95, 0, 0, 0, 0)
+}
+
+/// Assembles a program with named jump targets, so that jump offsets don't need to be counted by
+/// hand.
+///
+/// ```
+/// use rusty_rail::bpf::{self, Asm, JEQ, R0, R1};
+///
+/// let mut asm = Asm::new();
+/// asm.push(bpf::mov64_imm(R0, 0));
+/// asm.jump(bpf::jmp_imm(JEQ, R1, 0, 0), "out");
+/// asm.push(bpf::mov64_imm(R0, 1));
+/// asm.label("out");
+/// asm.push(bpf::exit());
+/// assert_eq!(asm.finish()[1], bpf::jmp_imm(JEQ, R1, 0, 1));
+/// ```
+pub struct Asm {
+    insns: Vec<Insn>,
+    labels: BTreeMap<&'static str, usize>,
+    fixups: Vec<(usize, &'static str)>,
+}
+
+impl Asm {
+    pub fn new() -> Asm {
+        Asm {
+            insns: vec![],
+            labels: BTreeMap::new(),
+            fixups: vec![],
+        }
+    }
+
+    pub fn push(&mut self, insn: Insn) {
+        self.insns.push(insn);
+    }
+
+    pub fn extend(&mut self, insns: &[Insn]) {
+        self.insns.extend_from_slice(insns);
+    }
+
+    /// Name the next instruction.
+    pub fn label(&mut self, name: &'static str) {
+        self.labels.insert(name, self.insns.len());
+    }
+
+    /// Add a jump instruction, to be pointed at target when the program is finished.
+    pub fn jump(&mut self, insn: Insn, target: &'static str) {
+        self.fixups.push((self.insns.len(), target));
+        self.insns.push(insn);
+    }
+
+    /// Resolve jumps and return the instructions. Panics on unknown labels.
+    pub fn finish(mut self) -> Vec<Insn> {
+        for &(pos, target) in &self.fixups {
+            let dest = match self.labels.get(target) {
+                Some(dest) => *dest,
+                None => panic!("Unknown label {}", target),
+            };
+            self.insns[pos].off = (dest as isize - pos as isize - 1) as i16;
+        }
+        self.insns
+    }
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> Result<RawFd, error::BrokenRail> {
+    let rv = unsafe {
+        libc::syscall(libc::SYS_bpf,
+                      cmd,
+                      attr as *mut T as *mut libc::c_void,
+                      mem::size_of::<T>())
+    };
+    if rv < 0 {
+        Err(error::BrokenRail::IO(io::Error::last_os_error()))
+    } else {
+        Ok(rv as RawFd)
+    }
+}
+
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct MapElemAttr {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct TestRunAttr {
+    prog_fd: u32,
+    retval: u32,
+    data_size_in: u32,
+    data_size_out: u32,
+    data_in: u64,
+    data_out: u64,
+    repeat: u32,
+    duration: u32,
+}
+
+/// An owned bpf object file descriptor (map, program or link), closed on drop.
+pub struct Fd(pub RawFd);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// A bpf map with fixed size keys and values.
+pub struct Map {
+    pub fd: Fd,
+    key_size: usize,
+    value_size: usize,
+}
+
+impl Map {
+    pub fn new(map_type: u32,
+               key_size: usize,
+               value_size: usize,
+               max_entries: u32)
+               -> Result<Map, error::BrokenRail> {
+        let mut attr = MapCreateAttr {
+            map_type: map_type,
+            key_size: key_size as u32,
+            value_size: value_size as u32,
+            max_entries: max_entries,
+            map_flags: 0,
+        };
+        Ok(Map {
+            fd: Fd(try!(bpf(BPF_MAP_CREATE, &mut attr))),
+            key_size: key_size,
+            value_size: value_size,
+        })
+    }
+
+    fn elem_attr(&self, key: &[u8], value: *const u8) -> MapElemAttr {
+        assert_eq!(key.len(), self.key_size);
+        MapElemAttr {
+            map_fd: self.fd.0 as u32,
+            pad: 0,
+            key: key.as_ptr() as u64,
+            value: value as u64,
+            flags: 0,
+        }
+    }
+
+    pub fn update(&self, key: &[u8], value: &[u8]) -> Result<(), error::BrokenRail> {
+        assert_eq!(value.len(), self.value_size);
+        let mut attr = self.elem_attr(key, value.as_ptr());
+        try!(bpf(BPF_MAP_UPDATE_ELEM, &mut attr));
+        Ok(())
+    }
+
+    pub fn lookup(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut value = vec![0u8; self.value_size];
+        let mut attr = self.elem_attr(key, value.as_mut_ptr());
+        match bpf(BPF_MAP_LOOKUP_ELEM, &mut attr) {
+            Ok(_) => Some(value),
+            Err(_) => None,
+        }
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<(), error::BrokenRail> {
+        let mut attr = self.elem_attr(key, 0 as *const u8);
+        try!(bpf(BPF_MAP_DELETE_ELEM, &mut attr));
+        Ok(())
+    }
+}
+
+/// A loaded XDP program.
+pub struct Program {
+    pub fd: Fd,
+}
+
+impl Program {
+    /// Load an XDP program. Verifier complaints are returned in the error.
+    pub fn load_xdp(name: &str, insns: &[Insn]) -> Result<Program, error::BrokenRail> {
+        let mut log = vec![];
+        match Program::load(name, insns, &mut log) {
+            Ok(program) => Ok(program),
+            Err(err) => {
+                // Go again with the verifier log enabled, to say why. (The log is not requested
+                // up front as overflowing the buffer would fail the load.)
+                log = vec![0u8; 1 << 20];
+                if let Ok(program) = Program::load(name, insns, &mut log) {
+                    return Ok(program);
+                }
+                let end = log.iter().position(|b| *b == 0).unwrap_or(log.len());
+                Err(error::BrokenRail::Configuration(format!("{} loading {}: {}",
+                                                              err,
+                                                              name,
+                                                              String::from_utf8_lossy(&log[..end]))))
+            }
+        }
+    }
+
+    fn load(name: &str, insns: &[Insn], log: &mut Vec<u8>) -> Result<Program, error::BrokenRail> {
+        let license = CString::new("GPL").unwrap();
+        let mut prog_name = [0u8; 16];
+        for (dst, src) in prog_name.iter_mut().zip(name.bytes().take(15)) {
+            *dst = src;
+        }
+        let mut attr = ProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_XDP,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            log_level: if log.is_empty() { 0 } else { 1 },
+            log_size: log.len() as u32,
+            log_buf: log.as_mut_ptr() as u64,
+            kern_version: 0,
+            prog_flags: 0,
+            prog_name: prog_name,
+            prog_ifindex: 0,
+            expected_attach_type: BPF_XDP,
+        };
+        Ok(Program { fd: Fd(try!(bpf(BPF_PROG_LOAD, &mut attr))) })
+    }
+
+    /// Attach to an interface; the program stays attached until the returned link is dropped.
+    pub fn attach(&self, ifindex: u32, flags: u32) -> Result<Fd, error::BrokenRail> {
+        let mut attr = LinkCreateAttr {
+            prog_fd: self.fd.0 as u32,
+            target_ifindex: ifindex,
+            attach_type: BPF_XDP,
+            flags: flags,
+        };
+        Ok(Fd(try!(bpf(BPF_LINK_CREATE, &mut attr))))
+    }
+
+    /// Run the program once over a frame (BPF_PROG_TEST_RUN), returning the XDP action and the
+    /// frame as the program left it.
+    pub fn test_run(&self, frame: &[u8]) -> Result<(u32, Vec<u8>), error::BrokenRail> {
+        let mut out = vec![0u8; frame.len() + 256];
+        let mut attr = TestRunAttr {
+            prog_fd: self.fd.0 as u32,
+            retval: 0,
+            data_size_in: frame.len() as u32,
+            data_size_out: out.len() as u32,
+            data_in: frame.as_ptr() as u64,
+            data_out: out.as_mut_ptr() as u64,
+            repeat: 1,
+            duration: 0,
+        };
+        try!(bpf(BPF_PROG_TEST_RUN, &mut attr));
+        out.truncate(attr.data_size_out as usize);
+        Ok((attr.retval, out))
+    }
+}
+
+#[test]
+fn jump_encodings() {
+    // The opcodes of the conditional jumps the programs use, as the kernel's BPF_JMP | op | src.
+    assert_eq!(jmp_imm(JEQ, R1, 7, 2),
+               Insn {
+                   code: 0x15,
+                   regs: 0x01,
+                   off: 2,
+                   imm: 7,
+               });
+    assert_eq!(jmp_imm(JNE, R5, 47, 0).code, 0x55);
+    assert_eq!(jmp_imm(JSET, R5, 1, 0).code, 0x45);
+    assert_eq!(jmp_reg(JGT, R4, R3, 0),
+               Insn {
+                   code: 0x2d,
+                   regs: 0x34,
+                   off: 0,
+                   imm: 0,
+               });
+    assert_eq!(jmp_reg(JGE, R4, R3, 0).code, 0x3d);
+    assert_eq!(ja(3).code, 0x05);
+}
+
+#[test]
+fn asm_labels() {
+    let mut asm = Asm::new();
+    asm.label("top");
+    asm.jump(jmp_reg(JGT, R4, R3, 0), "out");
+    // Two slot instructions count twice.
+    asm.extend(&ld_imm64(R1, 1));
+    asm.jump(jmp_imm(JNE, R1, 0, 0), "out");
+    asm.jump(ja(0), "top");
+    asm.label("out");
+    asm.push(exit());
+    let insns = asm.finish();
+    assert_eq!(insns.len(), 6);
+    assert_eq!(insns[0], jmp_reg(JGT, R4, R3, 4));
+    assert_eq!(insns[3], jmp_imm(JNE, R1, 0, 1));
+    // Backwards, to before the jump itself.
+    assert_eq!(insns[4], ja(-5));
+}
+
+#[test]
+#[should_panic(expected = "Unknown label nowhere")]
+fn asm_unknown_label() {
+    let mut asm = Asm::new();
+    asm.jump(ja(0), "nowhere");
+    asm.finish();
+}
@@ -0,0 +1,81 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// Internet (one's complement) checksums.
+
+use std::net::Ipv4Addr;
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// The checksum of data, as stored in a header whose checksum field is zero while summing.
+///
+/// ```
+/// use rusty_rail::checksum;
+///
+/// // RFC 1071 section 3's example.
+/// let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+/// assert_eq!(checksum::checksum(&data), !0xddf2);
+/// ```
+pub fn checksum(data: &[u8]) -> u16 {
+    !fold(sum(0, data))
+}
+
+/// The checksum of the concatenation of parts, all but the last of which must have an even
+/// length: for data that is not contiguous, such as rebuilt headers in front of a payload.
+pub fn checksum_parts(parts: &[&[u8]]) -> u16 {
+    !fold(parts.iter().fold(0, |acc, part| sum(acc, part)))
+}
+
+fn sum(mut sum: u32, data: &[u8]) -> u32 {
+    for pair in data.chunks(2) {
+        let word = if pair.len() == 2 {
+            (pair[0] as u32) << 8 | pair[1] as u32
+        } else {
+            (pair[0] as u32) << 8
+        };
+        sum += word;
+        sum = fold(sum) as u32;
+    }
+    sum
+}
+
+/// Update checksum for a 16 bit word of the covered data changing from old to new.
+///
+/// This is RFC 1624 equation 3, HC' = ~(~HC + ~m + m'), which unlike recomputing the checksum
+/// preserves any error already present in the packet for the receiver to detect.
+pub fn adjust(checksum: u16, old: u16, new: u16) -> u16 {
+    !fold((!checksum) as u32 + (!old) as u32 + new as u32)
+}
+
+/// Update checksum for an IPv4 address in the covered data changing from old to new.
+pub fn adjust_addr(checksum: u16, old: &Ipv4Addr, new: &Ipv4Addr) -> u16 {
+    let old = old.octets();
+    let new = new.octets();
+    let checksum = adjust(checksum,
+                          (old[0] as u16) << 8 | old[1] as u16,
+                          (new[0] as u16) << 8 | new[1] as u16);
+    adjust(checksum,
+           (old[2] as u16) << 8 | old[3] as u16,
+           (new[2] as u16) << 8 | new[3] as u16)
+}
+
+#[test]
+fn adjust_matches_recompute() {
+    // An IPv4 header with a correct checksum.
+    let mut header = [0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00,
+                      0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7];
+    let initial = checksum(&header);
+    assert_eq!(initial, 0xb861);
+    let old_src = Ipv4Addr::new(192, 168, 0, 1);
+    let new_src = Ipv4Addr::new(203, 0, 113, 77);
+    header[12..16].copy_from_slice(&new_src.octets());
+    // TTL 64 -> 63 in the TTL/protocol word.
+    header[8] = 0x3f;
+    let adjusted = adjust(adjust_addr(initial, &old_src, &new_src), 0x4011, 0x3f11);
+    assert_eq!(adjusted, checksum(&header));
+    assert_eq!(checksum_parts(&[&header[..8], &header[8..13]]), checksum(&header[..13]));
+}
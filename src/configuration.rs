@@ -1,15 +1,99 @@
 // Copyright (c) 2016 Robert Collins. Licensed under the Apache-2.0 license.
 use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+#[cfg(test)]
 use std::net::Ipv4Addr;
 use std::str::FromStr;
 
+use pnet::util::MacAddr;
+
 use super::error;
-use super::consistenthash::{Backend, ConsistentHash};
+use super::encap;
+use super::encap::Encap;
+use super::consistenthash::{self, Backend, ConsistentHash, Disruption, Routes};
+use super::xdp;
+
+/// The packet I/O implementation used to reach the wire and the host.
+#[derive(Debug, PartialEq)]
+pub enum IoBackend {
+    Netmap,
+    AfPacket,
+    AfXdp,
+    /// A TAP device, for building test topologies out of network namespaces.
+    Tap,
+    /// Forward within the kernel; userspace only maintains the tables.
+    Xdp,
+}
+
+/// How XDP programs are attached to the device.
+#[derive(Debug, PartialEq)]
+pub enum XdpMode {
+    /// Generic (SKB) mode: works on any device, including veth.
+    Generic,
+    /// Driver mode: needs driver support, but avoids allocating an skb per packet.
+    Native,
+}
+
+/// Which fields of the inner packet are hashed to choose its backend.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlowHash {
+    /// Addresses and protocol: every flow from one address reaches the same backend.
+    ThreeTuple,
+    /// Ports too, or the ICMP echo identifier, spreading clients that share an address (such as
+    /// a CGNAT pool) over the backends. Later IPv4 fragments follow their first fragment, and
+    /// IPv6 fragments are hashed on the addresses alone.
+    FiveTuple,
+}
+
+/// How forwarding work is spread over threads.
+#[derive(Debug, PartialEq)]
+pub enum Threading {
+    /// One thread services every ring.
+    Single,
+    /// One thread per NIC ring pair, each pinned to its own CPU. netmap only.
+    PerRing,
+}
+
+/// The keys being rotated away from, and the lookup table permuted under the previous key.
+pub struct Rotation {
+    pub flow_key: (u64, u64),
+    pub routes: ConsistentHash,
+}
 
 pub struct Config {
+    pub backend: IoBackend,
+    pub xdp_mode: XdpMode,
+    pub threading: Threading,
+    /// Send ICMP time exceeded to the sender of packets whose TTL runs out.
+    pub icmp_time_exceeded: bool,
     pub device: String,
     pub routes: ConsistentHash,
-    pub target_ips: Vec<Ipv4Addr>,
+    /// How packets are wrapped on the way to the backends.
+    pub encap: Encap,
+    /// Backends wrapped differently from the rest, by target address.
+    pub backend_encap: BTreeMap<IpAddr, Encap>,
+    /// The backends, reached over IPv4 or IPv6 as their addresses are.
+    pub target_ips: Vec<IpAddr>,
+    /// Neighbours to seed the ARP cache with, for replays away from the backends' network.
+    pub static_arp: Vec<(IpAddr, MacAddr)>,
+    /// Outer source addresses to use instead of the interface address, by inner destination
+    /// (VIP), for servers that expect GRE from a dedicated tunnel address. Only used for
+    /// backends of the same family.
+    pub vip_sources: BTreeMap<IpAddr, IpAddr>,
+    /// VIPs named by GRE key, overriding the inner destination when choosing a VIP source.
+    pub gre_key_vips: BTreeMap<u32, IpAddr>,
+    /// Drop GRE sequence numbers rather than pass them on to the backends.
+    pub gre_strip_sequence: bool,
+    /// The fields hashed to choose a backend.
+    pub flow_hash: FlowHash,
+    /// VIPs hashed differently from the rest, by inner destination.
+    pub vip_flow_hash: BTreeMap<IpAddr, FlowHash>,
+    /// The SipHash key for flow hashes, shared by every load balancer.
+    pub flow_key: (u64, u64),
+    /// The previous keys, while moving to new ones.
+    pub rotation: Option<Rotation>,
 }
 
 impl Config {
@@ -17,22 +101,387 @@ impl Config {
         where I: Iterator<Item = (String, String)>
     {
         let vars: BTreeMap<String, String> = vars.collect();
-        let ipstring = &vars.get("RR_TARGET_IPS").unwrap();
-        let target_ips: Vec<Ipv4Addr> =
-            ipstring.split(";").map(|i| Ipv4Addr::from_str(&i).unwrap()).collect();
-        let mut hash = ConsistentHash::new();
-        for target_name in ipstring.split(";") {
-            let ip = Ipv4Addr::from_str(&target_name).unwrap();
-            let backend = Backend::new(&target_name, ip);
-            hash.backends.push(backend);
-        }
-        hash.populate();
+        let ipstring = match vars.get("RR_TARGET_IPS") {
+            Some(ipstring) => ipstring,
+            None => return Err(error::BrokenRail::Configuration("no RR_TARGET_IPS".to_string())),
+        };
+        let mut target_ips = Vec::new();
+        for target in ipstring.split(";") {
+            match IpAddr::from_str(target) {
+                Ok(ip) => target_ips.push(ip),
+                Err(_) => {
+                    let msg = format!("bad target IP {}", target);
+                    return Err(error::BrokenRail::Configuration(msg));
+                }
+            }
+        }
+        let device = match vars.get("RR_DEVICE") {
+            Some(device) => device.clone(),
+            None => return Err(error::BrokenRail::Configuration("no RR_DEVICE".to_string())),
+        };
+        let mut keys = BTreeMap::new();
+        for name in &["RR_FLOW_KEY",
+                      "RR_PERMUTATION_KEY",
+                      "RR_PREVIOUS_FLOW_KEY",
+                      "RR_PREVIOUS_PERMUTATION_KEY"] {
+            if let Some(value) = vars.get(*name) {
+                keys.insert(*name, try!(parse_key(name, value)));
+            }
+        }
+        let flow_key = *keys.get("RR_FLOW_KEY").unwrap_or(&(0, 0));
+        let permutation_key = *keys.get("RR_PERMUTATION_KEY").unwrap_or(&(0, 0));
+        let mut weights = BTreeMap::new();
+        for entry in vars.get("RR_BACKEND_WEIGHTS").iter().flat_map(|s| s.split(";")) {
+            let mut parts = entry.splitn(2, "=");
+            let target = parts.next().and_then(|ip| IpAddr::from_str(ip).ok());
+            let weight = parts.next().and_then(|w| u32::from_str(w).ok());
+            match (target, weight) {
+                (Some(target), Some(weight)) if target_ips.contains(&target) && weight > 0 => {
+                    weights.insert(target, weight);
+                }
+                _ => {
+                    return Err(error::BrokenRail::Configuration(format!("bad backend weight {}",
+                                                                        entry)))
+                }
+            }
+        }
+        let table_size = match vars.get("RR_TABLE_SIZE").map(|s| usize::from_str(s)) {
+            None => consistenthash::DEFAULT_SIZE,
+            Some(Ok(size)) if size > 0 => size,
+            Some(_) => {
+                let msg = format!("bad table size {}", vars["RR_TABLE_SIZE"]);
+                return Err(error::BrokenRail::Configuration(msg));
+            }
+        };
+        let hash = try!(routes(&target_ips, &weights, table_size, permutation_key));
+        let rotation = match (keys.get("RR_PREVIOUS_FLOW_KEY"),
+                              keys.get("RR_PREVIOUS_PERMUTATION_KEY")) {
+            (None, None) => None,
+            (previous_flow, previous_permutation) => {
+                Some(Rotation {
+                    flow_key: *previous_flow.unwrap_or(&flow_key),
+                    routes: try!(routes(&target_ips,
+                                        &weights,
+                                        table_size,
+                                        *previous_permutation.unwrap_or(&permutation_key))),
+                })
+            }
+        };
+        let backend = match vars.get("RR_BACKEND").map(|b| b.as_str()) {
+            None | Some("netmap") => IoBackend::Netmap,
+            Some("afpacket") => IoBackend::AfPacket,
+            Some("afxdp") => IoBackend::AfXdp,
+            Some("xdp") => IoBackend::Xdp,
+            Some("tap") => IoBackend::Tap,
+            Some(other) => {
+                return Err(error::BrokenRail::Configuration(format!("unknown backend {}", other)))
+            }
+        };
+        let xdp_mode = match vars.get("RR_XDP_MODE").map(|m| m.as_str()) {
+            None | Some("generic") => XdpMode::Generic,
+            Some("native") => XdpMode::Native,
+            Some(other) => {
+                return Err(error::BrokenRail::Configuration(format!("unknown XDP mode {}", other)))
+            }
+        };
+        let threading = match vars.get("RR_THREADS").map(|t| t.as_str()) {
+            None | Some("single") => Threading::Single,
+            Some("per-ring") => Threading::PerRing,
+            Some(other) => {
+                return Err(error::BrokenRail::Configuration(format!("unknown threading {}", other)))
+            }
+        };
+        if threading == Threading::PerRing && backend != IoBackend::Netmap {
+            return Err(error::BrokenRail::Configuration("per-ring threads need netmap"
+                .to_string()));
+        }
+        let vni = match vars.get("RR_VNI").map(|v| u32::from_str(v)) {
+            None => None,
+            Some(Ok(vni)) if vni < 1 << 24 => Some(vni),
+            Some(_) => {
+                return Err(error::BrokenRail::Configuration(format!("bad VNI {}",
+                                                                    vars["RR_VNI"])))
+            }
+        };
+        let encap = match vars.get("RR_ENCAP") {
+            None => Encap::Gre,
+            Some(name) => try!(parse_encap(name, vni)),
+        };
+        let mut backend_encap = BTreeMap::new();
+        for entry in vars.get("RR_BACKEND_ENCAP").iter().flat_map(|s| s.split(";")) {
+            let mut parts = entry.splitn(2, "=");
+            let target = parts.next().and_then(|ip| IpAddr::from_str(ip).ok());
+            match (target, parts.next()) {
+                (Some(target), Some(name)) if target_ips.contains(&target) => {
+                    backend_encap.insert(target, try!(parse_encap(name, vni)));
+                }
+                _ => {
+                    return Err(error::BrokenRail::Configuration(format!("bad backend encap {}",
+                                                                        entry)))
+                }
+            }
+        }
+        let encaps: Vec<&Encap> = Some(&encap).into_iter().chain(backend_encap.values()).collect();
+        if vni.is_some() && encaps.iter().all(|e| e.vni().is_none()) {
+            return Err(error::BrokenRail::Configuration("RR_VNI needs vxlan or geneve"
+                .to_string()));
+        }
+        if encaps.iter().any(|e| **e != Encap::Gre) && backend == IoBackend::Xdp {
+            return Err(error::BrokenRail::Configuration("xdp only sends GRE".to_string()));
+        }
+        for target in target_ips.iter().filter(|t| t.is_ipv6()) {
+            if backend == IoBackend::Xdp {
+                return Err(error::BrokenRail::Configuration("xdp only sends over IPv4"
+                    .to_string()));
+            }
+            // Without a UDP checksum, which IPv6 requires.
+            match *backend_encap.get(target).unwrap_or(&encap) {
+                Encap::Gre | Encap::Ipip | Encap::Direct => (),
+                other => {
+                    let msg = format!("{:?} cannot reach IPv6 backend {}", other, target);
+                    return Err(error::BrokenRail::Configuration(msg));
+                }
+            }
+        }
+        let icmp_time_exceeded = match vars.get("RR_ICMP_TIME_EXCEEDED").map(|t| t.as_str()) {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(other) => {
+                let msg = format!("bad RR_ICMP_TIME_EXCEEDED {}", other);
+                return Err(error::BrokenRail::Configuration(msg));
+            }
+        };
+        if icmp_time_exceeded && backend == IoBackend::Xdp {
+            return Err(error::BrokenRail::Configuration("xdp cannot send ICMP time exceeded"
+                .to_string()));
+        }
+        let mut static_arp = vec![];
+        for entry in vars.get("RR_STATIC_ARP").iter().flat_map(|s| s.split(";")) {
+            let mut parts = entry.splitn(2, "=");
+            let ip = parts.next().and_then(|ip| IpAddr::from_str(ip).ok());
+            let mac = parts.next().and_then(|mac| MacAddr::from_str(mac).ok());
+            match (ip, mac) {
+                (Some(ip), Some(mac)) => static_arp.push((ip, mac)),
+                _ => {
+                    return Err(error::BrokenRail::Configuration(format!("bad ARP entry {}",
+                                                                        entry)))
+                }
+            }
+        }
+        let mut vip_sources = BTreeMap::new();
+        for entry in vars.get("RR_VIP_SOURCES").iter().flat_map(|s| s.split(";")) {
+            let mut parts = entry.splitn(2, "=");
+            let vip = parts.next().and_then(|ip| IpAddr::from_str(ip).ok());
+            let source = parts.next().and_then(|ip| IpAddr::from_str(ip).ok());
+            match (vip, source) {
+                (Some(vip), Some(source)) => {
+                    vip_sources.insert(vip, source);
+                }
+                _ => {
+                    return Err(error::BrokenRail::Configuration(format!("bad VIP source {}",
+                                                                        entry)))
+                }
+            }
+        }
+        let mut gre_key_vips = BTreeMap::new();
+        for entry in vars.get("RR_GRE_KEY_VIPS").iter().flat_map(|s| s.split(";")) {
+            let mut parts = entry.splitn(2, "=");
+            let key = parts.next().and_then(|key| u32::from_str(key).ok());
+            let vip = parts.next().and_then(|ip| IpAddr::from_str(ip).ok());
+            match (key, vip) {
+                (Some(key), Some(vip)) => {
+                    gre_key_vips.insert(key, vip);
+                }
+                _ => {
+                    return Err(error::BrokenRail::Configuration(format!("bad GRE key VIP {}",
+                                                                        entry)))
+                }
+            }
+        }
+        let gre_strip_sequence = match vars.get("RR_GRE_SEQUENCE").map(|s| s.as_str()) {
+            None | Some("preserve") => false,
+            Some("strip") => true,
+            Some(other) => {
+                let msg = format!("bad RR_GRE_SEQUENCE {}", other);
+                return Err(error::BrokenRail::Configuration(msg));
+            }
+        };
+        if (gre_strip_sequence || !gre_key_vips.is_empty()) && backend == IoBackend::Xdp {
+            return Err(error::BrokenRail::Configuration("xdp cannot act on GRE options"
+                .to_string()));
+        }
+        let flow_hash = match vars.get("RR_FLOW_HASH") {
+            None => FlowHash::ThreeTuple,
+            Some(name) => try!(parse_flow_hash(name)),
+        };
+        let mut vip_flow_hash = BTreeMap::new();
+        for entry in vars.get("RR_VIP_FLOW_HASH").iter().flat_map(|s| s.split(";")) {
+            let mut parts = entry.splitn(2, "=");
+            match (parts.next().and_then(|ip| IpAddr::from_str(ip).ok()), parts.next()) {
+                (Some(vip), Some(name)) => {
+                    vip_flow_hash.insert(vip, try!(parse_flow_hash(name)));
+                }
+                _ => {
+                    return Err(error::BrokenRail::Configuration(format!("bad VIP flow hash {}",
+                                                                        entry)))
+                }
+            }
+        }
+        let hashes: Vec<&FlowHash> =
+            Some(&flow_hash).into_iter().chain(vip_flow_hash.values()).collect();
+        if hashes.iter().any(|h| **h == FlowHash::FiveTuple) && backend == IoBackend::Xdp {
+            return Err(error::BrokenRail::Configuration("xdp only hashes addresses".to_string()));
+        }
+        if rotation.is_some() && backend == IoBackend::Xdp {
+            return Err(error::BrokenRail::Configuration("xdp cannot rotate keys".to_string()));
+        }
+        if hash.size() > xdp::MAX_LOOKUP && backend == IoBackend::Xdp {
+            let msg = format!("xdp tables hold at most {} slots", xdp::MAX_LOOKUP);
+            return Err(error::BrokenRail::Configuration(msg));
+        }
         Ok(Config {
-            device: vars.get("RR_DEVICE").unwrap().clone(),
+            backend: backend,
+            xdp_mode: xdp_mode,
+            threading: threading,
+            icmp_time_exceeded: icmp_time_exceeded,
+            device: device,
             routes: hash,
+            encap: encap,
+            backend_encap: backend_encap,
             target_ips: target_ips,
+            static_arp: static_arp,
+            vip_sources: vip_sources,
+            gre_key_vips: gre_key_vips,
+            gre_strip_sequence: gre_strip_sequence,
+            flow_hash: flow_hash,
+            vip_flow_hash: vip_flow_hash,
+            flow_key: flow_key,
+            rotation: rotation,
         })
     }
+
+    /// The lookup tables to publish for forwarding: the current table, and the previous one
+    /// while keys are being rotated.
+    pub fn lookup_tables(&self) -> Routes {
+        Routes {
+            current: self.routes.clone(),
+            previous: self.rotation.as_ref().map(|rotation| rotation.routes.clone()),
+        }
+    }
+
+    /// Move the published routes over to this configuration's backends, disrupting as few
+    /// flows as possible. The keys and table size stay those of the routes.
+    pub fn update_routes(&self,
+                         routes: &Routes)
+                         -> Result<(Routes, Disruption), error::BrokenRail> {
+        let mut current = routes.current.clone();
+        try!(current.update_backends(&self.routes));
+        let (current, disruption) = current.rebuild();
+        // The previous keys' table has the same backends.
+        let previous = match routes.previous {
+            Some(ref previous) => {
+                let mut previous = previous.clone();
+                try!(previous.update_backends(&self.routes));
+                previous.populate();
+                Some(previous)
+            }
+            None => None,
+        };
+        let routes = Routes {
+            current: current,
+            previous: previous,
+        };
+        Ok((routes, disruption))
+    }
+}
+
+/// Build the lookup table for the backend addresses in targets, with weights other than 1 from
+/// weights, of at least size slots, permuted under key.
+fn routes(targets: &[IpAddr],
+          weights: &BTreeMap<IpAddr, u32>,
+          size: usize,
+          key: (u64, u64))
+          -> Result<ConsistentHash, error::BrokenRail> {
+    let mut hash = ConsistentHash::new();
+    hash.set_key(key);
+    hash.set_size(size);
+    for &ip in targets {
+        let name = ip.to_string();
+        try!(hash.add_backend(Backend::new(&name, ip)));
+        try!(hash.set_weight(&name, *weights.get(&ip).unwrap_or(&1)));
+    }
+    hash.populate();
+    Ok(hash)
+}
+
+/// Parse the 128 bit SipHash key in the variable name, written as 32 hex digits, into its two
+/// halves.
+fn parse_key(name: &str, value: &str) -> Result<(u64, u64), error::BrokenRail> {
+    if value.len() == 32 && value.chars().all(|c| c.is_digit(16)) {
+        if let (Ok(k0), Ok(k1)) = (u64::from_str_radix(&value[..16], 16),
+                                   u64::from_str_radix(&value[16..], 16)) {
+            return Ok((k0, k1));
+        }
+    }
+    Err(error::BrokenRail::Configuration(format!("{} needs 32 hex digits", name)))
+}
+
+/// Parse a flow hash name: ``3-tuple`` or ``5-tuple``.
+fn parse_flow_hash(name: &str) -> Result<FlowHash, error::BrokenRail> {
+    match name {
+        "3-tuple" => Ok(FlowHash::ThreeTuple),
+        "5-tuple" => Ok(FlowHash::FiveTuple),
+        _ => Err(error::BrokenRail::Configuration(format!("unknown flow hash {}", name))),
+    }
+}
+
+/// The settings in vars, followed by those in the file RR_CONFIG_FILE names, if any: lines of
+/// NAME=value, overriding vars of the same name. Blank lines and lines starting with # are
+/// skipped.
+pub fn settings<I>(vars: I) -> Result<Vec<(String, String)>, error::BrokenRail>
+    where I: Iterator<Item = (String, String)>
+{
+    let mut settings: Vec<(String, String)> = vars.collect();
+    let path = match settings.iter().find(|&&(ref name, _)| name == "RR_CONFIG_FILE") {
+        None => return Ok(settings),
+        Some(&(_, ref path)) => path.clone(),
+    };
+    let mut contents = String::new();
+    try!(try!(File::open(&path)).read_to_string(&mut contents));
+    let lines = contents.lines().map(|l| l.trim());
+    for line in lines.filter(|l| !l.is_empty() && !l.starts_with("#")) {
+        let mut parts = line.splitn(2, "=");
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => settings.push((name.to_string(), value.to_string())),
+            _ => {
+                let msg = format!("bad setting {} in {}", line, path);
+                return Err(error::BrokenRail::Configuration(msg));
+            }
+        }
+    }
+    Ok(settings)
+}
+
+/// Parse an encapsulation name; vni is for VXLAN and GENEVE, and FOU and GUE take an optional
+/// port, as in ``fou:5555``.
+fn parse_encap(name: &str, vni: Option<u32>) -> Result<Encap, error::BrokenRail> {
+    let mut parts = name.splitn(2, ":");
+    let kind = parts.next().unwrap_or("");
+    let port = match parts.next().map(|p| u16::from_str(p)) {
+        None => None,
+        Some(Ok(port)) if kind == "fou" || kind == "gue" => Some(port),
+        Some(_) => return Err(error::BrokenRail::Configuration(format!("bad encap {}", name))),
+    };
+    match kind {
+        "gre" => Ok(Encap::Gre),
+        "ipip" => Ok(Encap::Ipip),
+        "vxlan" => Ok(Encap::Vxlan(vni.unwrap_or(0))),
+        "geneve" => Ok(Encap::Geneve(vni.unwrap_or(0))),
+        "fou" => Ok(Encap::Fou(port.unwrap_or(encap::FOU_PORT))),
+        "gue" => Ok(Encap::Gue(port.unwrap_or(encap::GUE_PORT))),
+        "direct" => Ok(Encap::Direct),
+        _ => Err(error::BrokenRail::Configuration(format!("unknown encap {}", name))),
+    }
 }
 
 #[test]
@@ -43,6 +492,321 @@ fn set_variables() {
     assert_eq!(config.device, "wlan0");
     assert_eq!(config.target_ips[0],
                Ipv4Addr::from_str("192.0.2.1").unwrap());
+    assert_eq!(config.backend, IoBackend::Netmap);
+    assert_eq!(config.threading, Threading::Single);
+    assert!(!config.icmp_time_exceeded);
+}
+
+#[test]
+fn settings_file() {
+    use std::{env, fs, process};
+    use std::io::Write;
+
+    let path = env::temp_dir().join(format!("rusty_rail-settings-{}", process::id()));
+    let contents = "# Backends\nRR_TARGET_IPS=192.0.2.1;192.0.2.2\n\n\
+                    RR_BACKEND_WEIGHTS=192.0.2.2=3\n";
+    File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+    let vars = vec![("RR_DEVICE".to_string(), "lo".to_string()),
+                    ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                    ("RR_CONFIG_FILE".to_string(), path.to_str().unwrap().to_string())];
+    let config = Config::new(settings(vars.clone().into_iter()).unwrap().into_iter()).unwrap();
+    assert_eq!(config.target_ips.len(), 2);
+    assert_eq!(config.routes.backends()[1].as_ref().unwrap().weight(), 3);
+    File::create(&path).unwrap().write_all(b"RR_TARGET_IPS\n").unwrap();
+    assert!(settings(vars.into_iter()).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn afpacket_backend() {
+    let vars = [("RR_DEVICE".to_string(), "veth0".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_BACKEND".to_string(), "afpacket".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.backend, IoBackend::AfPacket);
+}
+
+#[test]
+fn afxdp_backend() {
+    let vars = [("RR_DEVICE".to_string(), "veth0".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_BACKEND".to_string(), "afxdp".to_string()),
+                ("RR_XDP_MODE".to_string(), "native".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.backend, IoBackend::AfXdp);
+    assert_eq!(config.xdp_mode, XdpMode::Native);
+}
+
+#[test]
+fn tap_backend() {
+    let vars = [("RR_DEVICE".to_string(), "rr0".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_BACKEND".to_string(), "tap".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.backend, IoBackend::Tap);
+}
+
+#[test]
+fn per_ring_threads() {
+    let vars = [("RR_DEVICE".to_string(), "eth0".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_THREADS".to_string(), "per-ring".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.threading, Threading::PerRing);
+    let vars = [("RR_DEVICE".to_string(), "eth0".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_BACKEND".to_string(), "afpacket".to_string()),
+                ("RR_THREADS".to_string(), "per-ring".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn encap() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string())];
+    assert_eq!(Config::new(vars.iter().cloned()).unwrap().encap, Encap::Gre);
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_ENCAP".to_string(), "ipip".to_string())];
+    assert_eq!(Config::new(vars.iter().cloned()).unwrap().encap, Encap::Ipip);
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_ENCAP".to_string(), "vxlan".to_string()),
+                ("RR_VNI".to_string(), "42".to_string())];
+    assert_eq!(Config::new(vars.iter().cloned()).unwrap().encap, Encap::Vxlan(42));
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_ENCAP".to_string(), "geneve".to_string())];
+    assert_eq!(Config::new(vars.iter().cloned()).unwrap().encap, Encap::Geneve(0));
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_ENCAP".to_string(), "vxlan".to_string()),
+                ("RR_VNI".to_string(), "16777216".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_VNI".to_string(), "42".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn backend_encap() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1;192.0.2.2;192.0.2.3".to_string()),
+                ("RR_BACKEND_ENCAP".to_string(),
+                 "192.0.2.1=fou;192.0.2.2=gue:7000".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.encap, Encap::Gre);
+    assert_eq!(config.backend_encap[&IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))], Encap::Fou(5555));
+    assert_eq!(config.backend_encap[&IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))], Encap::Gue(7000));
+    assert_eq!(config.backend_encap.get(&IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3))), None);
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_ENCAP".to_string(), "direct".to_string())];
+    assert_eq!(Config::new(vars.iter().cloned()).unwrap().encap, Encap::Direct);
+    for bad in &["192.0.2.4=fou", "192.0.2.1=gre:7000", "192.0.2.1=fou:x", "192.0.2.1"] {
+        let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                    ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                    ("RR_BACKEND_ENCAP".to_string(), bad.to_string())];
+        assert!(Config::new(vars.iter().cloned()).is_err(), "{}", bad);
+    }
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_ENCAP".to_string(), "ipip".to_string()),
+                ("RR_BACKEND".to_string(), "xdp".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn icmp_time_exceeded() {
+    let vars = [("RR_DEVICE".to_string(), "eth0".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_ICMP_TIME_EXCEEDED".to_string(), "true".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert!(config.icmp_time_exceeded);
+    let vars = [("RR_DEVICE".to_string(), "eth0".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_BACKEND".to_string(), "xdp".to_string()),
+                ("RR_ICMP_TIME_EXCEEDED".to_string(), "true".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn unknown_backend_error() {
+    let vars = [("RR_DEVICE".to_string(), "veth0".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_BACKEND".to_string(), "dpdk".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn static_arp() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_STATIC_ARP".to_string(),
+                 "192.0.2.1=02:00:00:00:00:01;192.0.2.2=02:00:00:00:00:02".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.static_arp,
+               vec![(Ipv4Addr::new(192, 0, 2, 1).into(), MacAddr::new(2, 0, 0, 0, 0, 1)),
+                    (Ipv4Addr::new(192, 0, 2, 2).into(), MacAddr::new(2, 0, 0, 0, 0, 2))]);
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_STATIC_ARP".to_string(), "192.0.2.1".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn vip_sources() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_VIP_SOURCES".to_string(),
+                 "198.51.100.1=192.0.2.100;198.51.100.2=192.0.2.101;2001:db8::1=192.0.2.102"
+                     .to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.vip_sources.len(), 3);
+    assert_eq!(config.vip_sources[&IpAddr::V4(Ipv4Addr::new(198, 51, 100, 2))],
+               Ipv4Addr::new(192, 0, 2, 101));
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_VIP_SOURCES".to_string(), "198.51.100.1=eth0".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn gre_options() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert!(config.gre_key_vips.is_empty());
+    assert!(!config.gre_strip_sequence);
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_GRE_KEY_VIPS".to_string(), "7=198.51.100.1;8=198.51.100.2".to_string()),
+                ("RR_GRE_SEQUENCE".to_string(), "strip".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.gre_key_vips[&8], IpAddr::V4(Ipv4Addr::new(198, 51, 100, 2)));
+    assert!(config.gre_strip_sequence);
+    for &(name, bad) in &[("RR_GRE_KEY_VIPS", "x=198.51.100.1"),
+                          ("RR_GRE_KEY_VIPS", "7"),
+                          ("RR_GRE_SEQUENCE", "renumber")] {
+        let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                    ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                    (name.to_string(), bad.to_string())];
+        assert!(Config::new(vars.iter().cloned()).is_err(), "{}", bad);
+    }
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_BACKEND".to_string(), "xdp".to_string()),
+                ("RR_GRE_SEQUENCE".to_string(), "strip".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn flow_hash() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.flow_hash, FlowHash::ThreeTuple);
+    assert!(config.vip_flow_hash.is_empty());
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_FLOW_HASH".to_string(), "5-tuple".to_string()),
+                ("RR_VIP_FLOW_HASH".to_string(), "198.51.100.1=3-tuple".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.flow_hash, FlowHash::FiveTuple);
+    assert_eq!(config.vip_flow_hash[&IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1))],
+               FlowHash::ThreeTuple);
+    for &(name, bad) in &[("RR_FLOW_HASH", "ports"),
+                          ("RR_VIP_FLOW_HASH", "198.51.100.1"),
+                          ("RR_VIP_FLOW_HASH", "198.51.100.1=4-tuple")] {
+        let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                    ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                    (name.to_string(), bad.to_string())];
+        assert!(Config::new(vars.iter().cloned()).is_err(), "{}", bad);
+    }
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_BACKEND".to_string(), "xdp".to_string()),
+                ("RR_VIP_FLOW_HASH".to_string(), "198.51.100.1=5-tuple".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn backend_weights() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1;192.0.2.2".to_string()),
+                ("RR_BACKEND_WEIGHTS".to_string(), "192.0.2.2=3".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    let weights: Vec<u32> =
+        config.routes.backends().iter().map(|b| b.as_ref().unwrap().weight()).collect();
+    assert_eq!(weights, vec![1, 3]);
+    for bad in &["192.0.2.2", "192.0.2.2=0", "192.0.2.2=heavy", "192.0.2.3=2"] {
+        let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                    ("RR_TARGET_IPS".to_string(), "192.0.2.1;192.0.2.2".to_string()),
+                    ("RR_BACKEND_WEIGHTS".to_string(), bad.to_string())];
+        assert!(Config::new(vars.iter().cloned()).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn table_size() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1;192.0.2.2".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.routes.lookup().len(), 65537);
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1;192.0.2.2".to_string()),
+                ("RR_TABLE_SIZE".to_string(), "1000".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.routes.lookup().len(), 1009);
+    for bad in &["0", "-1", "big"] {
+        let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                    ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                    ("RR_TABLE_SIZE".to_string(), bad.to_string())];
+        assert!(Config::new(vars.iter().cloned()).is_err(), "{}", bad);
+    }
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_BACKEND".to_string(), "xdp".to_string()),
+                ("RR_TABLE_SIZE".to_string(), "1048577".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn keys() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1;192.0.2.2".to_string())];
+    let unkeyed = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(unkeyed.flow_key, (0, 0));
+    assert_eq!(unkeyed.routes.key(), (0, 0));
+    assert!(unkeyed.rotation.is_none());
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1;192.0.2.2".to_string()),
+                ("RR_FLOW_KEY".to_string(), "000102030405060708090a0b0c0d0e0f".to_string()),
+                ("RR_PERMUTATION_KEY".to_string(), "0f0e0d0c0b0a09080706050403020100".to_string()),
+                ("RR_PREVIOUS_PERMUTATION_KEY".to_string(),
+                 "00000000000000000000000000000000".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.flow_key, (0x0001020304050607, 0x08090a0b0c0d0e0f));
+    assert!(config.routes.lookup() != unkeyed.routes.lookup());
+    // Only the permutation key is rotating.
+    let rotation = config.rotation.unwrap();
+    assert_eq!(rotation.flow_key, config.flow_key);
+    assert_eq!(rotation.routes.lookup(), unkeyed.routes.lookup());
+    for bad in &["0001020304050607",
+                 "000102030405060708090a0b0c0d0e0g",
+                 "+001020304050607+8090a0b0c0d0e0f"] {
+        let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                    ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                    ("RR_FLOW_KEY".to_string(), bad.to_string())];
+        assert!(Config::new(vars.iter().cloned()).is_err(), "{}", bad);
+    }
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string()),
+                ("RR_BACKEND".to_string(), "xdp".to_string()),
+                ("RR_PREVIOUS_FLOW_KEY".to_string(),
+                 "00000000000000000000000000000000".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
 }
 
 #[test]
@@ -52,31 +816,78 @@ fn multiple_ips() {
     let config = Config::new(vars.iter().cloned()).unwrap();
     assert_eq!(config.device, "wlan0");
     assert_eq!(config.target_ips,
-               vec![Ipv4Addr::from_str("192.0.2.1").unwrap(),
-                    Ipv4Addr::from_str("192.0.2.2").unwrap()]);
+               vec![IpAddr::from_str("192.0.2.1").unwrap(),
+                    IpAddr::from_str("192.0.2.2").unwrap()]);
+}
+
+#[test]
+fn ipv6_targets() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1;2001:db8::10".to_string())];
+    let config = Config::new(vars.iter().cloned()).unwrap();
+    assert_eq!(config.target_ips[1], IpAddr::from_str("2001:db8::10").unwrap());
+    for &(name, value) in &[("RR_ENCAP", "vxlan"),
+                            ("RR_BACKEND_ENCAP", "2001:db8::10=fou:5555"),
+                            ("RR_BACKEND", "xdp")] {
+        let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                    ("RR_TARGET_IPS".to_string(), "192.0.2.1;2001:db8::10".to_string()),
+                    (name.to_string(), value.to_string())];
+        assert!(Config::new(vars.iter().cloned()).is_err(), "{}", value);
+    }
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "2001:db8::10".to_string()),
+                ("RR_ENCAP".to_string(), "ipip".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_ok());
 }
 
 #[test]
-#[should_panic]
-#[allow(unused_must_use)]
 fn no_device_error() {
     let vars = [("RR_TARGET_IPS".to_string(), "192.0.2.1".to_string())];
-    Config::new(vars.iter().cloned());
+    assert!(Config::new(vars.iter().cloned()).is_err());
 }
 
 #[test]
-#[should_panic]
-#[allow(unused_must_use)]
 fn no_ip_error() {
     let vars = [("RR_DEVICE".to_string(), "wlan0".to_string())];
-    Config::new(vars.iter().cloned());
+    assert!(Config::new(vars.iter().cloned()).is_err());
 }
 
 #[test]
-#[should_panic]
-#[allow(unused_must_use)]
 fn zero_length_ips_error() {
     let vars = [("RR_DEVICE".to_string(), "wlan0".to_string()),
                 ("RR_TARGET_IPS".to_string(), "".to_string())];
-    Config::new(vars.iter().cloned());
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn bad_ip_error() {
+    let vars = [("RR_DEVICE".to_string(), "wlan0".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1;192.0.2.300".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
+}
+
+#[test]
+fn reload_routes() {
+    let vars = vec![("RR_DEVICE".to_string(), "lo".to_string()),
+                    ("RR_TARGET_IPS".to_string(), "192.0.2.1;192.0.2.2".to_string())];
+    let routes = Config::new(vars.into_iter()).unwrap().lookup_tables();
+    let reload = |targets: &str| {
+        let vars = vec![("RR_DEVICE".to_string(), "lo".to_string()),
+                        ("RR_TARGET_IPS".to_string(), targets.to_string())];
+        Config::new(vars.into_iter()).and_then(|config| config.update_routes(&routes))
+    };
+    // A bad reload is an error rather than a panic, leaving the routes to forward as before.
+    assert!(reload("192.0.2.1;bogus").is_err());
+    assert!(reload("").is_err());
+    let (reloaded, disruption) = reload("192.0.2.1;192.0.2.2;192.0.2.3").unwrap();
+    assert_eq!(reloaded.current.backends().iter().filter(|b| b.is_some()).count(), 3);
+    assert!(disruption.fraction > 0.0 && disruption.fraction < 0.5);
+    assert_eq!(routes.current.backends().iter().filter(|b| b.is_some()).count(), 2);
+}
+
+#[test]
+fn duplicate_ips_error() {
+    let vars = [("RR_DEVICE".to_string(), "lo".to_string()),
+                ("RR_TARGET_IPS".to_string(), "192.0.2.1;192.0.2.2;192.0.2.1".to_string())];
+    assert!(Config::new(vars.iter().cloned()).is_err());
 }
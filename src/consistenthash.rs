@@ -3,48 +3,284 @@
 // Consistent hashing for selecting backends.
 
 use std::hash::{Hash, Hasher};
-use std::net::Ipv4Addr;
+use std::mem;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
 use siphasher::sip::SipHasher;
 
+use super::error;
 use super::primes;
 
+#[derive(Clone)]
 pub struct Backend {
-    pub name: String,
+    name: String,
     /// Should this backend receive new traffic.
-    pub live: bool,
-    pub target: Ipv4Addr, // | Ipv6Addr, weight
-    pub permutation: Vec<u32>,
+    live: bool,
+    target: IpAddr,
+    /// How many slots the backend takes per round of populating, relative to the others.
+    weight: u32,
+    permutation: Vec<u32>,
+    /// Removed, but still in the lookup table until the next populate.
+    removed: bool,
 }
 
 impl Backend {
-    pub fn new(name: &str, target: Ipv4Addr) -> Backend {
+    pub fn new<T: Into<IpAddr>>(name: &str, target: T) -> Backend {
         Backend {
             name: name.to_string(),
             live: true,
-            target: target,
+            target: target.into(),
+            weight: 1,
             permutation: vec![],
+            removed: false,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn live(&self) -> bool {
+        self.live
+    }
+
+    pub fn target(&self) -> IpAddr {
+        self.target
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
 }
 
+#[derive(Clone)]
 pub struct ConsistentHash {
-    // vector of known backends. References to this are held by lookup using their offset: never
-    // shrink except by marking the tail nodes not alive, populating the lookup table, then finally
-    // popping.
-    pub backends: Vec<Backend>,
-    pub lookup: Vec<u32>,
+    // Known backends, in the slots lookup refers to them by. A slot is only freed, to be reused or
+    // popped from the end, by populating the lookup table away from it.
+    backends: Vec<Option<Backend>>,
+    lookup: Vec<u32>,
+    /// The SipHash key for generating permutations. Every load balancer needs the same key to
+    /// build the same table, and nobody else should know it.
+    key: (u64, u64),
+    /// The number of lookup table slots: a prime, fixed whichever backends are live, so that
+    /// populating only moves the slots it must, and never regenerates the permutations.
+    size: u32,
 }
 
+/// The lookup table size unless set otherwise: the Maglev paper's smaller example.
+pub const DEFAULT_SIZE: usize = 65537;
+
+/// How far rebuilding a lookup table moves its slots between backends.
+#[derive(Debug, PartialEq)]
+pub struct Disruption {
+    /// The fraction of the table's slots that moved to a different backend.
+    pub fraction: f64,
+    /// Slots gained by each backend, by index.
+    pub gained: Vec<usize>,
+    /// Slots lost by each backend, by index.
+    pub lost: Vec<usize>,
+    /// The slots that moved, in ascending order.
+    pub moved: Vec<usize>,
+}
+
+impl Disruption {
+    /// Compare two lookup tables over the same backends. Slots only one of them has count as
+    /// moved.
+    pub fn new(before: &[u32], after: &[u32], backends: usize) -> Disruption {
+        let size = before.len().max(after.len());
+        let mut disruption = Disruption {
+            fraction: 0.0,
+            gained: vec![0; backends],
+            lost: vec![0; backends],
+            moved: vec![],
+        };
+        for slot in 0..size {
+            let (old, new) = (before.get(slot), after.get(slot));
+            if old == new {
+                continue;
+            }
+            if let Some(old) = old {
+                disruption.lost[*old as usize] += 1;
+            }
+            if let Some(new) = new {
+                disruption.gained[*new as usize] += 1;
+            }
+            disruption.moved.push(slot);
+        }
+        if size > 0 {
+            disruption.fraction = disruption.moved.len() as f64 / size as f64;
+        }
+        disruption
+    }
+}
 
 impl ConsistentHash {
     pub fn new() -> ConsistentHash {
         ConsistentHash {
             backends: vec![],
             lookup: vec![],
+            key: (0, 0),
+            size: DEFAULT_SIZE as u32,
+        }
+    }
+
+    /// Size the lookup table at the first prime at or above size, for populate to fill. More slots
+    /// per backend spread traffic more evenly, at the cost of memory: Maglev suggests at least
+    /// 100 per backend.
+    pub fn set_size(&mut self, size: usize) {
+        self.size = primes::next_prime(size);
+    }
+
+    /// The number of lookup table slots.
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Generate permutations under key from the next populate, which then moves practically
+    /// every slot.
+    pub fn set_key(&mut self, key: (u64, u64)) {
+        self.key = key;
+        for backend in self.backends.iter_mut().filter_map(|b| b.as_mut()) {
+            backend.permutation = vec![];
         }
     }
 
+    pub fn key(&self) -> (u64, u64) {
+        self.key
+    }
+
+    /// The backends, by the index the lookup table refers to them with: None where one was
+    /// removed.
+    pub fn backends(&self) -> &[Option<Backend>] {
+        &self.backends
+    }
+
+    /// The lookup table, of indices into backends.
+    pub fn lookup(&self) -> &[u32] {
+        &self.lookup
+    }
+
+    /// The target of the backend selected by hash, or None when no backend was both live and
+    /// weighted at the last populate.
+    pub fn target(&self, hash: u64) -> Option<IpAddr> {
+        if self.lookup.is_empty() {
+            return None;
+        }
+        let backend_idx = self.lookup[hash as usize % self.lookup.len()];
+        match self.backends[backend_idx as usize] {
+            Some(ref backend) => Some(backend.target),
+            None => unreachable!("lookup refers to a free backend slot"),
+        }
+    }
+
+    /// Add backend in the first free slot, returning its index. It takes no traffic until the
+    /// next populate.
+    pub fn add_backend(&mut self, backend: Backend) -> Result<usize, error::BrokenRail> {
+        let taken = self.backends
+            .iter()
+            .filter_map(|b| b.as_ref())
+            .any(|b| !b.removed && b.name == backend.name);
+        if taken {
+            let msg = format!("duplicate backend {}", backend.name);
+            return Err(error::BrokenRail::Configuration(msg));
+        }
+        match self.backends.iter().position(|b| b.is_none()) {
+            Some(free) => {
+                self.backends[free] = Some(backend);
+                Ok(free)
+            }
+            None => {
+                self.backends.push(Some(backend));
+                Ok(self.backends.len() - 1)
+            }
+        }
+    }
+
+    /// Remove the backend called name. Like a backend that is not live it keeps its traffic until
+    /// the next populate, which frees its slot once the lookup table no longer refers to it. Its
+    /// name is free straight away.
+    pub fn remove_backend(&mut self, name: &str) -> Result<(), error::BrokenRail> {
+        let backend = try!(self.named(name));
+        backend.live = false;
+        backend.removed = true;
+        Ok(())
+    }
+
+    /// Start or stop sending new traffic to the backend called name, from the next populate.
+    pub fn set_live(&mut self, name: &str, live: bool) -> Result<(), error::BrokenRail> {
+        try!(self.named(name)).live = live;
+        Ok(())
+    }
+
+    /// Set the slots per round the backend called name takes, from the next populate.
+    pub fn set_weight(&mut self, name: &str, weight: u32) -> Result<(), error::BrokenRail> {
+        try!(self.named(name)).weight = weight;
+        Ok(())
+    }
+
+    /// Add, remove, reweight and start or stop backends to match other's, by name and target,
+    /// from the next populate. Backends in both keep their slots, so that populating moves as
+    /// little as possible.
+    pub fn update_backends(&mut self, other: &ConsistentHash) -> Result<(), error::BrokenRail> {
+        let current = |hash: &ConsistentHash| -> Vec<Backend> {
+            hash.backends.iter().filter_map(|b| b.clone()).filter(|b| !b.removed).collect()
+        };
+        let wanted = current(other);
+        for backend in current(self) {
+            if !wanted.iter().any(|b| b.name == backend.name && b.target == backend.target) {
+                try!(self.remove_backend(&backend.name));
+            }
+        }
+        for backend in wanted {
+            if self.named(&backend.name).is_err() {
+                try!(self.add_backend(Backend::new(&backend.name, backend.target)));
+            }
+            let ours = try!(self.named(&backend.name));
+            ours.live = backend.live;
+            ours.weight = backend.weight;
+        }
+        Ok(())
+    }
+
+    fn named(&mut self, name: &str) -> Result<&mut Backend, error::BrokenRail> {
+        match self.backends
+            .iter_mut()
+            .filter_map(|b| b.as_mut())
+            .find(|b| !b.removed && b.name == name) {
+            Some(backend) => Ok(backend),
+            None => Err(error::BrokenRail::Configuration(format!("unknown backend {}", name))),
+        }
+    }
+
+    /// Populate a copy of this table with the current backend settings, leaving this one as it
+    /// is, and report how far the copy moves from it: the blast radius of a change to the
+    /// backends, before committing to it.
+    ///
+    /// ```
+    /// use rusty_rail::consistenthash::{Backend, ConsistentHash};
+    ///
+    /// let mut c = ConsistentHash::new();
+    /// for name in &["server-1", "server-2", "server-3"] {
+    ///     c.add_backend(Backend::new(name, [192, 0, 2, 1])).unwrap();
+    /// }
+    /// c.populate();
+    /// c.set_live("server-3", false).unwrap();
+    /// let (next, disruption) = c.rebuild();
+    /// assert_eq!(disruption.lost[2], c.lookup().iter().filter(|b| **b == 2).count());
+    /// assert_eq!(disruption.gained[2], 0);
+    /// assert!(disruption.fraction < 0.5);
+    /// c = next;
+    /// assert!(c.lookup().iter().all(|b| *b != 2));
+    /// ```
+    pub fn rebuild(&self) -> (ConsistentHash, Disruption) {
+        let mut next = self.clone();
+        next.populate();
+        let disruption = Disruption::new(&self.lookup, &next.lookup, self.backends.len());
+        (next, disruption)
+    }
+
     /// Populate the lookup table based on the current backend settings.
     ///
     /// ```
@@ -55,13 +291,14 @@ impl ConsistentHash {
     ///
     /// let mut c = consistenthash::ConsistentHash::new();
     /// let tgt = Ipv4Addr::from_str("1.2.3.4").unwrap();
-    /// c.backends.push(consistenthash::Backend::new("server-1", tgt));
-    /// c.backends.push(consistenthash::Backend::new("server-2", tgt));
-    /// c.backends.push(consistenthash::Backend::new("server-3", tgt));
-    /// c.backends.push(consistenthash::Backend::new("server-4", tgt));
-    /// c.backends[3].live = false;
+    /// c.add_backend(consistenthash::Backend::new("server-1", tgt)).unwrap();
+    /// c.add_backend(consistenthash::Backend::new("server-2", tgt)).unwrap();
+    /// c.add_backend(consistenthash::Backend::new("server-3", tgt)).unwrap();
+    /// c.add_backend(consistenthash::Backend::new("server-4", tgt)).unwrap();
+    /// c.set_live("server-4", false).unwrap();
+    /// c.set_size(290);
     /// c.populate();
-    /// assert_eq!(c.lookup,
+    /// assert_eq!(c.lookup(),
     /// vec!
     /// [2, 1, 1, 0, 1, 2, 0, 1, 2, 1, 2, 0, 2, 2, 0, 2, 1, 0, 2, 1, 2, 2, 0, 2, 0, 0, 1, 0, 1, 2,
     /// 0, 1, 0, 0, 2, 0, 2, 2, 1, 2, 1, 0, 2, 1, 2, 2, 0, 2, 0, 0, 2, 0, 1, 0, 0, 1, 0, 0, 2, 0, 0,
@@ -75,14 +312,29 @@ impl ConsistentHash {
     /// 1, 2, 0, 2, 2, 0, 2, 2, 2, 2, 1, 2, 2, 0, 1]);
     /// ```
     pub fn populate(&mut self) {
-        // This is 'approximately' 100x the number of backends; could look for the next higher
-        // prime in future to ensure that.
-        let lookup_size = self.backends.iter().filter(|b| b.live).count() * 100;
-        let p = primes::primes(lookup_size);
-        let lookup_size = p[p.len() - 1] as u32;
-        for backend in &mut self.backends {
+        if self.backends.iter().filter_map(|b| b.as_ref()).any(|b| b.live && b.weight > 0) {
+            self.fill();
+        } else {
+            // Nothing to fill it with.
+            self.lookup = vec![];
+        }
+        // Nothing refers to removed backends now, so their slots can be reused.
+        for slot in &mut self.backends {
+            if slot.as_ref().map_or(false, |b| b.removed) {
+                *slot = None;
+            }
+        }
+        while let Some(&None) = self.backends.last() {
+            self.backends.pop();
+        }
+    }
+
+    fn fill(&mut self) {
+        let lookup_size = self.size;
+        for backend in self.backends.iter_mut().filter_map(|b| b.as_mut()) {
             if backend.permutation.len() != lookup_size as usize {
-                backend.permutation = permute_backend(&backend.name, lookup_size)
+                backend.permutation =
+                    permute_backend(&backend.name, lookup_size, self.key.0, self.key.1)
             }
         }
         let mut next = vec![0; self.backends.len()];
@@ -90,26 +342,370 @@ impl ConsistentHash {
         let mut allocated = 0;
         loop {
             for (i, backend) in self.backends.iter().enumerate() {
-                if !backend.live {
-                    continue;
-                }
-                let mut candidate = backend.permutation[next[i]];
-                while self.lookup[candidate as usize] != u32::max_value() {
-                    // Find next unallocated position from backend.
+                let backend = match *backend {
+                    Some(ref backend) if backend.live => backend,
+                    _ => continue,
+                };
+                // Heavier backends take more turns per round.
+                for _ in 0..backend.weight {
+                    let mut candidate = backend.permutation[next[i]];
+                    while self.lookup[candidate as usize] != u32::max_value() {
+                        // Find next unallocated position from backend.
+                        next[i] += 1;
+                        candidate = backend.permutation[next[i]];
+                    }
+                    self.lookup[candidate as usize] = i as u32;
                     next[i] += 1;
-                    candidate = backend.permutation[next[i]];
-                }
-                self.lookup[candidate as usize] = i as u32;
-                next[i] += 1;
-                allocated += 1;
-                if allocated == lookup_size {
-                    return;
+                    allocated += 1;
+                    if allocated == lookup_size {
+                        return;
+                    }
                 }
             }
         }
     }
 }
 
+/// The lookup tables forwarding picks backends from.
+#[derive(Clone)]
+pub struct Routes {
+    pub current: ConsistentHash,
+    /// While keys are being rotated, the table permuted under the previous key.
+    pub previous: Option<ConsistentHash>,
+}
+
+/// Lookup tables published for forwarding threads, so that backends can change without a
+/// restart.
+///
+/// The control plane builds and populates each new set of tables off the forwarding path and
+/// publishes it here; each thread reads its own snapshot through a View. Publishing swaps a
+/// pointer, and a View picks up new tables with atomic loads alone, never waiting on a lock.
+/// Replaced tables are retired with the generation that replaced them, and freed by a later
+/// publish once every View has moved on to that generation, so forwarding threads never free
+/// tables, let alone allocate them.
+pub struct Published {
+    shared: Arc<Shared>,
+    /// Replaced tables, with the generation that replaced them.
+    retired: Vec<(usize, Box<Routes>)>,
+}
+
+/// The part of Published its Views share.
+struct Shared {
+    /// The current tables, owned through Box::into_raw.
+    current: AtomicPtr<Routes>,
+    generation: AtomicUsize,
+    /// The generation each View has moved on to. Only locked to add or drop a View, or to find
+    /// the oldest.
+    views: Mutex<Vec<Arc<AtomicUsize>>>,
+    /// Tables still retired when Published was dropped, freed with the last View.
+    orphans: Mutex<Vec<(usize, Box<Routes>)>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.current.load(Ordering::SeqCst))) }
+    }
+}
+
+impl Published {
+    pub fn new(routes: Routes) -> Published {
+        Published {
+            shared: Arc::new(Shared {
+                current: AtomicPtr::new(Box::into_raw(Box::new(routes))),
+                generation: AtomicUsize::new(0),
+                views: Mutex::new(vec![]),
+                orphans: Mutex::new(vec![]),
+            }),
+            retired: vec![],
+        }
+    }
+
+    /// A View of the published tables, for one forwarding thread.
+    pub fn view(&self) -> View {
+        View::new(self.shared.clone())
+    }
+
+    /// Replace the published tables with routes, which should already be populated.
+    pub fn publish(&mut self, routes: Routes) {
+        let routes = Box::into_raw(Box::new(routes));
+        let previous = self.shared.current.swap(routes, Ordering::SeqCst);
+        let generation = self.shared.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.retired.push((generation, unsafe { Box::from_raw(previous) }));
+        self.reclaim();
+    }
+
+    /// Free the retired tables every View has moved on from.
+    fn reclaim(&mut self) {
+        let oldest = self.shared
+            .views
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|seen| seen.load(Ordering::SeqCst))
+            .min()
+            .unwrap_or(usize::max_value());
+        self.retired.retain(|&(generation, _)| generation > oldest);
+    }
+}
+
+impl Drop for Published {
+    fn drop(&mut self) {
+        self.reclaim();
+        // Views can outlive Published: leave them whatever they may still be reading.
+        *self.shared.orphans.lock().unwrap() = mem::replace(&mut self.retired, vec![]);
+    }
+}
+
+/// One thread's snapshot of the Published tables.
+pub struct View {
+    shared: Arc<Shared>,
+    /// The generation this View has moved on to, for Published to free what it has left.
+    seen: Arc<AtomicUsize>,
+    generation: usize,
+    routes: *const Routes,
+}
+
+// The tables a View points at are only freed once it has moved on from them.
+unsafe impl Send for View {}
+
+impl View {
+    fn new(shared: Arc<Shared>) -> View {
+        let seen = Arc::new(AtomicUsize::new(0));
+        let generation = {
+            let mut views = shared.views.lock().unwrap();
+            let generation = shared.generation.load(Ordering::SeqCst);
+            seen.store(generation, Ordering::SeqCst);
+            views.push(seen.clone());
+            generation
+        };
+        // Whatever is current now is only retired by a later generation.
+        let routes = shared.current.load(Ordering::SeqCst);
+        View {
+            shared: shared,
+            seen: seen,
+            generation: generation,
+            routes: routes,
+        }
+    }
+
+    pub fn routes(&self) -> &Routes {
+        unsafe { &*self.routes }
+    }
+
+    /// Pick up any newly published tables. Called between batches of packets.
+    pub fn refresh(&mut self) {
+        let generation = self.shared.generation.load(Ordering::SeqCst);
+        if generation != self.generation {
+            // Move on before loading the pointer: the tables loaded are current at the
+            // generation announced or later, so are retired by a later one.
+            self.seen.store(generation, Ordering::SeqCst);
+            self.routes = self.shared.current.load(Ordering::SeqCst);
+            self.generation = generation;
+        }
+    }
+}
+
+impl Drop for View {
+    fn drop(&mut self) {
+        let seen = &self.seen;
+        self.shared.views.lock().unwrap().retain(|view| !Arc::ptr_eq(view, seen));
+    }
+}
+
+#[test]
+fn publish_to_view() {
+    let mut first = ConsistentHash::new();
+    first.set_size(7);
+    first.add_backend(Backend::new("server-1", IpAddr::from([192, 0, 2, 1]))).unwrap();
+    first.populate();
+    let routes = |current: &ConsistentHash| {
+        Routes {
+            current: current.clone(),
+            previous: None,
+        }
+    };
+    let mut published = Published::new(routes(&first));
+    let mut view = published.view();
+    let mut current = view.routes().current.clone();
+    current.add_backend(Backend::new("server-2", IpAddr::from([192, 0, 2, 2]))).unwrap();
+    current.populate();
+    published.publish(routes(&current));
+    assert_eq!(view.routes().current.backends().len(), 1);
+    view.refresh();
+    assert_eq!(view.routes().current.backends().len(), 2);
+    assert!(view.routes().current.lookup().contains(&1));
+    // The view had not moved on at the first publish; by the second it has.
+    assert_eq!(published.retired.len(), 1);
+    published.publish(routes(&ConsistentHash::new()));
+    assert_eq!(published.retired.len(), 1);
+    assert_eq!(published.retired[0].1.current.backends.len(), 2);
+    // Nothing is kept for a view that has gone.
+    drop(view);
+    published.publish(routes(&current));
+    assert!(published.retired.is_empty());
+    // Views outlive Published, and anything retired they still need.
+    let view = published.view();
+    published.publish(routes(&ConsistentHash::new()));
+    drop(published);
+    assert_eq!(view.routes().current.backends().len(), 2);
+}
+
+#[test]
+fn weights() {
+    let mut c = ConsistentHash::new();
+    for weight in 1..5 {
+        let name = format!("server-{}", weight);
+        c.add_backend(Backend::new(&name, IpAddr::from([0u8; 4]))).unwrap();
+        c.set_weight(&name, weight).unwrap();
+    }
+    c.populate();
+    // Slot shares follow the weights, out of 10, to within a percent of the table.
+    for (i, backend) in c.backends.iter().enumerate() {
+        let backend = backend.as_ref().unwrap();
+        let slots = c.lookup.iter().filter(|b| **b == i as u32).count() as f64;
+        let share = slots / c.lookup.len() as f64;
+        assert!((share - backend.weight as f64 / 10.0).abs() < 0.01,
+                "{} has {}",
+                backend.name,
+                share);
+    }
+    // Weightless backends take no slots.
+    c.set_weight("server-1", 0).unwrap();
+    c.populate();
+    assert!(c.lookup.iter().all(|b| *b != 0));
+}
+
+#[test]
+fn minimal_disruption() {
+    let mut c = ConsistentHash::new();
+    for i in 0..10 {
+        c.add_backend(Backend::new(&format!("server-{}", i), IpAddr::from([0u8; 4]))).unwrap();
+    }
+    c.set_live("server-9", false).unwrap();
+    c.populate();
+    // Rebuilding as is moves nothing.
+    let (_, disruption) = c.rebuild();
+    assert_eq!(disruption.fraction, 0.0);
+    assert!(disruption.moved.is_empty());
+    // Losing one of nine backends moves all of its slots, spread over the rest, and under as many
+    // again of the others.
+    c.set_live("server-3", false).unwrap();
+    let (next, disruption) = c.rebuild();
+    let owned = c.lookup.iter().filter(|b| **b == 3).count();
+    assert_eq!(disruption.lost[3], owned);
+    assert_eq!(disruption.gained[3], 0);
+    assert!(disruption.moved.iter().all(|slot| next.lookup[*slot] != c.lookup[*slot]));
+    assert_eq!(disruption.gained.iter().sum::<usize>(), disruption.moved.len());
+    assert_eq!(disruption.lost.iter().sum::<usize>(), disruption.moved.len());
+    assert!(disruption.fraction < 2.0 / 9.0, "{}", disruption.fraction);
+    assert!(disruption.gained.iter().enumerate().all(|(i, g)| (i == 3 || i == 9) == (*g == 0)));
+    // Gaining a ninth backend takes slots from every other, and again moves under twice its
+    // share.
+    c.set_live("server-9", true).unwrap();
+    let (_, disruption) = c.rebuild();
+    assert!(disruption.lost.iter().enumerate().all(|(i, l)| (i == 9) == (*l == 0)));
+    assert!(disruption.fraction < 2.0 / 9.0, "{}", disruption.fraction);
+    // Resizing moves practically everything, and every slot past the new end.
+    c.set_size(1000);
+    let (_, disruption) = c.rebuild();
+    assert!(disruption.fraction > 0.99, "{}", disruption.fraction);
+    assert!((1009..DEFAULT_SIZE).all(|slot| disruption.moved.binary_search(&slot).is_ok()));
+}
+
+#[test]
+fn membership() {
+    let mut c = ConsistentHash::new();
+    c.set_size(101);
+    let backend = |name: &str, last: u8| Backend::new(name, IpAddr::from([192, 0, 2, last]));
+    for i in 0..3 {
+        assert_eq!(c.add_backend(backend(&format!("server-{}", i), i)).unwrap(), i as usize);
+    }
+    assert!(c.add_backend(backend("server-1", 9)).is_err());
+    assert!(c.set_live("server-9", false).is_err());
+    assert!(c.set_weight("server-9", 2).is_err());
+    assert!(c.remove_backend("server-9").is_err());
+    c.populate();
+    // A removed backend keeps its slot, and its traffic, until populated away; its name is free
+    // straight away.
+    c.remove_backend("server-1").unwrap();
+    assert!(c.remove_backend("server-1").is_err());
+    assert!(c.lookup().contains(&1));
+    assert_eq!(c.add_backend(backend("server-1", 11)).unwrap(), 3);
+    c.populate();
+    assert!(c.backends()[1].is_none());
+    assert!(c.lookup().iter().all(|b| *b != 1));
+    assert!(c.lookup().contains(&3));
+    // Freed slots are reused, and freed slots at the end dropped.
+    assert_eq!(c.add_backend(backend("server-4", 4)).unwrap(), 1);
+    c.remove_backend("server-1").unwrap();
+    c.populate();
+    let names: Vec<&str> = c.backends().iter().map(|b| b.as_ref().unwrap().name()).collect();
+    assert_eq!(names, vec!["server-0", "server-4", "server-2"]);
+    assert!(c.lookup().contains(&1));
+    // Without backends nothing is selected.
+    for name in &["server-0", "server-4", "server-2"] {
+        c.remove_backend(name).unwrap();
+    }
+    assert!(c.target(0).is_some());
+    c.populate();
+    assert!(c.backends().is_empty());
+    assert_eq!(c.target(0), None);
+}
+
+#[test]
+fn update_backends() {
+    let hash = |backends: &[(&str, u8, u32)]| {
+        let mut c = ConsistentHash::new();
+        c.set_size(101);
+        for &(name, last, weight) in backends {
+            c.add_backend(Backend::new(name, [192, 0, 2, last])).unwrap();
+            c.set_weight(name, weight).unwrap();
+        }
+        c.populate();
+        c
+    };
+    let mut c = hash(&[("server-0", 0, 1), ("server-1", 1, 1), ("server-2", 2, 1)]);
+    let other = hash(&[("server-1", 1, 2), ("server-2", 12, 1), ("server-3", 3, 1)]);
+    c.update_backends(&other).unwrap();
+    let (next, disruption) = c.rebuild();
+    // Only server-1 keeps its slot, and with twice the weight it gains far more than it loses.
+    assert!(disruption.lost[1] < disruption.gained[1] / 2, "{:?}", disruption);
+    // Replacements take new slots, as the old ones were still in use until populated away.
+    let backends: Vec<(usize, &str, IpAddr, u32)> = next.backends()
+        .iter()
+        .enumerate()
+        .filter_map(|(i, b)| b.as_ref().map(|b| (i, b.name(), b.target(), b.weight())))
+        .collect();
+    assert_eq!(backends,
+               vec![(1, "server-1", IpAddr::from([192, 0, 2, 1]), 2),
+                    (3, "server-2", IpAddr::from([192, 0, 2, 12]), 1),
+                    (4, "server-3", IpAddr::from([192, 0, 2, 3]), 1)]);
+    // Updating to match again changes nothing.
+    let mut again = next.clone();
+    again.update_backends(&other).unwrap();
+    assert_eq!(again.rebuild().1.fraction, 0.0);
+}
+
+#[test]
+fn rekey() {
+    let build = |key| {
+        let mut c = ConsistentHash::new();
+        c.set_size(101);
+        c.set_key(key);
+        for i in 0..3 {
+            c.add_backend(Backend::new(&format!("server-{}", i), [192, 0, 2, i])).unwrap();
+        }
+        c.populate();
+        c
+    };
+    let mut c = build((0, 0));
+    c.set_key((1, 2));
+    c.populate();
+    assert_eq!(c.key(), (1, 2));
+    assert_eq!(c.lookup(), build((1, 2)).lookup());
+    assert!(c.lookup() != build((0, 0)).lookup());
+}
+
 /// Generate permutations for a given offset, skip, pool
 ///
 /// ```
@@ -121,24 +717,26 @@ impl ConsistentHash {
 /// ```
 pub fn permutations(offset: u32, skip: u32, pool_size: u32) -> Vec<u32> {
     let mut res: Vec<u32> = Vec::with_capacity(pool_size as usize);
-    for pos in 0..pool_size {
-        res.push((offset + pos * skip) % pool_size)
+    for pos in 0..pool_size as u64 {
+        // In 64 bits, as pos * skip overflows 32 for tables past 65536 slots.
+        res.push(((offset as u64 + pos * skip as u64) % pool_size as u64) as u32)
     }
     res
 }
 
-/// Generate permutations for a given name and pool size.
+/// Generate permutations for a given name and pool size, under the SipHash key k0, k1.
 ///
 /// ```
 /// use rusty_rail::consistenthash::permute_backend;
 ///
-/// assert_eq!(permute_backend("fred", 7), vec![1, 0, 6, 5, 4, 3, 2]);
-/// assert_eq!(permute_backend("ralph", 7), vec![3, 2, 1, 0, 6, 5, 4]);
-/// assert_eq!(permute_backend("larry", 7), vec![4, 0, 3, 6, 2, 5, 1]);
+/// assert_eq!(permute_backend("fred", 7, 0, 0), vec![1, 0, 6, 5, 4, 3, 2]);
+/// assert_eq!(permute_backend("ralph", 7, 0, 0), vec![3, 2, 1, 0, 6, 5, 4]);
+/// assert_eq!(permute_backend("larry", 7, 0, 0), vec![4, 0, 3, 6, 2, 5, 1]);
+/// assert_eq!(permute_backend("larry", 7, 1, 2), vec![3, 5, 0, 2, 4, 6, 1]);
 /// ```
-pub fn permute_backend(name: &str, pool_size: u32) -> Vec<u32> {
+pub fn permute_backend(name: &str, pool_size: u32, k0: u64, k1: u64) -> Vec<u32> {
     // May be faster to generate just-in-time as an iterator: profile eventually.
-    let mut s = SipHasher::new();
+    let mut s = SipHasher::new_with_keys(k0, k1);
     name.hash(&mut s);
     let offset = (s.finish() % pool_size as u64) as u32;
     "differenthash".hash(&mut s);
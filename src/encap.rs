@@ -0,0 +1,441 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// Tunnel encapsulations between the load balancer and its routers and backends.
+//
+// Packets arrive wrapped by the router in one encapsulation and leave wrapped in the backend
+// pool's. When the two match, move_packets rewrites the outer headers in place; otherwise the
+// outer headers are written afresh here, in front of the inner packet.
+//
+// VXLAN and GENEVE carry Ethernet: the inner Ethernet header is part of the encapsulation here,
+// and the inner packet is always IPv4 on the way in. They, FOU and GUE are always written afresh,
+// as the UDP source port, VNI and inner Ethernet header are ours to choose. FOU and GUE are only
+// sent: with no fixed port, they cannot be told apart from other UDP on the way in. Their UDP
+// checksum is zero, which IPv6 does not allow, so they only reach IPv4 backends.
+
+use std::net::IpAddr;
+
+use pnet::packet::MutablePacket;
+use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::ipv6::MutableIpv6Packet;
+use pnet::packet::udp::MutableUdpPacket;
+use pnet::util::MacAddr;
+
+use super::checksum;
+
+pub const ETHERNET_HEADER: usize = 14;
+pub const IPV4_HEADER: usize = 20;
+pub const IPV6_HEADER: usize = 40;
+pub const UDP_HEADER: usize = 8;
+pub const VXLAN_PORT: u16 = 4789;
+pub const GENEVE_PORT: u16 = 6081;
+const VXLAN_HEADER: usize = 8;
+const GENEVE_HEADER: usize = 8;
+const GUE_HEADER: usize = 4;
+/// Conventional ports for FOU, which has none assigned, and GUE.
+pub const FOU_PORT: u16 = 5555;
+pub const GUE_PORT: u16 = 6080;
+// The VNI is valid.
+const VXLAN_I: u8 = 0x08;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ETHERNET: u16 = 0x6558;
+/// Room for the largest headers write_headers produces.
+pub const MAX_HEADERS: usize = ETHERNET_HEADER + IPV6_HEADER + UDP_HEADER + GENEVE_HEADER +
+                               ETHERNET_HEADER;
+
+/// How packets are wrapped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encap {
+    /// GRE, protocol 47.
+    Gre,
+    /// IPv4 in IPv4, protocol 4, or IPv6 in IPv4, protocol 41.
+    Ipip,
+    /// VXLAN with the given VNI.
+    Vxlan(u32),
+    /// GENEVE with the given VNI, without options.
+    Geneve(u32),
+    /// Foo-over-UDP to the given port: the inner packet directly in UDP.
+    Fou(u16),
+    /// Generic UDP Encapsulation to the given port, without options.
+    Gue(u16),
+    /// No encapsulation: the inner packet in an Ethernet frame to the backend, for direct server
+    /// return on the same segment.
+    Direct,
+}
+
+impl Encap {
+    /// Length of the headers between the outer IP header and the inner packet.
+    pub fn header_len(&self) -> usize {
+        match *self {
+            Encap::Gre => 4,
+            Encap::Ipip | Encap::Direct => 0,
+            Encap::Vxlan(_) => UDP_HEADER + VXLAN_HEADER + ETHERNET_HEADER,
+            Encap::Geneve(_) => UDP_HEADER + GENEVE_HEADER + ETHERNET_HEADER,
+            Encap::Fou(_) => UDP_HEADER,
+            Encap::Gue(_) => UDP_HEADER + GUE_HEADER,
+        }
+    }
+
+    /// The VNI, for encapsulations that have one.
+    pub fn vni(&self) -> Option<u32> {
+        match *self {
+            Encap::Vxlan(vni) | Encap::Geneve(vni) => Some(vni),
+            _ => None,
+        }
+    }
+
+    /// Whether the outer headers can be rewritten in place when this is also how the packet
+    /// arrived.
+    pub fn in_place(&self) -> bool {
+        match *self {
+            Encap::Gre | Encap::Ipip => true,
+            Encap::Vxlan(_) | Encap::Geneve(_) | Encap::Fou(_) | Encap::Gue(_) |
+            Encap::Direct => false,
+        }
+    }
+}
+
+/// The optional fields of a GRE header (RFC 2890).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GreOptions {
+    /// The checksum is present, covering the GRE header and payload.
+    pub checksum: bool,
+    pub key: Option<u32>,
+    pub sequence: Option<u32>,
+}
+
+impl GreOptions {
+    /// Length of a GRE header with these options.
+    pub fn header_len(&self) -> usize {
+        4 + self.checksum as usize * 4 + self.key.is_some() as usize * 4 +
+        self.sequence.is_some() as usize * 4
+    }
+}
+
+/// A parsed GRE header.
+#[derive(Debug, PartialEq)]
+pub struct GreHeader {
+    pub protocol: u16,
+    pub options: GreOptions,
+}
+
+/// Why a GRE header was rejected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GreError {
+    /// Shorter than its flags say.
+    Truncated,
+    /// Source routed (RFC 1701), or a version other than 0.
+    Unsupported,
+    /// The checksum is present and wrong.
+    Checksum,
+}
+
+const GRE_C: u8 = 0x80;
+const GRE_R: u8 = 0x40;
+const GRE_K: u8 = 0x20;
+const GRE_S: u8 = 0x10;
+
+fn be32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+/// Parse the GRE header at the start of packet, the outer IPv4 payload, verifying the checksum
+/// if present.
+///
+/// ```
+/// use rusty_rail::encap::{self, GreError};
+///
+/// // Key 7 and sequence number 9.
+/// let packet = [0x30, 0, 0x08, 0, 0, 0, 0, 7, 0, 0, 0, 9, 0x45];
+/// let gre = encap::parse_gre(&packet).unwrap();
+/// assert_eq!(gre.protocol, 0x0800);
+/// assert_eq!((gre.options.key, gre.options.sequence), (Some(7), Some(9)));
+/// assert_eq!(gre.options.header_len(), 12);
+/// assert_eq!(encap::parse_gre(&packet[..8]), Err(GreError::Truncated));
+/// ```
+pub fn parse_gre(packet: &[u8]) -> Result<GreHeader, GreError> {
+    if packet.len() < 4 {
+        return Err(GreError::Truncated);
+    }
+    if packet[0] & GRE_R != 0 || packet[1] & 0x07 != 0 {
+        return Err(GreError::Unsupported);
+    }
+    let mut options = GreOptions::default();
+    let mut offset = 4;
+    let mut field = |present: bool| -> Result<Option<u32>, GreError> {
+        if !present {
+            return Ok(None);
+        }
+        if packet.len() < offset + 4 {
+            return Err(GreError::Truncated);
+        }
+        offset += 4;
+        Ok(Some(be32(&packet[offset - 4..offset])))
+    };
+    options.checksum = try!(field(packet[0] & GRE_C != 0)).is_some();
+    options.key = try!(field(packet[0] & GRE_K != 0));
+    options.sequence = try!(field(packet[0] & GRE_S != 0));
+    if options.checksum && checksum::checksum(packet) != 0 {
+        return Err(GreError::Checksum);
+    }
+    Ok(GreHeader {
+        protocol: (packet[2] as u16) << 8 | packet[3] as u16,
+        options: options,
+    })
+}
+
+/// What a UDP datagram holds.
+pub enum UdpPayload {
+    /// Not a tunnel: for the host.
+    NotTunnel,
+    /// A malformed tunnel, or one that does not carry IPv4.
+    Noise,
+    /// A tunnel, with the inner IPv4 packet at the given offset in the UDP payload.
+    Inner(Encap, usize),
+}
+
+/// Classify a UDP datagram by its destination port and payload.
+///
+/// ```
+/// use rusty_rail::encap::{self, Encap, UdpPayload};
+///
+/// let mut payload = vec![0x08, 0, 0, 0, 0, 0, 42, 0];
+/// payload.extend_from_slice(&[0; 12]);
+/// payload.extend_from_slice(&[0x08, 0x00]);
+/// match encap::udp_payload(encap::VXLAN_PORT, &payload) {
+///     UdpPayload::Inner(Encap::Vxlan(42), 22) => (),
+///     _ => panic!("not VXLAN"),
+/// }
+/// ```
+pub fn udp_payload(port: u16, payload: &[u8]) -> UdpPayload {
+    let (encap, ethernet) = match port {
+        VXLAN_PORT => {
+            if payload.len() < VXLAN_HEADER || payload[0] & VXLAN_I == 0 {
+                return UdpPayload::Noise;
+            }
+            (Encap::Vxlan(vni(&payload[4..7])), VXLAN_HEADER)
+        }
+        GENEVE_PORT => {
+            if payload.len() < GENEVE_HEADER || payload[0] >> 6 != 0 {
+                return UdpPayload::Noise;
+            }
+            let len = GENEVE_HEADER + (payload[0] & 0x3f) as usize * 4;
+            let encap = Encap::Geneve(vni(&payload[4..7]));
+            match (payload[2] as u16) << 8 | payload[3] as u16 {
+                ETHERTYPE_ETHERNET => (encap, len),
+                ETHERTYPE_IPV4 if len <= payload.len() => return UdpPayload::Inner(encap, len),
+                _ => return UdpPayload::Noise,
+            }
+        }
+        _ => return UdpPayload::NotTunnel,
+    };
+    // The Ethernet frame must hold IPv4.
+    let ethertype = ethernet + ETHERNET_HEADER - 2;
+    if payload.len() < ethernet + ETHERNET_HEADER ||
+       (payload[ethertype] as u16) << 8 | payload[ethertype + 1] as u16 != ETHERTYPE_IPV4 {
+        return UdpPayload::Noise;
+    }
+    UdpPayload::Inner(encap, ethernet + ETHERNET_HEADER)
+}
+
+fn vni(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32
+}
+
+/// The outer addresses of a packet being sent.
+pub struct Outer {
+    pub eth_src: MacAddr,
+    pub eth_dst: MacAddr,
+    pub ip_src: IpAddr,
+    pub ip_dst: IpAddr,
+    pub ttl: u8,
+    /// The inner flow's hash, spread over UDP source ports for ECMP and RSS.
+    pub flow_hash: u64,
+    /// The GRE options to send, when sending GRE.
+    pub gre: GreOptions,
+}
+
+/// Write the Ethernet, IP and encapsulation headers for the inner IPv4 or IPv6 packet into buf,
+/// returning their length. The outer IP header is IPv4 or IPv6 as the outer addresses are; UDP
+/// encapsulations are only written over IPv4, as their checksum is left out.
+///
+/// For Direct, only the Ethernet header is written, and the inner packet is sent unchanged.
+///
+/// An inner Ethernet header has the same addresses as the outer one, so backends terminating
+/// VXLAN or GENEVE should give the tunnel device their NIC's MAC address.
+///
+/// ```
+/// extern crate pnet;
+/// extern crate rusty_rail;
+///
+/// use std::net::{Ipv4Addr, Ipv6Addr};
+///
+/// use pnet::util::MacAddr;
+/// use rusty_rail::encap::{self, Encap, Outer};
+/// use rusty_rail::checksum;
+///
+/// # fn main() {
+/// let mut outer = Outer {
+///     eth_src: MacAddr::new(2, 0, 0, 0, 0, 1),
+///     eth_dst: MacAddr::new(2, 0, 0, 0, 0, 2),
+///     ip_src: Ipv4Addr::new(192, 0, 2, 1).into(),
+///     ip_dst: Ipv4Addr::new(192, 0, 2, 2).into(),
+///     ttl: 63,
+///     flow_hash: 0,
+///     gre: Default::default(),
+/// };
+/// let mut buf = [0u8; encap::MAX_HEADERS];
+/// let len = encap::write_headers(Encap::Ipip, &outer, &[0x45; 40], &mut buf);
+/// assert_eq!(len, 34);
+/// assert_eq!(buf[23], 4);
+/// assert_eq!(checksum::checksum(&buf[14..34]), 0);
+/// outer.ip_src = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into();
+/// outer.ip_dst = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).into();
+/// let len = encap::write_headers(Encap::Gre, &outer, &[0x45; 40], &mut buf);
+/// assert_eq!(len, 14 + 40 + 4);
+/// assert_eq!(&buf[12..14], &[0x86, 0xdd]);
+/// assert_eq!(buf[20], 47);
+/// # }
+/// ```
+pub fn write_headers(encap: Encap, outer: &Outer, inner: &[u8], buf: &mut [u8]) -> usize {
+    let tunnel_len = match encap {
+        Encap::Gre => outer.gre.header_len(),
+        _ => encap.header_len(),
+    };
+    let ip_header = match outer.ip_dst {
+        IpAddr::V4(_) => IPV4_HEADER,
+        IpAddr::V6(_) => IPV6_HEADER,
+    };
+    // The inner packet's type, as an ethertype and an IP protocol.
+    let (ethertype, protocol) = match inner.first().map(|b| b >> 4) {
+        Some(6) => (EtherTypes::Ipv6, IpNextHeaderProtocols::Ipv6),
+        _ => (EtherTypes::Ipv4, IpNextHeaderProtocols::Ipv4),
+    };
+    {
+        let mut eth = MutableEthernetPacket::new(buf).unwrap();
+        eth.set_source(outer.eth_src);
+        eth.set_destination(outer.eth_dst);
+        eth.set_ethertype(match (encap, outer.ip_dst) {
+            (Encap::Direct, _) => ethertype,
+            (_, IpAddr::V4(_)) => EtherTypes::Ipv4,
+            (_, IpAddr::V6(_)) => EtherTypes::Ipv6,
+        });
+    }
+    if encap == Encap::Direct {
+        return ETHERNET_HEADER;
+    }
+    let next_header = match encap {
+        Encap::Gre => IpNextHeaderProtocols::Gre,
+        Encap::Ipip => protocol,
+        Encap::Vxlan(_) | Encap::Geneve(_) | Encap::Fou(_) | Encap::Gue(_) => {
+            IpNextHeaderProtocols::Udp
+        }
+        // Returned above.
+        Encap::Direct => unreachable!(),
+    };
+    let payload_len = tunnel_len + inner.len();
+    match (outer.ip_src, outer.ip_dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut ip = MutableIpv4Packet::new(&mut buf[ETHERNET_HEADER..]).unwrap();
+            ip.set_version(4);
+            ip.set_header_length(5);
+            ip.set_dscp(0);
+            ip.set_ecn(0);
+            ip.set_total_length((IPV4_HEADER + payload_len) as u16);
+            ip.set_identification(0);
+            ip.set_flags(0);
+            ip.set_fragment_offset(0);
+            ip.set_ttl(outer.ttl);
+            ip.set_next_level_protocol(next_header);
+            ip.set_source(src);
+            ip.set_destination(dst);
+            ip.set_checksum(0);
+            let sum = checksum::checksum(&ip.packet_mut()[..IPV4_HEADER]);
+            ip.set_checksum(sum);
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut ip = MutableIpv6Packet::new(&mut buf[ETHERNET_HEADER..]).unwrap();
+            ip.set_version(6);
+            ip.set_traffic_class(0);
+            // The inner flow, for ECMP (RFC 6438).
+            ip.set_flow_label(outer.flow_hash as u32 & 0xfffff);
+            ip.set_payload_length(payload_len as u16);
+            ip.set_next_header(next_header);
+            ip.set_hop_limit(outer.ttl);
+            ip.set_source(src);
+            ip.set_destination(dst);
+        }
+        _ => panic!("outer addresses {} and {} differ in family", outer.ip_src, outer.ip_dst),
+    }
+    let tunnel = &mut buf[ETHERNET_HEADER + ip_header..];
+    let headers_len = ETHERNET_HEADER + ip_header + tunnel_len;
+    let (port, vni) = match encap {
+        Encap::Gre => {
+            let gre = &mut tunnel[..tunnel_len];
+            let options = &outer.gre;
+            gre[0] = 0;
+            gre[1] = 0;
+            gre[2] = (ethertype.0 >> 8) as u8;
+            gre[3] = ethertype.0 as u8;
+            let mut offset = 4;
+            for &(flag, value) in &[(GRE_C, if options.checksum { Some(0) } else { None }),
+                                    (GRE_K, options.key),
+                                    (GRE_S, options.sequence)] {
+                if let Some(value) = value {
+                    gre[0] |= flag;
+                    for i in 0..4 {
+                        gre[offset + i] = (value >> (24 - 8 * i)) as u8;
+                    }
+                    offset += 4;
+                }
+            }
+            if options.checksum {
+                let sum = checksum::checksum_parts(&[gre, inner]);
+                gre[4] = (sum >> 8) as u8;
+                gre[5] = sum as u8;
+            }
+            return headers_len;
+        }
+        Encap::Ipip => return headers_len,
+        Encap::Direct => unreachable!(),
+        Encap::Vxlan(vni) => (VXLAN_PORT, vni),
+        Encap::Geneve(vni) => (GENEVE_PORT, vni),
+        Encap::Fou(port) |
+        Encap::Gue(port) => (port, 0),
+    };
+    let mut udp = MutableUdpPacket::new(tunnel).unwrap();
+    // The dynamic port range, as RFC 7348 recommends.
+    udp.set_source(0xc000 | outer.flow_hash as u16 & 0x3fff);
+    udp.set_destination(port);
+    udp.set_length(payload_len as u16);
+    // Optional over IPv4, and the inner packet has its own.
+    udp.set_checksum(0);
+    let tunnel = udp.payload_mut();
+    match encap {
+        Encap::Fou(_) => return headers_len,
+        Encap::Gue(_) => {
+            // Version 0, no options, carrying IPv4 or IPv6 as the protocol says.
+            tunnel[..GUE_HEADER].copy_from_slice(&[0, protocol.0, 0, 0]);
+            return headers_len;
+        }
+        _ => (),
+    }
+    for b in tunnel[..8].iter_mut() {
+        *b = 0;
+    }
+    if port == VXLAN_PORT {
+        tunnel[0] = VXLAN_I;
+    } else {
+        tunnel[2] = (ETHERTYPE_ETHERNET >> 8) as u8;
+        tunnel[3] = ETHERTYPE_ETHERNET as u8;
+    }
+    tunnel[4] = (vni >> 16) as u8;
+    tunnel[5] = (vni >> 8) as u8;
+    tunnel[6] = vni as u8;
+    let mut inner_eth = MutableEthernetPacket::new(&mut tunnel[8..]).unwrap();
+    inner_eth.set_source(outer.eth_src);
+    inner_eth.set_destination(outer.eth_dst);
+    inner_eth.set_ethertype(ethertype);
+    headers_len
+}
@@ -11,6 +11,8 @@ pub enum BrokenRail {
     IO(io::Error),
     BadPacket,
     NoIPV4Address,
+    NoIPV6Address,
+    Configuration(String),
 }
 
 impl fmt::Display for BrokenRail {
@@ -20,6 +22,8 @@ impl fmt::Display for BrokenRail {
             BrokenRail::IO(ref err) => err.fmt(f),
             BrokenRail::BadPacket => write!(f, "Couldn't handle packet"),
             BrokenRail::NoIPV4Address => write!(f, "No IPV4 address on interface"),
+            BrokenRail::NoIPV6Address => write!(f, "No IPV6 address on interface"),
+            BrokenRail::Configuration(ref msg) => write!(f, "Bad configuration: {}", msg),
         }
     }
 }
@@ -31,6 +35,8 @@ impl error::Error for BrokenRail {
             BrokenRail::IO(ref err) => err.description(),
             BrokenRail::BadPacket => "Couldn't handle packet",
             BrokenRail::NoIPV4Address => "No IPV4 address on interface",
+            BrokenRail::NoIPV6Address => "No IPV6 address on interface",
+            BrokenRail::Configuration(_) => "Bad configuration",
         }
     }
 
@@ -40,6 +46,8 @@ impl error::Error for BrokenRail {
             BrokenRail::IO(ref err) => Some(err),
             BrokenRail::BadPacket => None,
             BrokenRail::NoIPV4Address => None,
+            BrokenRail::NoIPV6Address => None,
+            BrokenRail::Configuration(_) => None,
         }
     }
 }
@@ -0,0 +1,210 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// PacketIo pair for passing packets between forwarding threads.
+//
+// With one worker per NIC ring, only one worker can own the host rings, but every worker
+// receives packets destined for the host. The others hand those packets to the owner, which
+// receives them like any other ring. Host bound traffic is the exception rather than the rule,
+// so each packet is copied into its own allocation.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError};
+
+use libc;
+
+use super::error;
+use super::packetio::{PacketIo, TxSlot};
+
+// Packets in flight between two threads before the sender starts dropping.
+const DEPTH: usize = 1024;
+const BUF_SIZE: usize = 2048;
+
+/// Create a connected pair: packets transmitted on the HandoffTx are received on the HandoffRx.
+///
+/// HandoffTx can be cloned for each sending thread.
+pub fn handoff() -> Result<(HandoffTx, HandoffRx), error::BrokenRail> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(error::BrokenRail::IO(io::Error::last_os_error()));
+    }
+    let event = unsafe { File::from_raw_fd(fd) };
+    let (sender, receiver) = sync_channel(DEPTH);
+    Ok((HandoffTx {
+        sender: sender,
+        event: try!(event.try_clone()),
+        slot: HandoffTxSlot { len: 0 },
+        buf: vec![0; BUF_SIZE],
+        sent: false,
+    },
+        HandoffRx {
+        receiver: receiver,
+        event: event,
+        claimed: None,
+        given_back: None,
+    }))
+}
+
+pub struct HandoffTxSlot {
+    len: usize,
+}
+
+impl TxSlot for HandoffTxSlot {
+    fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
+}
+
+/// Sending side of a handoff. Never receives anything.
+pub struct HandoffTx {
+    sender: SyncSender<Vec<u8>>,
+    event: File,
+    slot: HandoffTxSlot,
+    buf: Vec<u8>,
+    // A packet has been queued since the last sync.
+    sent: bool,
+}
+
+impl HandoffTx {
+    pub fn try_clone(&self) -> Result<HandoffTx, error::BrokenRail> {
+        Ok(HandoffTx {
+            sender: self.sender.clone(),
+            event: try!(self.event.try_clone()),
+            slot: HandoffTxSlot { len: 0 },
+            buf: vec![0; BUF_SIZE],
+            sent: false,
+        })
+    }
+
+    fn flush(&mut self) {
+        if self.slot.len != 0 {
+            // If the receiver is this far behind, or gone, drop the packet as a full ring would.
+            if self.sender.try_send(self.buf[..self.slot.len].to_vec()).is_ok() {
+                self.sent = true;
+            }
+            self.slot.len = 0;
+        }
+    }
+}
+
+impl PacketIo for HandoffTx {
+    fn rx_next(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn rx_give_back(&mut self) {}
+
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])> {
+        self.flush();
+        Some((&mut self.slot, &mut self.buf[..]))
+    }
+
+    fn tx_give_back(&mut self) {
+        self.slot.len = 0;
+    }
+
+    fn sync(&mut self) -> Result<(), error::BrokenRail> {
+        self.flush();
+        if self.sent {
+            self.sent = false;
+            // Wake the receiver's poll. The counter cannot overflow in practice, and if it is
+            // already signalled there is nothing more to do.
+            if let Err(err) = self.event.write(&1u64.to_ne_bytes()) {
+                if err.kind() != io::ErrorKind::WouldBlock {
+                    return Err(error::BrokenRail::IO(err));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn fd(&self) -> RawFd {
+        -1
+    }
+}
+
+/// Receiving side of a handoff. Polls readable when packets have been sent.
+pub struct HandoffRx {
+    receiver: Receiver<Vec<u8>>,
+    event: File,
+    claimed: Option<Vec<u8>>,
+    given_back: Option<Vec<u8>>,
+}
+
+impl PacketIo for HandoffRx {
+    fn rx_next(&mut self) -> Option<&mut [u8]> {
+        self.claimed = match self.given_back.take() {
+            Some(packet) => Some(packet),
+            None => {
+                match self.receiver.try_recv() {
+                    Ok(packet) => Some(packet),
+                    Err(TryRecvError::Empty) |
+                    Err(TryRecvError::Disconnected) => None,
+                }
+            }
+        };
+        match self.claimed {
+            Some(ref mut packet) => Some(&mut packet[..]),
+            None => None,
+        }
+    }
+
+    fn rx_give_back(&mut self) {
+        self.given_back = self.claimed.take();
+    }
+
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])> {
+        None
+    }
+
+    fn tx_give_back(&mut self) {}
+
+    fn sync(&mut self) -> Result<(), error::BrokenRail> {
+        self.claimed = None;
+        // Clear the wakeup; anything sent after this point signals again.
+        let mut count = [0u8; 8];
+        if let Err(err) = self.event.read(&mut count) {
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(error::BrokenRail::IO(err));
+            }
+        }
+        Ok(())
+    }
+
+    fn fd(&self) -> RawFd {
+        self.event.as_raw_fd()
+    }
+}
+
+#[test]
+fn handoff_between_threads() {
+    use std::thread;
+
+    let (tx, mut rx) = handoff().unwrap();
+    let mut tx2 = tx.try_clone().unwrap();
+    drop(tx);
+    thread::spawn(move || {
+            {
+                let (slot, buf) = tx2.tx_next().unwrap();
+                buf[..3].copy_from_slice(&[1, 2, 3]);
+                slot.set_len(3);
+            }
+            tx2.sync().unwrap();
+        })
+        .join()
+        .unwrap();
+    let mut pollfd = libc::pollfd {
+        fd: rx.fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 0) }, 1);
+    assert_eq!(rx.rx_next().unwrap(), &[1, 2, 3]);
+    rx.rx_give_back();
+    assert_eq!(rx.rx_next().unwrap(), &[1, 2, 3]);
+    assert!(rx.rx_next().is_none());
+    rx.sync().unwrap();
+    assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 0) }, 0);
+}
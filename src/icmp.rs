@@ -0,0 +1,71 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// ICMP errors generated while forwarding.
+
+use std::net::Ipv4Addr;
+
+use super::checksum;
+
+const ETH_HEADER: usize = 14;
+const IP_HEADER: usize = 20;
+const ICMP_HEADER: usize = 8;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+// RFC 1812 4.3.2.3: quote as much of the original datagram as fits in 576 bytes.
+const MAX_DATAGRAM: usize = 576;
+
+/// Build an ICMP time exceeded (in transit) frame reporting the IPv4 packet in frame, from
+/// source, back the way the frame came.
+///
+/// Returns None if frame does not hold an IPv4 header.
+///
+/// ```
+/// use std::net::Ipv4Addr;
+///
+/// use rusty_rail::icmp;
+///
+/// let mut frame = vec![0u8; 14 + 20 + 8];
+/// frame[12] = 0x08;
+/// frame[14] = 0x45;
+/// frame[26..30].copy_from_slice(&[192, 0, 2, 254]);
+/// let reply = icmp::time_exceeded(&frame, &Ipv4Addr::new(192, 0, 2, 1)).unwrap();
+/// assert_eq!(&reply[30..34], &[192, 0, 2, 254]);
+/// assert_eq!(reply[34], 11);
+/// assert_eq!(&reply[42..], &frame[14..]);
+/// ```
+pub fn time_exceeded(frame: &[u8], source: &Ipv4Addr) -> Option<Vec<u8>> {
+    if frame.len() < ETH_HEADER + IP_HEADER {
+        return None;
+    }
+    let original = &frame[ETH_HEADER..];
+    let quoted = &original[..original.len().min(MAX_DATAGRAM - IP_HEADER - ICMP_HEADER)];
+    let ip_len = IP_HEADER + ICMP_HEADER + quoted.len();
+    let mut reply = Vec::with_capacity(ETH_HEADER + ip_len);
+    // Back to whoever sent it to us, from the address it was sent to.
+    reply.extend_from_slice(&frame[6..12]);
+    reply.extend_from_slice(&frame[0..6]);
+    reply.extend_from_slice(&[0x08, 0x00]);
+    reply.extend_from_slice(&[0x45, 0, (ip_len >> 8) as u8, ip_len as u8, 0, 0, 0, 0, 64, 1, 0,
+                              0]);
+    reply.extend_from_slice(&source.octets());
+    reply.extend_from_slice(&original[12..16]);
+    let ip_checksum = checksum::checksum(&reply[ETH_HEADER..]);
+    reply[ETH_HEADER + 10] = (ip_checksum >> 8) as u8;
+    reply[ETH_HEADER + 11] = ip_checksum as u8;
+    reply.extend_from_slice(&[ICMP_TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0]);
+    reply.extend_from_slice(quoted);
+    let icmp_checksum = checksum::checksum(&reply[ETH_HEADER + IP_HEADER..]);
+    reply[ETH_HEADER + IP_HEADER + 2] = (icmp_checksum >> 8) as u8;
+    reply[ETH_HEADER + IP_HEADER + 3] = icmp_checksum as u8;
+    Some(reply)
+}
+
+#[test]
+fn checksums_valid() {
+    let frame = ::tests::gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+    let reply = time_exceeded(&frame, &Ipv4Addr::new(192, 0, 2, 1)).unwrap();
+    assert_eq!(reply.len(), 14 + 20 + 8 + frame.len() - 14);
+    assert_eq!(checksum::checksum(&reply[14..34]), 0);
+    assert_eq!(checksum::checksum(&reply[34..]), 0);
+    assert_eq!(&reply[0..6], &frame[6..12]);
+    assert_eq!(&reply[26..30], &[192, 0, 2, 1]);
+}
@@ -1,34 +1,73 @@
 // Copyright (c) 2016 Robert Collins. Licensed under the Apache-2.0 license.
+extern crate libc;
 extern crate netmap;
 extern crate pnet;
 extern crate pnetlink;
 extern crate siphasher;
 
-use std::hash::{Hash, Hasher};
-use std::net::Ipv4Addr;
+use std::hash::Hasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use netmap::{NetmapSlot, NetmapRing};
 use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
-use pnet::packet::ethernet::EtherTypes::Ipv4;
-use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ethernet::EtherTypes::{Ipv4, Ipv6};
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
 use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ip::IpNextHeaderProtocols::Gre;
-use pnet::packet::gre;
+use pnet::packet::udp::UdpPacket;
 use pnet::util::MacAddr;
 use siphasher::sip::SipHasher;
 
-use consistenthash::ConsistentHash;
+use configuration::{Config, FlowHash};
+use consistenthash::{ConsistentHash, View};
+use encap::{Encap, GreError, GreOptions, Outer, UdpPayload};
+use packetio::{PacketIo, RxSlot, TxSlot};
 
+pub mod afpacket;
+pub mod afxdp;
 pub mod arpcache;
+pub mod bpf;
+pub mod checksum;
+pub mod xdp;
 pub mod configuration;
+pub mod encap;
 pub mod error;
+pub mod handoff;
+pub mod icmp;
 pub mod primes;
 pub mod consistenthash;
+pub mod netmapio;
+pub mod packetio;
+pub mod pcapio;
+pub mod tap;
 
 enum Direction {
     Destination,
     Drop,
-    Wire(Ipv4Addr),
+    /// A tunnel header too broken to forward.
+    Malformed(GreError),
+    /// A tunnelled packet with no backend live and weighted to take it.
+    NoBackend,
+    Wire(Forward),
+}
+
+/// A tunnelled packet to forward to a backend.
+struct Forward {
+    /// The backend chosen for the inner packet.
+    target: IpAddr,
+    /// The inner destination.
+    vip: IpAddr,
+    /// How the packet arrived.
+    encap: Encap,
+    /// The inner packet, within the received frame.
+    inner: Range<usize>,
+    /// The inner packet's flow hash.
+    hash: u64,
+    /// The received GRE options, when it arrived as GRE.
+    gre: GreOptions,
 }
 
 pub enum TransferStatus {
@@ -37,236 +76,1944 @@ pub enum TransferStatus {
     Complete,
 }
 
+/// The egress interface's addresses, which forwarded packets are sent from unless overridden per
+/// VIP. Backends are reached over IPv4 or IPv6 as their addresses are, so an interface needs an
+/// address of each family its backends have.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Addresses {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+/// Counts of how packets were moved, accumulated across calls to move_packets.
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// Packets sent by swapping the received buffer into the transmit slot.
+    pub swapped: u64,
+    /// Packets sent by copying into the transmit slot's buffer.
+    pub copied: u64,
+    /// Packets dropped because forwarding them would take their TTL to zero.
+    pub ttl_expired: u64,
+    /// GRE packets dropped for truncated, source routed or unknown version headers.
+    pub gre_malformed: u64,
+    /// GRE packets dropped for a bad checksum.
+    pub gre_bad_checksum: u64,
+    /// Packets dropped for not fitting the transmit buffers.
+    pub oversized: u64,
+    /// Packets dropped because no backend was live and weighted to take them.
+    pub no_backend: u64,
+    /// Packets dropped for want of a source address of their backend's family: neither the
+    /// interface nor RR_VIP_SOURCES has one.
+    pub no_source: u64,
+    /// First fragments forgotten before the rest of their datagram arrived, or timed out anyway.
+    pub fragments_expired: u64,
+    /// First fragments forgotten, while still in time, for another datagram's that hashed to the
+    /// same slot of the fragment table.
+    pub fragments_overflowed: u64,
+    /// Later fragments hashed on the 3-tuple for want of their first fragment.
+    pub fragments_unmatched: u64,
+    /// Connections opened during a key rotation but not remembered because another live
+    /// connection held their slot of the connection table: the rest of them goes where the
+    /// previous keys send it.
+    pub connections_overflowed: u64,
+}
+
 
-fn hash_ipv4_packet(packet: &Ipv4Packet) -> u64 {
-    let mut s = SipHasher::new();
-    packet.get_source().hash(&mut s);
-    packet.get_destination().hash(&mut s);
-    packet.get_next_level_protocol().hash(&mut s);
-    // Should we add ports in here for udp/tcp? Maglev does, but no reason is given.
-    // (Assumed reason is spreading traffic from the same client to differnet backends.)
+fn hash_ipv4_packet(packet: &Ipv4Packet, fields: FlowHash, key: (u64, u64)) -> u64 {
+    // The header fields are hashed as raw bytes rather than through their Hash impls, whose
+    // encoding is up to the standard library: every load balancer, including the in-kernel XDP
+    // forwarder, has to agree on the result.
+    let mut s = SipHasher::new_with_keys(key.0, key.1);
+    s.write(&packet.get_source().octets());
+    s.write(&packet.get_destination().octets());
+    let protocol = packet.get_next_level_protocol().0;
+    s.write_u8(protocol);
+    // Only the first fragment carries the ports: the rest go by the addresses alone, unless
+    // select_ipv4 finds their first fragment.
+    if fields == FlowHash::FiveTuple && packet.get_fragment_offset() == 0 {
+        hash_ports(&mut s, protocol, packet.payload());
+    }
+    s.finish()
+}
+
+fn hash_ipv6_packet(packet: &Ipv6Packet, fields: FlowHash, key: (u64, u64)) -> u64 {
+    let mut s = SipHasher::new_with_keys(key.0, key.1);
+    s.write(&packet.get_source().octets());
+    s.write(&packet.get_destination().octets());
+    let (protocol, offset, fragment) = ipv6_upper_layer(packet.packet());
+    s.write_u8(protocol);
+    if fields == FlowHash::FiveTuple && !fragment {
+        hash_ports(&mut s, protocol, &packet.packet()[offset..]);
+    }
     s.finish()
 }
 
+/// Add the ports from the upper layer header in payload to a flow hash: the ports of TCP, UDP
+/// and SCTP, or the identifier of ICMP and ICMPv6 echoes. Other protocols, and headers too
+/// short to hold them, add nothing.
+fn hash_ports(s: &mut SipHasher, protocol: u8, payload: &[u8]) {
+    match protocol {
+        // The source and destination ports lead the header.
+        6 | 17 | 132 if payload.len() >= 4 => s.write(&payload[..4]),
+        // Echo request and reply.
+        1 | 58 if payload.len() >= 6 && [0, 8, 128, 129].contains(&payload[0]) => {
+            s.write(&payload[4..6])
+        }
+        _ => (),
+    }
+}
+
+/// The upper layer protocol of an IPv6 packet, the offset of its header, and whether a fragment
+/// header was passed on the way, found by walking the extension headers. A truncated extension
+/// header is reported as the protocol.
+fn ipv6_upper_layer(packet: &[u8]) -> (u8, usize, bool) {
+    let mut next = packet[6];
+    let mut offset = 40;
+    let mut fragment = false;
+    while packet.len() >= offset + 2 {
+        let len = match next {
+            // Hop-by-hop options, routing and destination options: in 8 octets, less 1.
+            0 | 43 | 60 => (packet[offset + 1] as usize + 1) * 8,
+            // Fragment.
+            44 => 8,
+            // Authentication header: in 4 octets, less 2.
+            51 => (packet[offset + 1] as usize + 2) * 4,
+            _ => break,
+        };
+        if packet.len() < offset + len {
+            break;
+        }
+        fragment |= next == 44;
+        next = packet[offset];
+        offset += len;
+    }
+    (next, offset, fragment)
+}
+
 /// Move a packet from one ring to another.
 ///
-/// rx_slot_buf is the receipt slot to move from
+/// rx_slot is the received packet's slot, if its buffer can be swapped rather than copied
+/// rx_buf is the received packet to move from
 /// tx_slot_buf is the transmission slot to move it into
-fn move_packet(rx_slot_buf: (&mut netmap::RxSlot, &mut [u8]),
-               tx_slot_buf: (&mut netmap::TxSlot, &mut [u8]))
-               -> Result<(), error::BrokenRail> {
-    // XXX: TODO: zero-copy when possible.
-    let tgt_buf = &mut tx_slot_buf.1[0..rx_slot_buf.0.get_len() as usize];
-    tgt_buf.copy_from_slice(rx_slot_buf.1);
-    tx_slot_buf.0.set_len(rx_slot_buf.0.get_len());
-    Ok(())
+///
+/// Returns false, having set nothing, if the packet does not fit the slot's buffer.
+fn move_packet(rx_slot: Option<&mut RxSlot>,
+               rx_buf: &[u8],
+               tx_slot_buf: (&mut TxSlot, &mut [u8]),
+               counters: &mut Counters)
+               -> bool {
+    if let Some(rx_slot) = rx_slot {
+        if tx_slot_buf.0.swap_buffer(rx_slot, rx_buf.len()) {
+            counters.swapped += 1;
+            return true;
+        }
+    }
+    if rx_buf.len() > tx_slot_buf.1.len() {
+        return false;
+    }
+    let tgt_buf = &mut tx_slot_buf.1[0..rx_buf.len()];
+    tgt_buf.copy_from_slice(rx_buf);
+    tx_slot_buf.0.set_len(rx_buf.len());
+    counters.copied += 1;
+    true
 }
 
-// Debug code
-//
-// #[cfg(debug_assertions)]
-// fn debug_gre() {
-// println!("GRE protocol {:#06X} flags=checksum_present {:?}, routing {:?}, key {:?}, sequence \
-// {:?}",
-// gre.get_protocol_type(),
-// gre.get_checksum_present(),
-// gre.get_routing_present(),
-// gre.get_key_present(),
-// gre.get_sequence_present());
-// }
+/// Move a packet made of new outer headers and the inner packet of a received one into a
+/// transmission slot. The received buffer can never be swapped. Returns false, as move_packet
+/// does, if they do not fit.
+fn move_encapsulated(headers: &[u8],
+                     inner: &[u8],
+                     tx_slot_buf: (&mut TxSlot, &mut [u8]),
+                     counters: &mut Counters)
+                     -> bool {
+    let len = headers.len() + inner.len();
+    if len > tx_slot_buf.1.len() {
+        return false;
+    }
+    tx_slot_buf.1[..headers.len()].copy_from_slice(headers);
+    tx_slot_buf.1[headers.len()..len].copy_from_slice(inner);
+    tx_slot_buf.0.set_len(len);
+    counters.copied += 1;
+    true
+}
 
+/// Rewrite a packet leaving in the encapsulation it arrived in, in place: from eth_src and source
+/// to eth_dst and dest, one hop further on. Returns false, having set nothing, if it is not an
+/// IP packet of their family.
+fn rewrite_in_place(buf: &mut [u8],
+                    eth_src: MacAddr,
+                    eth_dst: MacAddr,
+                    source: IpAddr,
+                    dest: IpAddr)
+                    -> bool {
+    let mut packet = match MutableEthernetPacket::new(buf) {
+        Some(packet) => packet,
+        None => return false,
+    };
+    match (source, dest) {
+        (IpAddr::V4(source), IpAddr::V4(dest)) => {
+            match MutableIpv4Packet::new(packet.payload_mut()) {
+                Some(mut ip) => {
+                    let old_source = ip.get_source();
+                    let old_dest = ip.get_destination();
+                    ip.set_source(source);
+                    ip.set_destination(dest);
+                    let ttl = ip.get_ttl();
+                    ip.set_ttl(ttl - 1);
+                    // Adjust rather than recompute the checksum, so that corruption on the
+                    // way to us is still detected at the backend.
+                    let proto = ip.get_next_level_protocol().0 as u16;
+                    let mut sum = ip.get_checksum();
+                    sum = checksum::adjust_addr(sum, &old_source, &source);
+                    sum = checksum::adjust_addr(sum, &old_dest, &dest);
+                    sum = checksum::adjust(sum,
+                                           (ttl as u16) << 8 | proto,
+                                           ((ttl - 1) as u16) << 8 | proto);
+                    ip.set_checksum(sum);
+                }
+                None => return false,
+            }
+        }
+        (IpAddr::V6(source), IpAddr::V6(dest)) => {
+            match MutableIpv6Packet::new(packet.payload_mut()) {
+                Some(mut ip) => {
+                    // No checksum to adjust.
+                    ip.set_source(source);
+                    ip.set_destination(dest);
+                    let hop_limit = ip.get_hop_limit();
+                    ip.set_hop_limit(hop_limit - 1);
+                }
+                None => return false,
+            }
+        }
+        // Sources are chosen in the backend's family.
+        _ => unreachable!(),
+    }
+    // We received it, now we're sending it, from our egress interface.
+    packet.set_source(eth_src);
+    packet.set_destination(eth_dst);
+    true
+}
+
+/// Pick the backend for the inner packet at inner in rx_buf, which arrived wrapped in encap
+/// with the GRE options gre.
+fn tunnelled(rx_buf: &[u8],
+             encap: Encap,
+             gre: GreOptions,
+             inner: Range<usize>,
+             config: &Config,
+             tables: &mut Tables,
+             counters: &mut Counters)
+             -> Direction {
+    let packet = &rx_buf[inner.clone()];
+    let version = packet.first().map(|b| b >> 4);
+    let fields = |vip: &IpAddr| *config.vip_flow_hash.get(vip).unwrap_or(&config.flow_hash);
+    let (vip, flow): (IpAddr, Option<(u64, IpAddr)>) =
+        if let (Some(4), Some(ip)) = (version, Ipv4Packet::new(packet)) {
+            let vip = ip.get_destination().into();
+            (vip, select_ipv4(config, &ip, fields(&vip), tables, counters))
+        } else if let (Some(6), Some(ip)) = (version, Ipv6Packet::new(packet)) {
+            let vip = ip.get_destination().into();
+            let fields = fields(&vip);
+            let (protocol, offset, fragment) = ipv6_upper_layer(ip.packet());
+            let segment = if fragment {
+                None
+            } else {
+                tcp_segment(&ip.get_source().into(), &vip, protocol, &packet[offset..])
+            };
+            let flow = select_flow(config,
+                                   &|key| hash_ipv6_packet(&ip, fields, key),
+                                   segment,
+                                   tables,
+                                   counters);
+            (vip, flow)
+        } else {
+            // if we can't handle the packet, drop it.
+            return Direction::Drop;
+        };
+    let (hash, target) = match flow {
+        Some(flow) => flow,
+        None => return Direction::NoBackend,
+    };
+    Direction::Wire(Forward {
+        target: target,
+        vip: vip,
+        encap: encap,
+        inner: inner,
+        hash: hash,
+        gre: gre,
+    })
+}
+
+
+/// Pick the backend for the GRE packet at payload in rx_buf, the outer IP payload.
+fn gre_tunnelled(rx_buf: &[u8],
+                 payload: Range<usize>,
+                 config: &Config,
+                 tables: &mut Tables,
+                 counters: &mut Counters)
+                 -> Direction {
+    match encap::parse_gre(&rx_buf[payload.clone()]) {
+        Ok(ref gre) if gre.protocol == 0x0800 || gre.protocol == 0x86DD => {
+            let inner = payload.start + gre.options.header_len()..payload.end;
+            tunnelled(rx_buf, Encap::Gre, gre.options, inner, config, tables, counters)
+        }
+        // Drop all other gre packets as noise
+        Ok(_) => Direction::Drop,
+        Err(error) => Direction::Malformed(error),
+    }
+}
 
-type RxSlotBuf<'a> = (&'a mut netmap::RxSlot, &'a mut [u8]);
 
 #[allow(non_upper_case_globals)]
 /// Determine the interface (and when appropriate new targets) for a single packet.
 ///
-/// rx_slot_buf is a packet that has been received.
-fn examine_one<'a>(rx_slot_buf: RxSlotBuf, routes:&ConsistentHash) -> Result<Direction, error::BrokenRail> {
-    let packet = match EthernetPacket::new(rx_slot_buf.1) {
+/// rx_buf is a packet that has been received.
+fn examine_one(rx_buf: &[u8],
+               config: &Config,
+               tables: &mut Tables,
+               counters: &mut Counters)
+               -> Result<Direction, error::BrokenRail> {
+    // Runts are dropped rather than returned as errors: one from the wire must not stop
+    // forwarding.
+    let packet = match EthernetPacket::new(rx_buf) {
         Some(packet) => packet,
-        None => return Err(error::BrokenRail::BadPacket),
+        None => return Ok(Direction::Drop),
     };
     match packet.get_ethertype() {
         Ipv4 => {
             if let Some(ip) = Ipv4Packet::new(packet.payload()) {
+                // Where the outer payload starts and ends in rx_buf.
+                let start = encap::ETHERNET_HEADER + ip.get_header_length() as usize * 4;
+                let end = start + ip.payload().len();
                 match ip.get_next_level_protocol() {
                     Gre => {
-                        if let Some(gre) = gre::GrePacket::new(ip.payload()) {
-                            match gre.get_protocol_type() {
-                                0x0800 => {
-                                    if let Some(inner_ip) = Ipv4Packet::new(gre.payload()) {
-                                        let hash = hash_ipv4_packet(&inner_ip);
-                                        let target_ipv4 = select_destination(routes, &inner_ip);
-                                        println!("Inner IP {:?} {:?} {:?} {:?}",
-                                                 inner_ip.get_source(),
-                                                 inner_ip.get_destination(),
-                                                 hash, target_ipv4);
-                                        return Ok(Direction::Wire(target_ipv4));
-                                    }
-                                    // try!(move_packet(rx_slot_buf, tx_slot_buf));
-                                    // if we can't handle the packet, drop it.
-                                    return Ok(Direction::Drop);
+                        return Ok(gre_tunnelled(rx_buf, start..end, config, tables, counters))
+                    }
+                    IpNextHeaderProtocols::Ipv4 => {
+                        return Ok(tunnelled(rx_buf, Encap::Ipip, GreOptions::default(),
+                                            start..end, config, tables, counters));
+                    }
+                    IpNextHeaderProtocols::Udp => {
+                        if let Some(udp) = UdpPacket::new(ip.payload()) {
+                            let payload = start + encap::UDP_HEADER;
+                            match encap::udp_payload(udp.get_destination(), udp.payload()) {
+                                UdpPayload::Inner(encap, offset) => {
+                                    let inner = payload + offset..end;
+                                    return Ok(tunnelled(rx_buf, encap, GreOptions::default(),
+                                                        inner, config, tables, counters));
                                 }
-                                // Drop all other gre packets as noise
-                                _ => return Ok(Direction::Drop),
+                                UdpPayload::Noise => return Ok(Direction::Drop),
+                                UdpPayload::NotTunnel => return Ok(Direction::Destination),
                             }
-
-                        } else {
-                            println!("Failed to process GRE packet");
-                            // in future perhaps forward to host?
-                            return Ok(Direction::Drop);
                         }
+                        return Ok(Direction::Destination);
                     }
                     // Forward non-GRE
                     _ => return Ok(Direction::Destination),
                     // try!(move_packet(rx_slot_buf, tx_slot_buf)),
                 }
             } else {
-                return Err(error::BrokenRail::BadPacket);
+                return Ok(Direction::Drop);
             };
             // println!("packet {:?}", packet.get_ethertype())
         }
-        // Forward non-IPV4 packets - ARP etc
+        Ipv6 => {
+            if let Some(ip) = Ipv6Packet::new(packet.payload()) {
+                // Past any extension headers.
+                let (next_header, offset, _) = ipv6_upper_layer(ip.packet());
+                let start = encap::ETHERNET_HEADER + offset;
+                let end = encap::ETHERNET_HEADER + encap::IPV6_HEADER + ip.payload().len();
+                if next_header == Gre.0 && start <= end {
+                    return Ok(gre_tunnelled(rx_buf, start..end, config, tables, counters));
+                }
+                // Forward non-GRE
+                return Ok(Direction::Destination);
+            }
+            return Ok(Direction::Drop);
+        }
+        // Forward non-IP packets - ARP etc
         _ => return Ok(Direction::Destination),
         // try!(move_packet(rx_slot_buf, tx_slot_buf)),
     }
 }
 
 
-pub fn select_destination(routes: &ConsistentHash, packet: &Ipv4Packet) -> Ipv4Addr {
-    let hash = hash_ipv4_packet(&packet) as usize;
-    let backend_idx = routes.lookup[hash % routes.lookup.len()];
-    routes.backends[backend_idx as usize].target
+/// The backend for an IPv4 packet, hashed under key on its addresses and protocol as the XDP
+/// forwarder does, if routes has any.
+pub fn select_destination(routes: &ConsistentHash,
+                          key: (u64, u64),
+                          packet: &Ipv4Packet)
+                          -> Option<IpAddr> {
+    routes.target(hash_ipv4_packet(&packet, FlowHash::ThreeTuple, key))
+}
+
+/// Pick the flow hash and backend for a packet from the routes published to tables, given hash,
+/// which hashes it under a key, and its TCP segment if it has one: None if there is no backend
+/// to pick.
+///
+/// While keys are being rotated, only connections opened under the new keys use them, and are
+/// remembered in tables for the rest of their segments to follow: everything else keeps to
+/// the backends the previous keys give it, so that no established connection moves.
+fn select_flow(config: &Config,
+               hash: &Fn((u64, u64)) -> u64,
+               segment: Option<Segment>,
+               tables: &mut Tables,
+               counters: &mut Counters)
+               -> Option<(u64, IpAddr)> {
+    let routes = tables.routes.routes();
+    let (previous, previous_key) = match (&routes.previous, &config.rotation) {
+        (&Some(ref previous), &Some(ref rotation)) => (previous, rotation.flow_key),
+        _ => {
+            let hash = hash(config.flow_key);
+            return routes.current.target(hash).map(|target| (hash, target));
+        }
+    };
+    if let Some(segment) = segment {
+        if segment.opening {
+            let hash = hash(config.flow_key);
+            let target = match routes.current.target(hash) {
+                Some(target) => target,
+                None => return None,
+            };
+            tables.connections
+                .insert(segment.connection, config.flow_key, hash, target, tables.now, counters);
+            return Some((hash, target));
+        }
+        let now = tables.now;
+        if let Some(flow) = tables.connections.lookup(&segment.connection, config.flow_key, now) {
+            return Some(flow);
+        }
+    }
+    let hash = hash(previous_key);
+    previous.target(hash).map(|target| (hash, target))
+}
+
+/// Pick the flow hash and backend for an IPv4 packet, hashed on fields. With ports, the first
+/// fragment of a datagram is remembered in tables for the rest to follow.
+fn select_ipv4(config: &Config,
+               packet: &Ipv4Packet,
+               fields: FlowHash,
+               tables: &mut Tables,
+               counters: &mut Counters)
+               -> Option<(u64, IpAddr)> {
+    let hash = |key| hash_ipv4_packet(packet, fields, key);
+    let first = packet.get_fragment_offset() == 0;
+    let protocol = packet.get_next_level_protocol().0;
+    let segment = if first {
+        tcp_segment(&packet.get_source().into(),
+                    &packet.get_destination().into(),
+                    protocol,
+                    packet.payload())
+    } else {
+        None
+    };
+    if fields == FlowHash::ThreeTuple ||
+       (first && packet.get_flags() & Ipv4Flags::MoreFragments == 0) {
+        return select_flow(config, &hash, segment, tables, counters);
+    }
+    let key = (packet.get_source(),
+               packet.get_destination(),
+               protocol,
+               packet.get_identification());
+    if first {
+        let flow = select_flow(config, &hash, segment, tables, counters);
+        if let Some((hash, target)) = flow {
+            tables.fragments.insert(key, config.flow_key, hash, target, tables.now, counters);
+        }
+        return flow;
+    }
+    match tables.fragments.lookup(&key, config.flow_key, tables.now, counters) {
+        Some(flow) => Some(flow),
+        None => {
+            counters.fragments_unmatched += 1;
+            select_flow(config, &hash, None, tables, counters)
+        }
+    }
+}
+
+/// The state forwarding keeps from one packet to the next, one set per thread.
+pub struct Tables {
+    /// This thread's snapshot of the lookup tables: refresh it between batches of packets.
+    pub routes: View,
+    pub fragments: Fragments,
+    pub connections: Connections,
+    /// Seconds since the epoch, read once per batch of packets by move_packets: the fragment and
+    /// connection tables time their entries by it, rather than reading the clock for every
+    /// packet.
+    pub now: u64,
+}
+
+impl Tables {
+    pub fn new(routes: View) -> Tables {
+        Tables {
+            routes: routes,
+            fragments: Fragments::new(FRAGMENT_TABLE_SIZE),
+            connections: Connections::new(CONNECTION_TABLE_SIZE),
+            now: now_secs(),
+        }
+    }
+}
+
+/// The time in whole seconds since the epoch.
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// How many datagrams a thread's fragment table remembers.
+pub const FRAGMENT_TABLE_SIZE: usize = 4096;
+
+/// How long a first fragment is remembered: Linux's default reassembly timeout, after which the
+/// backend will have given up on the datagram anyway.
+const FRAGMENT_LIFETIME_SECS: u64 = 30;
+
+/// The source, destination, protocol and identification shared by the fragments of a datagram.
+type FragmentKey = (Ipv4Addr, Ipv4Addr, u8, u16);
+
+#[derive(Clone, Copy)]
+struct FragmentEntry {
+    key: FragmentKey,
+    hash: u64,
+    target: IpAddr,
+    expires: u64,
+}
+
+/// The flow hashes and backends chosen for the first fragments of IPv4 datagrams hashed with
+/// their ports, for the fragments after them, which carry no ports, to follow. A fixed table
+/// allocated up front, so that floods of fragments cannot exhaust memory nor slow forwarding:
+/// each datagram has one slot, and a first fragment takes it over from any other datagram's.
+pub struct Fragments {
+    slots: Vec<Option<FragmentEntry>>,
+}
+
+impl Fragments {
+    /// A table of capacity slots, which must be at least one.
+    pub fn new(capacity: usize) -> Fragments {
+        Fragments { slots: vec![None; capacity] }
+    }
+
+    /// The slot for key, hashed under flow_key so that which datagrams share a slot cannot be
+    /// worked out by those sending them.
+    fn slot(&self, key: &FragmentKey, flow_key: (u64, u64)) -> usize {
+        let mut s = SipHasher::new_with_keys(flow_key.0, flow_key.1);
+        s.write(&key.0.octets());
+        s.write(&key.1.octets());
+        s.write_u8(key.2);
+        s.write_u16(key.3);
+        (s.finish() % self.slots.len() as u64) as usize
+    }
+
+    fn insert(&mut self,
+              key: FragmentKey,
+              flow_key: (u64, u64),
+              hash: u64,
+              target: IpAddr,
+              now: u64,
+              counters: &mut Counters) {
+        let slot = self.slot(&key, flow_key);
+        match self.slots[slot] {
+            Some(ref entry) if entry.key != key && entry.expires >= now => {
+                counters.fragments_overflowed += 1
+            }
+            Some(ref entry) if entry.key != key => counters.fragments_expired += 1,
+            _ => (),
+        }
+        self.slots[slot] = Some(FragmentEntry {
+            key: key,
+            hash: hash,
+            target: target,
+            expires: now + FRAGMENT_LIFETIME_SECS,
+        });
+    }
+
+    fn lookup(&mut self,
+              key: &FragmentKey,
+              flow_key: (u64, u64),
+              now: u64,
+              counters: &mut Counters)
+              -> Option<(u64, IpAddr)> {
+        let slot = self.slot(key, flow_key);
+        match self.slots[slot] {
+            Some(entry) if entry.key == *key && entry.expires >= now => {
+                Some((entry.hash, entry.target))
+            }
+            Some(entry) if entry.key == *key => {
+                self.slots[slot] = None;
+                counters.fragments_expired += 1;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How many connections opened during a key rotation a thread's connection table remembers.
+pub const CONNECTION_TABLE_SIZE: usize = 65536;
+
+/// How long a connection is remembered after its last segment: TCP's default keepalive interval,
+/// so that idle connections kept alive are not forgotten.
+const CONNECTION_IDLE_SECS: u64 = 7200;
+
+/// The addresses and ports of a TCP connection.
+type ConnectionKey = (IpAddr, IpAddr, u16, u16);
+
+/// A TCP segment, as far as choosing its backend goes.
+struct Segment {
+    connection: ConnectionKey,
+    /// A SYN without an ACK, opening a new connection.
+    opening: bool,
+}
+
+/// The TCP segment in payload, if protocol is TCP and its header is long enough to say.
+fn tcp_segment(source: &IpAddr, destination: &IpAddr, protocol: u8, payload: &[u8])
+               -> Option<Segment> {
+    if protocol != IpNextHeaderProtocols::Tcp.0 || payload.len() < 14 {
+        return None;
+    }
+    let port = |at: usize| (payload[at] as u16) << 8 | payload[at + 1] as u16;
+    Some(Segment {
+        connection: (*source, *destination, port(0), port(2)),
+        opening: payload[13] & 0x12 == 0x02,
+    })
+}
+
+#[derive(Clone, Copy)]
+struct ConnectionEntry {
+    key: ConnectionKey,
+    hash: u64,
+    target: IpAddr,
+    expires: u64,
+}
+
+/// The flow hashes and backends of connections opened under the new keys while keys are being
+/// rotated. A fixed table allocated up front, like Fragments: each connection has one slot, and
+/// keeps it until it has been idle too long, so that connections opened later cannot move it.
+pub struct Connections {
+    slots: Vec<Option<ConnectionEntry>>,
+}
+
+impl Connections {
+    /// A table of capacity slots, which must be at least one.
+    pub fn new(capacity: usize) -> Connections {
+        Connections { slots: vec![None; capacity] }
+    }
+
+    /// The slot for key, hashed under flow_key as Fragments' are.
+    fn slot(&self, key: &ConnectionKey, flow_key: (u64, u64)) -> usize {
+        let mut s = SipHasher::new_with_keys(flow_key.0, flow_key.1);
+        for address in &[key.0, key.1] {
+            match *address {
+                IpAddr::V4(address) => s.write(&address.octets()),
+                IpAddr::V6(address) => s.write(&address.octets()),
+            }
+        }
+        s.write_u16(key.2);
+        s.write_u16(key.3);
+        (s.finish() % self.slots.len() as u64) as usize
+    }
+
+    fn insert(&mut self,
+              key: ConnectionKey,
+              flow_key: (u64, u64),
+              hash: u64,
+              target: IpAddr,
+              now: u64,
+              counters: &mut Counters) {
+        let slot = self.slot(&key, flow_key);
+        match self.slots[slot] {
+            Some(ref entry) if entry.key != key && entry.expires >= now => {
+                counters.connections_overflowed += 1;
+                return;
+            }
+            _ => (),
+        }
+        self.slots[slot] = Some(ConnectionEntry {
+            key: key,
+            hash: hash,
+            target: target,
+            expires: now + CONNECTION_IDLE_SECS,
+        });
+    }
+
+    /// The flow hash and backend of a remembered connection, which is then remembered for
+    /// longer.
+    fn lookup(&mut self,
+              key: &ConnectionKey,
+              flow_key: (u64, u64),
+              now: u64)
+              -> Option<(u64, IpAddr)> {
+        let slot = self.slot(key, flow_key);
+        match self.slots[slot] {
+            Some(ref mut entry) if entry.key == *key && entry.expires >= now => {
+                entry.expires = now + CONNECTION_IDLE_SECS;
+                Some((entry.hash, entry.target))
+            }
+            _ => None,
+        }
+    }
+}
+
+
+/// Synchronise the rings touched by one call to move_packets.
+fn sync_all(src: &mut PacketIo,
+            dst: &mut PacketIo,
+            maybe_wire: Option<&mut PacketIo>)
+            -> Result<(), error::BrokenRail> {
+    try!(src.sync());
+    try!(dst.sync());
+    if let Some(wire) = maybe_wire {
+        try!(wire.sync());
+    }
+    Ok(())
 }
 
 
 #[allow(non_upper_case_globals)]
-pub fn move_packets(src: &mut netmap::NetmapDescriptor,
-                    dst: &mut netmap::NetmapDescriptor,
-                    mut maybe_wire: Option<&mut netmap::NetmapDescriptor>,
-                    interface_ipv4: &Ipv4Addr,
+pub fn move_packets(src: &mut PacketIo,
+                    dst: &mut PacketIo,
+                    mut maybe_wire: Option<&mut PacketIo>,
+                    interface_ips: &Addresses,
                     interface_mac: &MacAddr,
-                    routes: &ConsistentHash,
-                    arp_cache: &mut arpcache::Cache)
+                    config: &Config,
+                    arp_cache: &mut arpcache::Resolver,
+                    tables: &mut Tables,
+                    counters: &mut Counters)
                     -> Result<TransferStatus, error::BrokenRail> {
-    {
-        // We need up to three iterators:
-        // RX from src
-        // TX from dst
-        // TX from wire, if src is src is wire.
-        //
-        // The inner loop will advance the relevant TX iterator as needed, and return when a
-        // received packet can't be processed (because of no outbound buffer space).
-        //
-        // In future, we could allocate additional buffers from netmap and switch received buffers
-        // out of the ring to permit more reads to take place while we process packets - whether
-        // thats waiting for tx to free up or using a worker thread pool
-        let mut dst_slots = dst.tx_iter().flat_map(|tx_ring| tx_ring.iter_mut());
-        let mut maybe_wire_slots = match maybe_wire {
-            None => None,
-            Some(ref mut wire) => Some(wire.tx_iter().flat_map(|tx_ring| tx_ring.iter_mut())),
-        };
-        'rx: for rx_ring in src.rx_iter() {
-            let mut rx_slot_iter = rx_ring.iter();
-            'rx_slot: loop {
-                match rx_slot_iter.next() {
-                    None => break 'rx,
-                    Some((rx_slot, buf)) => {
-                        // We have a received packet.
-                        let direction = try!(examine_one((rx_slot, buf), routes));
-                        let maybe_tx_slot_buf = match direction {
-                            Direction::Destination => dst_slots.next(),
-                            Direction::Drop => continue 'rx_slot,
-                            Direction::Wire(target_ipv4) => {
-                                match maybe_wire_slots {
-                                    None => dst_slots.next(),
-                                    Some(ref mut wire_slots) => wire_slots.next(),
-                                }
-                            }
-                        };
-                        if let Direction::Wire(target_ipv4) = direction {
-                            let mut packet = match MutableEthernetPacket::new(buf) {
-                                Some(packet) => packet,
-                                None => return Err(error::BrokenRail::BadPacket),
-                            };
-                            // We received it, now we're sending it.
-                            {
-                                let t = packet.get_destination();
-                                packet.set_source(t);
-                            }
-                            let ip_pkt_dest = target_ipv4;
-                            if let Some(ref mut ip) = MutableIpv4Packet::new(packet.payload_mut()) {
-                                {
-                                    let t = ip.get_destination();
-                                    ip.set_source(t);
-                                }
-                                ip.set_destination(ip_pkt_dest);
-                                // let tmp_mac = packet.get_source();
-                                // packet.set_source(packet.get_destination());
-                                // packet.set_destination(tmp_mac);
-                                // TODO HERE:
-                                // - update outer source MAC
-                                // - update outer source IP
-                                // - update outer dest MAC
-                                // - update outer dest IP
-                                // profit
-                            } else {
-                                // Not a valid IPv4 packet - discard it:
-                                continue 'rx_slot;
-                                // return Err(error::BrokenRail::BadPacket);
-                            }
-                            // ////// move ipv4 lookup to outside; set ipv4, then
-                            // do arp cache lookup - helper fn time?
-                            match arp_cache.lookup(&ip_pkt_dest) {
-                                Some(target_mac) => {
-                                    packet.set_destination(target_mac);
-                                }
-                                None => {
-                                    // println!("Dropping {:?}", packet);
-                                    // Drop the packet: without a spare buffer to put the packet
-                                    // in, the recieve ring will rapidly block.
-                                    continue 'rx_slot;
-                                }
-                            }
+    tables.now = now_secs();
+    // We read from src, and write to dst, or to wire if src is the wire.
+    //
+    // The loop claims a TX slot on the relevant side once a packet is ready to go, and returns
+    // when a received packet can't be processed (because of no outbound buffer space).
+    //
+    // In future, we could allocate additional buffers from the backend and switch received
+    // buffers out of the ring to permit more reads to take place while we process packets -
+    // whether thats waiting for tx to free up or using a worker thread pool
+    'rx_slot: loop {
+        let blocked = {
+            let (rx_slot, buf) = match src.rx_next_slot() {
+                None => break 'rx_slot,
+                Some(slot_buf) => slot_buf,
+            };
+            // We have a received packet.
+            let direction = try!(examine_one(buf, config, tables, counters));
+            let (out, status): (&mut PacketIo, TransferStatus) = match direction {
+                Direction::Destination => (&mut *dst, TransferStatus::BlockedDestination),
+                Direction::Drop => continue 'rx_slot,
+                Direction::Malformed(GreError::Checksum) => {
+                    counters.gre_bad_checksum += 1;
+                    continue 'rx_slot;
+                }
+                Direction::Malformed(_) => {
+                    counters.gre_malformed += 1;
+                    continue 'rx_slot;
+                }
+                Direction::NoBackend => {
+                    counters.no_backend += 1;
+                    continue 'rx_slot;
+                }
+                Direction::Wire(..) => {
+                    match maybe_wire {
+                        None => (&mut *dst, TransferStatus::BlockedWire),
+                        Some(ref mut wire) => (&mut **wire, TransferStatus::BlockedWire),
+                    }
+                }
+            };
+            // Nothing below changes buf until a TX slot is claimed: a packet given back for
+            // want of one is examined afresh on the next pass.
+            //
+            // An ICMP error to send in place of the packet.
+            let mut reply = None;
+            let mut outer_ttl = 0;
+            let mut outer_ipv6 = false;
+            if let Direction::Wire(..) = direction {
+                let packet = match EthernetPacket::new(buf) {
+                    Some(packet) => packet,
+                    None => return Err(error::BrokenRail::BadPacket),
+                };
+                // The hop limit, for IPv6.
+                outer_ipv6 = packet.get_ethertype() == Ipv6;
+                let ttl = if outer_ipv6 {
+                    Ipv6Packet::new(packet.payload()).map(|ip| ip.get_hop_limit())
+                } else {
+                    Ipv4Packet::new(packet.payload()).map(|ip| ip.get_ttl())
+                };
+                match ttl {
+                    // Forwarding would take the TTL to zero (RFC 1812 5.3.1).
+                    Some(ttl) if ttl <= 1 => {
+                        // Errors are only sent over IPv4.
+                        reply = match (config.icmp_time_exceeded, outer_ipv6, interface_ips.ipv4) {
+                            (true, false, Some(ref source)) => icmp::time_exceeded(buf, source),
+                            _ => None,
                         };
-                        if let Some(tx_slot_buf) = maybe_tx_slot_buf {
-                            try!(move_packet((rx_slot, buf), tx_slot_buf));
-                        } else {
-                            // Couldn't get a tx slot, break out to the event loop.
-                            // We should perhaps instead discard the packet: if we can't transmit
-                            // do we really want to stall entirely?
-                            rx_slot_iter.give_back();
-                            return Ok(match direction {
-                                Direction::Destination => TransferStatus::BlockedDestination,
-                                Direction::Drop => panic!("Unreachable"),
-                                Direction::Wire(target_ipv4) => TransferStatus::BlockedWire,
-                            });
+                        if reply.is_none() {
+                            counters.ttl_expired += 1;
+                            continue 'rx_slot;
                         }
                     }
+                    Some(ttl) => outer_ttl = ttl,
+                    // Not a valid IP packet - discard it:
+                    None => continue 'rx_slot,
                 }
             }
-        }
-    };
-    // These need a comment : the use of early return may interact. Someone should think of these
-    // things!
-    for ring in src.rx_iter() {
-        ring.head_from_cur();
-    }
-    for ring in dst.tx_iter() {
-        ring.head_from_cur();
-    }
-    if let Some(wire) = maybe_wire {
-        for ring in wire.tx_iter() {
-            ring.head_from_cur();
+            // Outer headers written afresh, when leaving in a different encapsulation.
+            let mut headers = [0u8; encap::MAX_HEADERS];
+            let mut headers_len = 0;
+            // The addresses to rewrite in place, when leaving in the encapsulation it came in.
+            let mut in_place = None;
+            if let (&Direction::Wire(ref forward), None) = (&direction, &reply) {
+                let ip_pkt_dest = forward.target;
+                // A GRE key can name the VIP (or tenant) in place of the inner destination.
+                let vip = forward.gre
+                    .key
+                    .and_then(|key| config.gre_key_vips.get(&key))
+                    .unwrap_or(&forward.vip);
+                // The source must be of the backend's family.
+                let interface_ip = match ip_pkt_dest {
+                    IpAddr::V4(_) => interface_ips.ipv4.map(IpAddr::V4),
+                    IpAddr::V6(_) => interface_ips.ipv6.map(IpAddr::V6),
+                };
+                let ip_pkt_source = match (config.vip_sources.get(vip), interface_ip) {
+                    (Some(source), _) if source.is_ipv4() == ip_pkt_dest.is_ipv4() => *source,
+                    (_, Some(source)) => source,
+                    (_, None) => {
+                        counters.no_source += 1;
+                        continue 'rx_slot;
+                    }
+                };
+                let target_mac = match arp_cache.lookup(&ip_pkt_dest) {
+                    Some(target_mac) => target_mac,
+                    None => {
+                        // println!("Dropping {:?}", packet);
+                        // Drop the packet: without a spare buffer to put the packet
+                        // in, the recieve ring will rapidly block.
+                        continue 'rx_slot;
+                    }
+                };
+                let egress = *config.backend_encap.get(&ip_pkt_dest).unwrap_or(&config.encap);
+                let mut gre = forward.gre;
+                if config.gre_strip_sequence {
+                    gre.sequence = None;
+                }
+                if forward.encap != egress || !egress.in_place() || gre != forward.gre ||
+                   outer_ipv6 != ip_pkt_dest.is_ipv6() {
+                    let outer = Outer {
+                        eth_src: *interface_mac,
+                        eth_dst: target_mac,
+                        ip_src: ip_pkt_source,
+                        ip_dst: ip_pkt_dest,
+                        ttl: outer_ttl - 1,
+                        flow_hash: forward.hash,
+                        gre: gre,
+                    };
+                    headers_len = encap::write_headers(egress,
+                                                       &outer,
+                                                       &buf[forward.inner.clone()],
+                                                       &mut headers);
+                } else {
+                    in_place = Some((target_mac, ip_pkt_source, ip_pkt_dest));
+                }
+            };
+            if let Some(tx_slot_buf) = out.tx_next() {
+                if let Some((target_mac, source, dest)) = in_place {
+                    if !rewrite_in_place(buf, *interface_mac, target_mac, source, dest) {
+                        // Not a valid IP packet - discard it:
+                        out.tx_give_back();
+                        continue 'rx_slot;
+                    }
+                }
+                let moved = match (&reply, &direction) {
+                    (&Some(ref reply), _) => {
+                        counters.ttl_expired += 1;
+                        move_packet(None, reply, tx_slot_buf, counters)
+                    }
+                    (&None, &Direction::Wire(ref forward)) if headers_len != 0 => {
+                        move_encapsulated(&headers[..headers_len],
+                                          &buf[forward.inner.clone()],
+                                          tx_slot_buf,
+                                          counters)
+                    }
+                    _ => move_packet(rx_slot, buf, tx_slot_buf, counters),
+                };
+                if !moved {
+                    // Larger than the other side's buffers, as frames merged by GRO or read
+                    // from a TAP can be: drop it rather than stop forwarding.
+                    out.tx_give_back();
+                    counters.oversized += 1;
+                }
+                None
+            } else {
+                // Couldn't get a tx slot, break out to the event loop.
+                // We should perhaps instead discard the packet: if we can't transmit
+                // do we really want to stall entirely?
+                Some(status)
+            }
+        };
+        if let Some(status) = blocked {
+            src.rx_give_back();
+            try!(sync_all(src, dst, maybe_wire));
+            return Ok(status);
         }
     }
+    try!(sync_all(src, dst, maybe_wire));
     Ok(TransferStatus::Complete)
 }
 
-
 #[cfg(test)]
 mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use pnet::packet::{MutablePacket, Packet};
+    use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+    use pnet::packet::gre::MutableGrePacket;
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+    use pnet::packet::ipv6::Ipv6Packet;
+    use pnet::packet::udp::UdpPacket;
+    use pnet::util::MacAddr;
+
+    use arpcache::Resolver;
+    use checksum;
+    use configuration::Config;
+    use consistenthash::Published;
+    use packetio::{MemoryIo, PacketIo, RxSlot, TxSlot};
+    use encap::{self, Encap, GreOptions, Outer};
+    use error;
+    use super::{Addresses, Connections, Counters, Direction, Fragments, Tables, TransferStatus,
+                FRAGMENT_LIFETIME_SECS, examine_one, ipv6_upper_layer, move_packet,
+                move_packets};
+
+    /// Build an ethernet frame carrying GRE wrapped IPv4 from inner_src to inner_dst.
+    pub fn gre_frame(inner_src: Ipv4Addr, inner_dst: Ipv4Addr) -> Vec<u8> {
+        let mut buf = vec![0u8; 14 + 20 + 4 + 20];
+        {
+            let mut eth = MutableEthernetPacket::new(&mut buf[..]).unwrap();
+            eth.set_ethertype(EtherTypes::Ipv4);
+            let mut ip = MutableIpv4Packet::new(eth.payload_mut()).unwrap();
+            ip.set_version(4);
+            ip.set_header_length(5);
+            ip.set_total_length(20 + 4 + 20);
+            ip.set_ttl(64);
+            ip.set_next_level_protocol(IpNextHeaderProtocols::Gre);
+            ip.set_source(Ipv4Addr::new(192, 0, 2, 254));
+            ip.set_destination(Ipv4Addr::new(192, 0, 2, 1));
+            let mut gre = MutableGrePacket::new(ip.payload_mut()).unwrap();
+            gre.set_protocol_type(0x0800);
+            let mut inner = MutableIpv4Packet::new(gre.payload_mut()).unwrap();
+            inner.set_version(4);
+            inner.set_header_length(5);
+            inner.set_total_length(20);
+            inner.set_ttl(64);
+            inner.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+            inner.set_source(inner_src);
+            inner.set_destination(inner_dst);
+            let sum = checksum::checksum(&ip.packet()[..20]);
+            ip.set_checksum(sum);
+        }
+        buf
+    }
+
+    /// Build an ethernet frame carrying GRE in IPv6, wrapping IPv4 from inner_src to inner_dst.
+    pub fn ipv6_gre_frame(inner_src: Ipv4Addr, inner_dst: Ipv4Addr) -> Vec<u8> {
+        let gre = gre_frame(inner_src, inner_dst);
+        let mut buf = gre[..14].to_vec();
+        buf[12..14].copy_from_slice(&[0x86, 0xdd]);
+        let len = gre.len() - 14 - 20;
+        buf.extend_from_slice(&[0x60, 0, 0, 0, (len >> 8) as u8, len as u8, 47, 64]);
+        buf.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0xfe).octets());
+        buf.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        buf.extend_from_slice(&gre[14 + 20..]);
+        buf
+    }
+
+    /// Build an ethernet frame carrying IPv4 in IPv4 from inner_src to inner_dst.
+    pub fn ipip_frame(inner_src: Ipv4Addr, inner_dst: Ipv4Addr) -> Vec<u8> {
+        let mut buf = gre_frame(inner_src, inner_dst);
+        buf.drain(14 + 20..14 + 20 + 4);
+        {
+            let mut ip = MutableIpv4Packet::new(&mut buf[14..]).unwrap();
+            ip.set_total_length(20 + 20);
+            ip.set_next_level_protocol(IpNextHeaderProtocols::Ipv4);
+            ip.set_checksum(0);
+            let sum = checksum::checksum(&ip.packet()[..20]);
+            ip.set_checksum(sum);
+        }
+        buf
+    }
+
+    /// Build an ethernet frame carrying GRE from a router, wrapping IPv6 from inner_src to
+    /// inner_dst: extension headers, as their types and contents, then a TCP header.
+    fn gre6_frame(inner_src: Ipv6Addr,
+                  inner_dst: Ipv6Addr,
+                  extensions: &[(u8, &[u8])])
+                  -> Vec<u8> {
+        let mut payload = vec![];
+        for (i, &(_, extension)) in extensions.iter().enumerate() {
+            payload.extend_from_slice(extension);
+            let next = payload.len() - extension.len();
+            payload[next] = extensions.get(i + 1).map_or(6, |e| e.0);
+        }
+        payload.extend_from_slice(&[0; 20]);
+        let mut buf = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        buf.truncate(14 + 20 + 4);
+        buf[14 + 20 + 2..14 + 20 + 4].copy_from_slice(&[0x86, 0xdd]);
+        buf.extend_from_slice(&[0x60, 0, 0, 0, (payload.len() >> 8) as u8, payload.len() as u8,
+                                extensions.first().map_or(6, |e| e.0), 64]);
+        buf.extend_from_slice(&inner_src.octets());
+        buf.extend_from_slice(&inner_dst.octets());
+        buf.extend_from_slice(&payload);
+        {
+            let total = buf.len() - 14;
+            let mut ip = MutableIpv4Packet::new(&mut buf[14..]).unwrap();
+            ip.set_total_length(total as u16);
+            ip.set_checksum(0);
+            let sum = checksum::checksum(&ip.packet()[..20]);
+            ip.set_checksum(sum);
+        }
+        buf
+    }
+
+    /// Build gre_frame from 10.0.0.1 to 10.1.0.1, its inner packet carrying the upper layer
+    /// header l4 of protocol.
+    fn l4_frame(protocol: u8, l4: &[u8]) -> Vec<u8> {
+        let mut buf = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        buf.extend_from_slice(l4);
+        {
+            let mut ip = MutableIpv4Packet::new(&mut buf[14..]).unwrap();
+            ip.set_total_length((20 + 4 + 20 + l4.len()) as u16);
+            ip.set_checksum(0);
+            let sum = checksum::checksum(&ip.packet()[..20]);
+            ip.set_checksum(sum);
+        }
+        buf[14 + 20 + 4 + 3] = (20 + l4.len()) as u8;
+        buf[14 + 20 + 4 + 9] = protocol;
+        buf
+    }
+
+    /// Build an ethernet frame carrying the inner IPv4 of gre_frame, wrapped by encap.
+    fn encap_frame(encap: Encap, inner_src: Ipv4Addr, inner_dst: Ipv4Addr) -> Vec<u8> {
+        wrap(encap, GreOptions::default(), inner_src, inner_dst)
+    }
+
+    /// Build an ethernet frame carrying the inner IPv4 of gre_frame, in GRE with options.
+    fn gre_options_frame(options: GreOptions,
+                         inner_src: Ipv4Addr,
+                         inner_dst: Ipv4Addr)
+                         -> Vec<u8> {
+        wrap(Encap::Gre, options, inner_src, inner_dst)
+    }
+
+    fn wrap(encap: Encap, gre: GreOptions, inner_src: Ipv4Addr, inner_dst: Ipv4Addr) -> Vec<u8> {
+        let gre_frame = gre_frame(inner_src, inner_dst);
+        let inner = &gre_frame[14 + 20 + 4..];
+        let outer = Outer {
+            eth_src: MacAddr::new(2, 0, 0, 0, 0, 254),
+            eth_dst: MacAddr::new(2, 0, 0, 0, 0, 1),
+            ip_src: Ipv4Addr::new(192, 0, 2, 254).into(),
+            ip_dst: Ipv4Addr::new(192, 0, 2, 1).into(),
+            ttl: 64,
+            flow_hash: 0,
+            gre: gre,
+        };
+        let mut buf = vec![0; encap::MAX_HEADERS];
+        let len = encap::write_headers(encap, &outer, inner, &mut buf);
+        buf.truncate(len);
+        buf.extend_from_slice(inner);
+        buf
+    }
+
+    /// Every address is at the same MAC.
+    struct Neighbours;
+
+    impl Resolver for Neighbours {
+        fn lookup(&mut self, _: &IpAddr) -> Option<MacAddr> {
+            Some(MacAddr::new(2, 0, 0, 0, 0, 10))
+        }
+    }
+
+    fn config(extra: &[(&str, &str)]) -> Config {
+        let mut vars = vec![("RR_DEVICE".to_string(), "eth0".to_string()),
+                            ("RR_TARGET_IPS".to_string(), "192.0.2.10".to_string())];
+        vars.extend(extra.iter().map(|&(k, v)| (k.to_string(), v.to_string())));
+        Config::new(vars.into_iter()).unwrap()
+    }
+
+    /// Fresh tables, with config's routes published to them.
+    fn tables(config: &Config) -> Tables {
+        Tables::new(Published::new(config.lookup_tables()).view())
+    }
+
+    /// Examine frame with config, remembering no earlier packets.
+    fn examine(frame: &[u8], config: &Config) -> Result<Direction, error::BrokenRail> {
+        examine_one(frame, config, &mut tables(config), &mut Counters::default())
+    }
+
+    /// Forward frame from the wire with config, returning what went to the wire.
+    fn forward(frame: Vec<u8>, config: &Config) -> (Vec<Vec<u8>>, Counters) {
+        let addresses = Addresses {
+            ipv4: Some(Ipv4Addr::new(192, 0, 2, 1)),
+            ipv6: Some(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        };
+        forward_from(frame, config, &addresses)
+    }
+
+    /// Forward frame from the wire with config, from the interface addresses given.
+    fn forward_from(frame: Vec<u8>,
+                    config: &Config,
+                    addresses: &Addresses)
+                    -> (Vec<Vec<u8>>, Counters) {
+        let mut wire_in = MemoryIo::new(1);
+        let mut host = MemoryIo::new(1);
+        let mut wire_out = MemoryIo::new(1);
+        let mut counters = Counters::default();
+        wire_in.rx.push_back(frame);
+        match move_packets(&mut wire_in,
+                           &mut host,
+                           Some(&mut wire_out),
+                           addresses,
+                           &MacAddr::new(2, 0, 0, 0, 0, 1),
+                           config,
+                           &mut Neighbours,
+                           &mut tables(config),
+                           &mut counters) {
+            Ok(TransferStatus::Complete) => (),
+            _ => panic!("packet not consumed"),
+        }
+        wire_out.sync().unwrap();
+        assert!(host.tx.is_empty());
+        (wire_out.tx, counters)
+    }
+
+    #[test]
+    fn rewrite_adjusts_checksum_and_ttl() {
+        let frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let (sent, _) = forward(frame, &config(&[]));
+        assert_eq!(sent.len(), 1);
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_ttl(), 63);
+        assert_eq!(ip.get_destination(), Ipv4Addr::new(192, 0, 2, 10));
+        assert_eq!(checksum::checksum(&ip.packet()[..20]), 0);
+    }
+
+    #[test]
+    fn oversized_dropped() {
+        // Padded past the transmit buffers, as a frame merged by GRO can be.
+        let mut frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        frame.resize(3000, 0);
+        let (sent, counters) = forward(frame, &config(&[]));
+        assert!(sent.is_empty());
+        assert_eq!(counters.oversized, 1);
+    }
+
+    #[test]
+    fn runts_dropped() {
+        let frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let mut ipv6 = frame[..14 + 20].to_vec();
+        ipv6[12..14].copy_from_slice(&[0x86, 0xdd]);
+        for runt in &[frame[..10].to_vec(), frame[..14 + 10].to_vec(), ipv6] {
+            let (sent, _) = forward(runt.clone(), &config(&[]));
+            assert!(sent.is_empty());
+        }
+    }
+
+    #[test]
+    fn no_source_dropped() {
+        let frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let v6_config = config(&[("RR_TARGET_IPS", "2001:db8::10")]);
+        let ipv4_only = Addresses {
+            ipv4: Some(Ipv4Addr::new(192, 0, 2, 1)),
+            ipv6: None,
+        };
+        let (sent, counters) = forward_from(frame.clone(), &v6_config, &ipv4_only);
+        assert!(sent.is_empty());
+        assert_eq!(counters.no_source, 1);
+        // A VIP source of the backend's family will do.
+        let sourced = config(&[("RR_TARGET_IPS", "2001:db8::10"),
+                               ("RR_VIP_SOURCES", "10.1.0.1=2001:db8::7")]);
+        let (sent, counters) = forward_from(frame, &sourced, &ipv4_only);
+        assert_eq!((sent.len(), counters.no_source), (1, 0));
+    }
+
+    #[test]
+    fn no_backend_dropped() {
+        let frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let mut config = config(&[]);
+        config.routes.remove_backend("192.0.2.10").unwrap();
+        config.routes.populate();
+        let (sent, counters) = forward(frame, &config);
+        assert!(sent.is_empty());
+        assert_eq!(counters.no_backend, 1);
+    }
+
+    #[test]
+    fn rewrite_source_from_interface() {
+        let frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let (sent, _) = forward(frame.clone(), &config(&[]));
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        assert_eq!(ether.get_source(), MacAddr::new(2, 0, 0, 0, 0, 1));
+        assert_eq!(ether.get_destination(), MacAddr::new(2, 0, 0, 0, 0, 10));
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_source(), Ipv4Addr::new(192, 0, 2, 1));
+        // Per VIP sources override the interface address, for that VIP only.
+        let config = config(&[("RR_VIP_SOURCES", "10.1.0.1=198.51.100.7")]);
+        let (sent, _) = forward(frame, &config);
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        assert_eq!(ether.get_source(), MacAddr::new(2, 0, 0, 0, 0, 1));
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_source(), Ipv4Addr::new(198, 51, 100, 7));
+        assert_eq!(checksum::checksum(&ip.packet()[..20]), 0);
+        let frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 2));
+        let (sent, _) = forward(frame, &config);
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_source(), Ipv4Addr::new(192, 0, 2, 1));
+    }
+
+    #[test]
+    fn reencapsulate() {
+        let gre = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let ipip = ipip_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        // Matching encapsulations are rewritten in place.
+        let (sent, _) = forward(ipip.clone(), &config(&[("RR_ENCAP", "ipip")]));
+        assert_eq!(sent[0].len(), ipip.len());
+        for &(frame, expected, encap) in &[(&gre, &ipip, "ipip"), (&ipip, &gre, "gre")] {
+            let (sent, _) = forward(frame.to_vec(), &config(&[("RR_ENCAP", encap)]));
+            assert_eq!(sent[0].len(), expected.len());
+            let ether = EthernetPacket::new(&sent[0]).unwrap();
+            assert_eq!(ether.get_source(), MacAddr::new(2, 0, 0, 0, 0, 1));
+            assert_eq!(ether.get_destination(), MacAddr::new(2, 0, 0, 0, 0, 10));
+            let ip = Ipv4Packet::new(ether.payload()).unwrap();
+            assert_eq!(ip.get_ttl(), 63);
+            assert_eq!(ip.get_source(), Ipv4Addr::new(192, 0, 2, 1));
+            assert_eq!(ip.get_destination(), Ipv4Addr::new(192, 0, 2, 10));
+            assert_eq!(checksum::checksum(&ip.packet()[..20]), 0);
+            // Everything from the protocol onwards, bar the checksum, matches.
+            assert_eq!(sent[0][14 + 9], expected[14 + 9]);
+            assert_eq!(&sent[0][14 + 20..], &expected[14 + 20..]);
+        }
+    }
+
+    #[test]
+    fn encapsulate_udp() {
+        let gre = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let vxlan = encap_frame(Encap::Vxlan(42),
+                                Ipv4Addr::new(10, 0, 0, 1),
+                                Ipv4Addr::new(10, 1, 0, 1));
+        let (sent, _) = forward(gre.clone(), &config(&[("RR_ENCAP", "geneve"), ("RR_VNI", "7")]));
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_next_level_protocol(), IpNextHeaderProtocols::Udp);
+        assert_eq!(checksum::checksum(&ip.packet()[..20]), 0);
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        assert_eq!(udp.get_destination(), encap::GENEVE_PORT);
+        assert!(udp.get_source() >= 0xc000);
+        assert_eq!(udp.get_length() as usize, ip.payload().len());
+        assert_eq!(&udp.payload()[..8], &[0, 0, 0x65, 0x58, 0, 0, 7, 0]);
+        let inner_ether = EthernetPacket::new(&udp.payload()[8..]).unwrap();
+        assert_eq!(inner_ether.get_destination(), MacAddr::new(2, 0, 0, 0, 0, 10));
+        assert_eq!(inner_ether.payload(), &gre[14 + 20 + 4..]);
+        // Different flows use different source ports; the same flow always the same one.
+        let other = gre_frame(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 1, 0, 1));
+        let (again, _) = forward(gre, &config(&[("RR_ENCAP", "geneve")]));
+        let (others, _) = forward(other, &config(&[("RR_ENCAP", "geneve")]));
+        assert_eq!(&again[0][14 + 20..14 + 20 + 2], &sent[0][14 + 20..14 + 20 + 2]);
+        assert!(&others[0][14 + 20..14 + 20 + 2] != &sent[0][14 + 20..14 + 20 + 2]);
+        // Arriving as VXLAN, leaving as GRE.
+        let (sent, _) = forward(vxlan, &config(&[]));
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_next_level_protocol(), IpNextHeaderProtocols::Gre);
+        assert_eq!(&ip.payload()[4..], inner_ether.payload());
+    }
+
+    #[test]
+    fn encapsulate_per_backend() {
+        let gre = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let inner = &gre[14 + 20 + 4..];
+        let cases: [(&str, u16, &[u8]); 2] = [("fou", 5555, &[]), ("gue:7000", 7000, &[0, 4, 0, 0])];
+        for &(name, port, header) in &cases {
+            let entry = format!("192.0.2.10={}", name);
+            let (sent, _) = forward(gre.clone(), &config(&[("RR_BACKEND_ENCAP", &entry)]));
+            let ether = EthernetPacket::new(&sent[0]).unwrap();
+            let ip = Ipv4Packet::new(ether.payload()).unwrap();
+            assert_eq!(ip.get_next_level_protocol(), IpNextHeaderProtocols::Udp);
+            let udp = UdpPacket::new(ip.payload()).unwrap();
+            assert_eq!(udp.get_destination(), port);
+            assert!(udp.get_source() >= 0xc000);
+            assert_eq!(&udp.payload()[..header.len()], header);
+            assert_eq!(&udp.payload()[header.len()..], inner);
+        }
+    }
+
+    #[test]
+    fn direct_server_return() {
+        let gre = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let (sent, _) = forward(gre.clone(), &config(&[("RR_ENCAP", "direct")]));
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        assert_eq!(ether.get_source(), MacAddr::new(2, 0, 0, 0, 0, 1));
+        assert_eq!(ether.get_destination(), MacAddr::new(2, 0, 0, 0, 0, 10));
+        assert_eq!(ether.get_ethertype(), EtherTypes::Ipv4);
+        // The inner packet is untouched: the backend holds the VIP itself.
+        assert_eq!(ether.payload(), &gre[14 + 20 + 4..]);
+    }
+
+    #[test]
+    fn gre_key_selects_vip() {
+        let options = GreOptions { key: Some(7), ..GreOptions::default() };
+        let frame = gre_options_frame(options,
+                                      Ipv4Addr::new(10, 0, 0, 1),
+                                      Ipv4Addr::new(10, 1, 0, 1));
+        let keyed = config(&[("RR_GRE_KEY_VIPS", "7=198.51.100.1"),
+                             ("RR_VIP_SOURCES", "198.51.100.1=192.0.2.100")]);
+        let (sent, _) = forward(frame.clone(), &keyed);
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_source(), Ipv4Addr::new(192, 0, 2, 100));
+        // The key goes on to the backend.
+        assert_eq!(&sent[0][14 + 20..], &frame[14 + 20..]);
+        let (sent, _) = forward(frame, &config(&[]));
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_source(), Ipv4Addr::new(192, 0, 2, 1));
+    }
+
+    #[test]
+    fn gre_sequence() {
+        let options = GreOptions {
+            checksum: true,
+            key: Some(7),
+            sequence: Some(9),
+        };
+        let frame = gre_options_frame(options,
+                                      Ipv4Addr::new(10, 0, 0, 1),
+                                      Ipv4Addr::new(10, 1, 0, 1));
+        assert_eq!(checksum::checksum(&frame[14 + 20..]), 0);
+        // Preserved, in place: the GRE checksum still holds.
+        let (sent, _) = forward(frame.clone(), &config(&[]));
+        assert_eq!(&sent[0][14 + 20..], &frame[14 + 20..]);
+        // Stripped, with the GRE checksum recomputed over the shorter header.
+        let (sent, _) = forward(frame.clone(), &config(&[("RR_GRE_SEQUENCE", "strip")]));
+        assert_eq!(sent[0].len(), frame.len() - 4);
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(checksum::checksum(&ip.packet()[..20]), 0);
+        let gre = encap::parse_gre(ip.payload()).unwrap();
+        assert_eq!(gre.options, GreOptions { sequence: None, ..options });
+        assert_eq!(&ip.payload()[12..], &frame[14 + 20 + 16..]);
+    }
+
+    #[test]
+    fn gre_malformed() {
+        let options = GreOptions { checksum: true, ..GreOptions::default() };
+        let mut frame = gre_options_frame(options,
+                                          Ipv4Addr::new(10, 0, 0, 1),
+                                          Ipv4Addr::new(10, 1, 0, 1));
+        frame[14 + 20 + 8 + 8] ^= 1;
+        let (sent, counters) = forward(frame, &config(&[]));
+        assert!(sent.is_empty());
+        assert_eq!((counters.gre_bad_checksum, counters.gre_malformed), (1, 0));
+        // Source routed.
+        let mut frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        frame[14 + 20] = 0x40;
+        let (sent, counters) = forward(frame, &config(&[]));
+        assert!(sent.is_empty());
+        assert_eq!((counters.gre_bad_checksum, counters.gre_malformed), (0, 1));
+        // Claims a key it doesn't have room for.
+        let mut frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        frame.truncate(14 + 20 + 4);
+        frame[14 + 20] = 0x20;
+        {
+            let mut ip = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+            ip.set_total_length(20 + 4);
+        }
+        let (sent, counters) = forward(frame, &config(&[]));
+        assert!(sent.is_empty());
+        assert_eq!(counters.gre_malformed, 1);
+    }
+
+    #[test]
+    fn examine_ipv6_outer() {
+        let frame = ipv6_gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        match examine(&frame, &config(&[])).unwrap() {
+            Direction::Wire(forward) => {
+                assert_eq!(forward.target, Ipv4Addr::new(192, 0, 2, 10));
+                assert_eq!(forward.vip, Ipv4Addr::new(10, 1, 0, 1));
+                assert_eq!(forward.inner, 14 + 40 + 4..frame.len());
+            }
+            _ => panic!("GRE packet not sent to the wire"),
+        }
+        // Other IPv6 is for the host.
+        let mut frame = frame;
+        frame[14 + 6] = 17;
+        match examine(&frame, &config(&[])).unwrap() {
+            Direction::Destination => (),
+            _ => panic!("UDP not passed through"),
+        }
+    }
+
+    #[test]
+    fn forward_over_ipv6() {
+        let gre = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let ipv6_gre = ipv6_gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let backend = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x10);
+        let v6_config = config(&[("RR_TARGET_IPS", "2001:db8::10")]);
+        // Rewritten in place within a family, rebuilt across them.
+        for &(frame, config) in &[(&ipv6_gre, &v6_config), (&gre, &v6_config)] {
+            let (sent, _) = forward(frame.clone(), config);
+            assert_eq!(sent[0].len(), ipv6_gre.len());
+            let ether = EthernetPacket::new(&sent[0]).unwrap();
+            assert_eq!(ether.get_ethertype(), EtherTypes::Ipv6);
+            assert_eq!(ether.get_destination(), MacAddr::new(2, 0, 0, 0, 0, 10));
+            let ip = Ipv6Packet::new(ether.payload()).unwrap();
+            assert_eq!(ip.get_source(), Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+            assert_eq!(ip.get_destination(), backend);
+            assert_eq!(ip.get_hop_limit(), 63);
+            assert_eq!(ip.get_next_header(), IpNextHeaderProtocols::Gre);
+            assert_eq!(ip.payload(), &gre[14 + 20..]);
+        }
+        let (sent, _) = forward(ipv6_gre.clone(), &config(&[]));
+        assert_eq!(sent[0].len(), gre.len());
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_source(), Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(ip.get_ttl(), 63);
+        assert_eq!(checksum::checksum(&ip.packet()[..20]), 0);
+        assert_eq!(ip.payload(), &gre[14 + 20..]);
+        // VIP sources of the other family are passed over.
+        let sourced = config(&[("RR_TARGET_IPS", "2001:db8::10"),
+                               ("RR_VIP_SOURCES", "10.1.0.1=192.0.2.100")]);
+        let (sent, _) = forward(ipv6_gre.clone(), &sourced);
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        let ip = Ipv6Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_source(), Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        // The hop limit runs out as the TTL does, with no ICMPv6 errors.
+        let mut expiring = ipv6_gre;
+        expiring[14 + 7] = 1;
+        let (sent, counters) = forward(expiring, &config(&[("RR_ICMP_TIME_EXCEEDED", "true")]));
+        assert!(sent.is_empty());
+        assert_eq!(counters.ttl_expired, 1);
+    }
+
+    #[test]
+    fn ttl_expiry() {
+        let mut frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        MutableIpv4Packet::new(&mut frame[14..]).unwrap().set_ttl(1);
+        let (sent, counters) = forward(frame.clone(), &config(&[]));
+        assert!(sent.is_empty());
+        assert_eq!(counters.ttl_expired, 1);
+        let (sent, counters) = forward(frame, &config(&[("RR_ICMP_TIME_EXCEEDED", "true")]));
+        assert_eq!(counters.ttl_expired, 1);
+        assert_eq!(sent.len(), 1);
+        let ether = EthernetPacket::new(&sent[0]).unwrap();
+        let ip = Ipv4Packet::new(ether.payload()).unwrap();
+        assert_eq!(ip.get_next_level_protocol(), IpNextHeaderProtocols::Icmp);
+        assert_eq!(ip.get_destination(), Ipv4Addr::new(192, 0, 2, 254));
+    }
+
+    #[test]
+    fn full_tx_ring() {
+        let addresses = Addresses {
+            ipv4: Some(Ipv4Addr::new(192, 0, 2, 1)),
+            ipv6: None,
+        };
+        let icmp = config(&[("RR_ICMP_TIME_EXCEEDED", "true")]);
+        let with_ttl = |ttl| {
+            let mut frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+            {
+                let mut ip = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+                ip.set_ttl(ttl);
+                ip.set_checksum(0);
+                let sum = checksum::checksum(&ip.packet()[..20]);
+                ip.set_checksum(sum);
+            }
+            frame
+        };
+        for frame in vec![with_ttl(2), with_ttl(1)] {
+            let mut wire_in = MemoryIo::new(1);
+            let mut host = MemoryIo::new(1);
+            let mut wire_out = MemoryIo::new(0);
+            let mut tables = tables(&icmp);
+            let mut counters = Counters::default();
+            wire_in.rx.push_back(frame.clone());
+            for _ in 0..2 {
+                match move_packets(&mut wire_in,
+                                   &mut host,
+                                   Some(&mut wire_out),
+                                   &addresses,
+                                   &MacAddr::new(2, 0, 0, 0, 0, 1),
+                                   &icmp,
+                                   &mut Neighbours,
+                                   &mut tables,
+                                   &mut counters) {
+                    Ok(TransferStatus::BlockedWire) => (),
+                    _ => panic!("packet sent without a TX slot"),
+                }
+                // Given back as it arrived, to be forwarded once and only once.
+                assert_eq!(wire_in.rx[0], frame);
+                assert_eq!(counters.ttl_expired, 0);
+            }
+            wire_out.tx_slots = 1;
+            match move_packets(&mut wire_in,
+                               &mut host,
+                               Some(&mut wire_out),
+                               &addresses,
+                               &MacAddr::new(2, 0, 0, 0, 0, 1),
+                               &icmp,
+                               &mut Neighbours,
+                               &mut tables,
+                               &mut counters) {
+                Ok(TransferStatus::Complete) => (),
+                _ => panic!("packet not consumed"),
+            }
+            assert_eq!(wire_out.tx.len(), 1);
+            let ether = EthernetPacket::new(&wire_out.tx[0]).unwrap();
+            let ip = Ipv4Packet::new(ether.payload()).unwrap();
+            assert_eq!(checksum::checksum(&ip.packet()[..20]), 0);
+            if ip.get_next_level_protocol() == IpNextHeaderProtocols::Icmp {
+                assert_eq!(counters.ttl_expired, 1);
+            } else {
+                assert_eq!(ip.get_ttl(), 1);
+                assert_eq!(counters.ttl_expired, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn examine_gre() {
+        let frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        match examine(&frame, &config(&[])).unwrap() {
+            Direction::Wire(forward) => {
+                assert_eq!(forward.target, Ipv4Addr::new(192, 0, 2, 10));
+                assert_eq!(forward.vip, Ipv4Addr::new(10, 1, 0, 1));
+                assert_eq!(forward.encap, Encap::Gre);
+                assert_eq!(forward.inner, 14 + 20 + 4..frame.len());
+            }
+            _ => panic!("GRE packet not sent to the wire"),
+        }
+    }
+
+    #[test]
+    fn examine_gre_ipv6() {
+        let src = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let vip = Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 1);
+        let frame = gre6_frame(src, vip, &[]);
+        let plain = match examine(&frame, &config(&[])).unwrap() {
+            Direction::Wire(forward) => {
+                assert_eq!(forward.target, Ipv4Addr::new(192, 0, 2, 10));
+                assert_eq!(forward.vip, IpAddr::V6(vip));
+                assert_eq!(forward.encap, Encap::Gre);
+                assert_eq!(forward.inner, 14 + 20 + 4..frame.len());
+                forward.hash
+            }
+            _ => panic!("GRE packet not sent to the wire"),
+        };
+        // Extension headers don't hide the upper layer protocol.
+        let hop_by_hop: &[u8] = &[0, 0, 1, 4, 0, 0, 0, 0];
+        let fragment: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 1];
+        let frame = gre6_frame(src, vip, &[(0, hop_by_hop), (44, fragment)]);
+        assert_eq!(ipv6_upper_layer(&frame[14 + 20 + 4..]), (6, 40 + 16, true));
+        match examine(&frame, &config(&[])).unwrap() {
+            Direction::Wire(forward) => assert_eq!(forward.hash, plain),
+            _ => panic!("GRE packet not sent to the wire"),
+        }
+        // Destination options, 16 octets long.
+        let options: &[u8] = &[0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let frame = gre6_frame(src, vip, &[(60, options)]);
+        assert_eq!(ipv6_upper_layer(&frame[14 + 20 + 4..]), (6, 40 + 16, false));
+        assert_eq!(ipv6_upper_layer(&frame[14 + 20 + 4..14 + 20 + 4 + 40 + 8]),
+                   (60, 40, false));
+    }
+
+    #[test]
+    fn forward_ipv6() {
+        let src = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let vip = Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 1);
+        let frame = gre6_frame(src, vip, &[]);
+        let inner = &frame[14 + 20 + 4..];
+        let (sent, _) = forward(frame.clone(), &config(&[]));
+        assert_eq!(&sent[0][14 + 20..], &frame[14 + 20..]);
+        let (sent, _) = forward(frame.clone(), &config(&[("RR_ENCAP", "ipip")]));
+        assert_eq!(sent[0][14 + 9], 41);
+        assert_eq!(&sent[0][14 + 20..], inner);
+        let (sent, _) = forward(frame.clone(), &config(&[("RR_ENCAP", "vxlan")]));
+        assert_eq!(&sent[0][14 + 20 + 8 + 8 + 12..14 + 20 + 8 + 8 + 14], &[0x86, 0xdd]);
+        assert_eq!(&sent[0][14 + 20 + 8 + 8 + 14..], inner);
+        let (sent, _) = forward(frame.clone(), &config(&[("RR_ENCAP", "direct")]));
+        assert_eq!(&sent[0][12..14], &[0x86, 0xdd]);
+        assert_eq!(&sent[0][14..], inner);
+    }
+
+    #[test]
+    fn five_tuple() {
+        let hash = |frame: &[u8], config: &Config| match examine(frame, config).unwrap() {
+            Direction::Wire(forward) => forward.hash,
+            _ => panic!("packet not sent to the wire"),
+        };
+        let three = config(&[]);
+        let five = config(&[("RR_FLOW_HASH", "5-tuple")]);
+        let per_vip = config(&[("RR_VIP_FLOW_HASH", "10.1.0.1=5-tuple")]);
+        let other_vip = config(&[("RR_VIP_FLOW_HASH", "10.1.0.2=5-tuple")]);
+        // TCP, UDP and SCTP ports, and ICMP echo identifiers.
+        let echo = l4_frame(1, &[8, 0, 0, 0, 0, 1, 0, 1]);
+        let other_echo = l4_frame(1, &[8, 0, 0, 0, 0, 2, 0, 1]);
+        let mut pairs = vec![(echo, other_echo)];
+        for &(protocol, ports, other_ports) in &[(6, [0x30, 0x39, 0, 80], [0x30, 0x3a, 0, 80]),
+                                                 (17, [0x30, 0x39, 0, 53], [0x30, 0x39, 0, 54]),
+                                                 (132, [0, 1, 0, 2], [0, 1, 0, 3])] {
+            pairs.push((l4_frame(protocol, &ports), l4_frame(protocol, &other_ports)));
+        }
+        for &(ref a, ref b) in &pairs {
+            assert_eq!(hash(a, &three), hash(b, &three));
+            assert!(hash(a, &five) != hash(b, &five));
+            assert_eq!(hash(a, &per_vip), hash(a, &five));
+            assert_eq!(hash(a, &other_vip), hash(a, &three));
+        }
+        // Other ICMP, and truncated headers, have no ports.
+        let unreachable = l4_frame(1, &[3, 0, 0, 0, 0, 1, 0, 1]);
+        assert_eq!(hash(&unreachable, &five), hash(&unreachable, &three));
+        let short = l4_frame(6, &[0x30, 0x39]);
+        assert_eq!(hash(&short, &five), hash(&short, &three));
+        // IPv4 fragments after the first carry no ports (see fragment_table).
+        let mut last = l4_frame(6, &[0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 0]);
+        last[14 + 20 + 4 + 7] = 1;
+        assert_eq!(hash(&last, &five), hash(&last, &three));
+        // Every IPv6 fragment goes by the addresses alone, found by the fragment header.
+        let src = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let vip = Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 1);
+        let plain = gre6_frame(src, vip, &[]);
+        let fragment: &[u8] = &[0, 0, 0, 1, 0, 0, 0, 1];
+        let fragmented = gre6_frame(src, vip, &[(44, fragment)]);
+        assert!(hash(&plain, &five) != hash(&plain, &three));
+        assert_eq!(hash(&fragmented, &five), hash(&plain, &three));
+    }
+
+    #[test]
+    fn fragment_table() {
+        let five = config(&[("RR_FLOW_HASH", "5-tuple")]);
+        let mut tables = tables(&five);
+        // One slot, which every datagram shares.
+        tables.fragments = Fragments::new(1);
+        let mut counters = Counters::default();
+        let hash = |frame: &[u8], tables: &mut Tables, counters: &mut Counters| {
+            match examine_one(frame, &five, tables, counters).unwrap() {
+                Direction::Wire(forward) => forward.hash,
+                _ => panic!("packet not sent to the wire"),
+            }
+        };
+        // Fragments of datagram id, first with ports and more to come, or later with other data.
+        let fragment = |id: u8, ports: [u8; 4], offset: u8| {
+            let mut frame = l4_frame(6, &ports);
+            frame[14 + 20 + 4 + 5] = id;
+            frame[14 + 20 + 4 + 6] = if offset == 0 { 0x20 } else { 0 };
+            frame[14 + 20 + 4 + 7] = offset;
+            frame
+        };
+        let whole = l4_frame(6, &[0x30, 0x39, 0, 80]);
+        let first = hash(&fragment(1, [0x30, 0x39, 0, 80], 0), &mut tables, &mut counters);
+        assert_eq!(first, hash(&whole, &mut tables, &mut counters));
+        let later = fragment(1, [0xde, 0xad, 0xbe, 0xef], 1);
+        assert_eq!(hash(&later, &mut tables, &mut counters), first);
+        // Without its first fragment a later one goes by the addresses.
+        let three = match examine(&whole, &config(&[])).unwrap() {
+            Direction::Wire(forward) => forward.hash,
+            _ => panic!("packet not sent to the wire"),
+        };
+        let stray = fragment(2, [0xde, 0xad, 0xbe, 0xef], 1);
+        assert_eq!(hash(&stray, &mut tables, &mut counters), three);
+        assert_eq!(counters.fragments_unmatched, 1);
+        // A first fragment takes the slot over from another datagram's, still in time.
+        let second = hash(&fragment(2, [0x30, 0x3a, 0, 80], 0), &mut tables, &mut counters);
+        assert_eq!(counters.fragments_overflowed, 1);
+        assert_eq!(hash(&later, &mut tables, &mut counters), three);
+        assert_eq!(hash(&fragment(2, [0; 4], 1), &mut tables, &mut counters), second);
+        // Expired first fragments are forgotten, on lookup or when their slot is taken over.
+        tables.now += FRAGMENT_LIFETIME_SECS + 1;
+        assert_eq!(hash(&fragment(2, [0; 4], 1), &mut tables, &mut counters), three);
+        assert_eq!(counters.fragments_expired, 1);
+        hash(&fragment(3, [0x30, 0x3b, 0, 80], 0), &mut tables, &mut counters);
+        tables.now += FRAGMENT_LIFETIME_SECS + 1;
+        hash(&fragment(4, [0x30, 0x3c, 0, 80], 0), &mut tables, &mut counters);
+        assert_eq!((counters.fragments_expired, counters.fragments_overflowed), (2, 1));
+    }
+
     #[test]
-    fn it_works() {}
+    fn key_rotation() {
+        let targets = ("RR_TARGET_IPS", "192.0.2.10;192.0.2.11;192.0.2.12");
+        let five = ("RR_FLOW_HASH", "5-tuple");
+        let old_key = ("RR_FLOW_KEY", "000102030405060708090a0b0c0d0e0f");
+        let new_key = ("RR_FLOW_KEY", "f0e0d0c0b0a090807060504030201000");
+        let old = config(&[targets, five, old_key]);
+        let new = config(&[targets, five, new_key]);
+        let rotating = config(&[targets, five, new_key, ("RR_PREVIOUS_FLOW_KEY", old_key.1)]);
+        let target = |frame: &[u8], config: &Config, tables: &mut Tables| {
+            match examine_one(frame, config, tables, &mut Counters::default()).unwrap() {
+                Direction::Wire(forward) => forward.target,
+                _ => panic!("packet not sent to the wire"),
+            }
+        };
+        // A TCP segment from port with flags.
+        let segment = |port: u8, flags: u8| {
+            let mut tcp = [0u8; 20];
+            tcp[1] = port;
+            tcp[3] = 80;
+            tcp[13] = flags;
+            l4_frame(6, &tcp)
+        };
+        let mut tables = tables(&rotating);
+        let moves = |port: u8, tables: &mut Tables| {
+            target(&segment(port, 0x10), &old, tables) != target(&segment(port, 0x10), &new, tables)
+        };
+        let port = (1..255).find(|&p| moves(p, &mut tables)).unwrap();
+        let other = (port + 1..255).find(|&p| moves(p, &mut tables)).unwrap();
+        let before = target(&segment(port, 0x10), &old, &mut tables);
+        let after = target(&segment(port, 0x10), &new, &mut tables);
+        // Without a rotation nothing is remembered.
+        target(&segment(port, 0x02), &new, &mut tables);
+        assert!(tables.connections.slots.iter().all(|slot| slot.is_none()));
+        // Established connections, and anything not TCP, stay put while new connections move.
+        assert_eq!(target(&segment(port, 0x10), &rotating, &mut tables), before);
+        assert_eq!(target(&segment(port, 0x02), &rotating, &mut tables), after);
+        assert_eq!(target(&segment(port, 0x10), &rotating, &mut tables), after);
+        assert_eq!(target(&segment(port, 0x12), &rotating, &mut tables), after);
+        assert_eq!(target(&segment(other, 0x10), &rotating, &mut tables),
+                   target(&segment(other, 0x10), &old, &mut tables));
+        let udp = l4_frame(17, &[0, port, 0, 80]);
+        assert_eq!(target(&udp, &rotating, &mut tables), target(&udp, &old, &mut tables));
+        // Connections whose slot another live connection holds are lost to the previous keys.
+        tables.connections = Connections::new(1);
+        let mut counters = Counters::default();
+        examine_one(&segment(other, 0x02), &rotating, &mut tables, &mut counters).unwrap();
+        examine_one(&segment(port, 0x02), &rotating, &mut tables, &mut counters).unwrap();
+        assert_eq!(counters.connections_overflowed, 1);
+        assert_eq!(target(&segment(port, 0x10), &rotating, &mut tables), before);
+    }
+
+    #[test]
+    fn published_routes() {
+        let targets = ("RR_TARGET_IPS", "192.0.2.10;192.0.2.11");
+        let rotating = config(&[targets,
+                                ("RR_PREVIOUS_FLOW_KEY", "000102030405060708090a0b0c0d0e0f")]);
+        let config = config(&[targets]);
+        let mut published = Published::new(config.lookup_tables());
+        let mut tables = Tables::new(published.view());
+        let target = |frame: &[u8], config: &Config, tables: &mut Tables| {
+            match examine_one(frame, config, tables, &mut Counters::default()).unwrap() {
+                Direction::Wire(forward) => forward.target,
+                _ => panic!("packet not sent to the wire"),
+            }
+        };
+        let frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        let before = target(&frame, &config, &mut tables);
+        let mut routes = config.lookup_tables();
+        routes.current.set_live(&before.to_string(), false).unwrap();
+        routes.current.populate();
+        published.publish(routes);
+        // Forwarding keeps to its snapshot until it next refreshes.
+        assert_eq!(target(&frame, &config, &mut tables), before);
+        tables.routes.refresh();
+        assert!(target(&frame, &config, &mut tables) != before);
+        // While keys are rotated, flows that are not new connections go by the previous table,
+        // published alongside.
+        let mut published = Published::new(rotating.lookup_tables());
+        let mut tables = Tables::new(published.view());
+        let before = target(&frame, &rotating, &mut tables);
+        let mut routes = rotating.lookup_tables();
+        routes.current.set_live(&before.to_string(), false).unwrap();
+        routes.current.populate();
+        published.publish(routes.clone());
+        tables.routes.refresh();
+        assert_eq!(target(&frame, &rotating, &mut tables), before);
+        if let Some(ref mut previous) = routes.previous {
+            previous.set_live(&before.to_string(), false).unwrap();
+            previous.populate();
+        }
+        published.publish(routes);
+        tables.routes.refresh();
+        assert!(target(&frame, &rotating, &mut tables) != before);
+    }
+
+    #[test]
+    fn swap_routes_while_forwarding() {
+        let config = config(&[("RR_TARGET_IPS", "192.0.2.10;192.0.2.11")]);
+        // Tables sending everything to one backend or the other.
+        let only = |live: &str, dead: &str| {
+            let mut routes = config.lookup_tables();
+            routes.current.set_live(dead, false).unwrap();
+            routes.current.populate();
+            assert!(routes.current.target(0) == Some(live.parse().unwrap()));
+            routes
+        };
+        let choices = [only("192.0.2.10", "192.0.2.11"), only("192.0.2.11", "192.0.2.10")];
+        let mut published = Published::new(choices[0].clone());
+        let view = published.view();
+        let stop = Arc::new(AtomicBool::new(false));
+        let publisher = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut publishes = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    publishes += 1;
+                    published.publish(choices[publishes % 2].clone());
+                }
+                publishes
+            })
+        };
+        let mut tables = Tables::new(view);
+        let mut counters = Counters::default();
+        let backends = [Ipv4Addr::new(192, 0, 2, 10), Ipv4Addr::new(192, 0, 2, 11)];
+        for batch in 0..500 {
+            let mut wire_in = MemoryIo::new(0);
+            let mut host = MemoryIo::new(0);
+            let mut wire_out = MemoryIo::new(8);
+            for client in 0..8 {
+                wire_in.rx.push_back(gre_frame(Ipv4Addr::new(10, 0, batch as u8, client),
+                                               Ipv4Addr::new(10, 1, 0, 1)));
+            }
+            tables.routes.refresh();
+            match move_packets(&mut wire_in,
+                               &mut host,
+                               Some(&mut wire_out),
+                               &Addresses {
+                                   ipv4: Some(Ipv4Addr::new(192, 0, 2, 1)),
+                                   ipv6: None,
+                               },
+                               &MacAddr::new(2, 0, 0, 0, 0, 1),
+                               &config,
+                               &mut Neighbours,
+                               &mut tables,
+                               &mut counters) {
+                Ok(TransferStatus::Complete) => (),
+                _ => panic!("packets not consumed"),
+            }
+            // Every packet of a batch goes by the same snapshot.
+            let targets: Vec<Ipv4Addr> = wire_out.tx
+                .iter()
+                .map(|frame| {
+                    let ether = EthernetPacket::new(frame).unwrap();
+                    Ipv4Packet::new(ether.payload()).unwrap().get_destination()
+                })
+                .collect();
+            assert_eq!(targets.len(), 8);
+            assert!(backends.contains(&targets[0]));
+            assert!(targets.iter().all(|target| *target == targets[0]));
+        }
+        stop.store(true, Ordering::SeqCst);
+        assert!(publisher.join().unwrap() > 0);
+    }
+
+    #[test]
+    fn examine_ipip() {
+        let frame = ipip_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        match examine(&frame, &config(&[])).unwrap() {
+            Direction::Wire(forward) => {
+                assert_eq!(forward.target, Ipv4Addr::new(192, 0, 2, 10));
+                assert_eq!(forward.encap, Encap::Ipip);
+                assert_eq!(forward.inner, 14 + 20..frame.len());
+            }
+            _ => panic!("IPIP packet not sent to the wire"),
+        }
+    }
+
+    #[test]
+    fn examine_udp_tunnels() {
+        for &encap in &[Encap::Vxlan(42), Encap::Geneve(7)] {
+            let frame = encap_frame(encap, Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+            match examine(&frame, &config(&[])).unwrap() {
+                Direction::Wire(forward) => {
+                    assert_eq!(forward.target, Ipv4Addr::new(192, 0, 2, 10));
+                    assert_eq!(forward.encap, encap);
+                    assert_eq!(forward.inner, 14 + 20 + 8 + 8 + 14..frame.len());
+                }
+                _ => panic!("{:?} packet not sent to the wire", encap),
+            }
+        }
+        // Other UDP is for the host.
+        let mut frame = encap_frame(Encap::Vxlan(42),
+                                    Ipv4Addr::new(10, 0, 0, 1),
+                                    Ipv4Addr::new(10, 1, 0, 1));
+        frame[14 + 20 + 3] = 53;
+        match examine(&frame, &config(&[])).unwrap() {
+            Direction::Destination => (),
+            _ => panic!("DNS not passed through"),
+        }
+    }
+
+    #[test]
+    fn examine_non_ip() {
+        let mut frame = gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        MutableEthernetPacket::new(&mut frame[..]).unwrap().set_ethertype(EtherTypes::Arp);
+        match examine(&frame, &config(&[])).unwrap() {
+            Direction::Destination => (),
+            _ => panic!("ARP packet not passed through"),
+        }
+    }
+
+    /// A slot in a pretend shared memory region, for both directions.
+    struct Slot {
+        region: usize,
+        buf_idx: u32,
+        len: usize,
+    }
+
+    impl RxSlot for Slot {
+        fn region(&self) -> usize {
+            self.region
+        }
+
+        fn buf_idx(&self) -> u32 {
+            self.buf_idx
+        }
+
+        fn set_buf_idx(&mut self, idx: u32) {
+            self.buf_idx = idx;
+        }
+    }
+
+    impl TxSlot for Slot {
+        fn set_len(&mut self, len: usize) {
+            self.len = len;
+        }
+
+        fn swap_buffer(&mut self, rx: &mut RxSlot, len: usize) -> bool {
+            if rx.region() != self.region {
+                return false;
+            }
+            let ours = self.buf_idx;
+            self.buf_idx = rx.buf_idx();
+            self.len = len;
+            rx.set_buf_idx(ours);
+            true
+        }
+    }
+
+    #[test]
+    fn move_packet_swaps_within_region() {
+        let mut counters = Counters::default();
+        let mut rx = Slot { region: 1, buf_idx: 7, len: 0 };
+        let mut tx = Slot { region: 1, buf_idx: 9, len: 0 };
+        let mut tx_buf = [0u8; 4];
+        assert!(move_packet(Some(&mut rx), &[1, 2, 3], (&mut tx, &mut tx_buf), &mut counters));
+        assert_eq!((rx.buf_idx, tx.buf_idx, tx.len), (9, 7, 3));
+        assert_eq!(tx_buf, [0, 0, 0, 0]);
+        assert_eq!((counters.swapped, counters.copied), (1, 0));
+    }
+
+    #[test]
+    fn move_packet_copies_across_regions() {
+        let mut counters = Counters::default();
+        let mut rx = Slot { region: 1, buf_idx: 7, len: 0 };
+        let mut tx = Slot { region: 2, buf_idx: 9, len: 0 };
+        let mut tx_buf = [0u8; 4];
+        assert!(move_packet(Some(&mut rx), &[1, 2, 3], (&mut tx, &mut tx_buf), &mut counters));
+        assert_eq!((rx.buf_idx, tx.buf_idx, tx.len), (7, 9, 3));
+        assert_eq!(tx_buf, [1, 2, 3, 0]);
+        assert!(move_packet(None, &[4], (&mut tx, &mut tx_buf), &mut counters));
+        assert_eq!((counters.swapped, counters.copied), (0, 2));
+        // Too big to copy: nothing is set.
+        assert!(!move_packet(None, &[5; 5], (&mut tx, &mut tx_buf), &mut counters));
+        assert_eq!((tx.len, counters.copied), (1, 2));
+    }
 }
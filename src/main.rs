@@ -10,20 +10,40 @@ extern crate rusty_rail;
 
 
 use std::env;
+use std::fs::File;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr};
+use std::io::{BufReader, BufWriter, Write};
+use std::mem;
+use std::net::IpAddr;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use ipnetwork::IpNetwork;
 // use netmap::Direction;
 use pnet::datalink::{interfaces, NetworkInterface};
+use pnet::util::MacAddr;
 
 use pnetlink::packet::netlink::NetlinkConnection;
 use pnetlink::packet::route::link::Links;
 
+use rusty_rail::afpacket;
+use rusty_rail::afpacket::{AfPacketRx, AfPacketTx, KernelPassthrough};
+use rusty_rail::afxdp;
 use rusty_rail::arpcache;
-use rusty_rail::configuration::Config;
+use rusty_rail::bpf;
+use rusty_rail::configuration::{self, Config, IoBackend, Threading, XdpMode};
+use rusty_rail::consistenthash;
 use rusty_rail::error::BrokenRail;
-use rusty_rail::{move_packets, TransferStatus};
+use rusty_rail::handoff;
+use rusty_rail::handoff::{HandoffRx, HandoffTx};
+use rusty_rail::netmapio::NetmapIo;
+use rusty_rail::packetio::PacketIo;
+use rusty_rail::pcapio;
+use rusty_rail::tap;
+use rusty_rail::xdp::XdpForwarder;
+use rusty_rail::{move_packets, Addresses, Counters, Tables, TransferStatus};
 
 
 pub fn poll(pollfds: &mut Vec<libc::pollfd>,
@@ -37,6 +57,7 @@ pub fn poll(pollfds: &mut Vec<libc::pollfd>,
     // TODO: fix layering violations.
     // 0, 2 we read from.
     // 1 we only write to.
+    // Anything after 2 (handoffs from other threads) we only read from.
     // Need to have a marker for switching to block-until-we-can-write.
     if wire_read {
         pollfds[0].events = libc::POLLIN;
@@ -50,6 +71,9 @@ pub fn poll(pollfds: &mut Vec<libc::pollfd>,
     } else {
         pollfds[2].events = libc::POLLOUT
     }
+    for extra in pollfds.iter_mut().skip(3) {
+        extra.events = libc::POLLIN
+    }
 
     let poll_len = pollfds.len();
     if let Some(first) = pollfds.first_mut() {
@@ -66,6 +90,60 @@ pub fn poll(pollfds: &mut Vec<libc::pollfd>,
 }
 
 
+// How often to print packet counters.
+const REPORT_INTERVAL_SECS: u64 = 10;
+
+
+/// Set by SIGHUP, asking for the backends to be read again.
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn hangup(_: libc::c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+}
+
+
+/// If SIGHUP has asked for it, read the configuration again and update the backends of routes to
+/// match, returning the new routes and the backends' addresses. Backends are updated in place,
+/// so that as few flows as possible move; other settings need a restart to change. A
+/// configuration that cannot be read leaves the backends as they are.
+fn reload(routes: &consistenthash::Routes) -> Option<(consistenthash::Routes, Vec<IpAddr>)> {
+    if !HANGUP.swap(false, Ordering::SeqCst) {
+        return None;
+    }
+    match rebuild_routes(routes) {
+        Ok(reloaded) => Some(reloaded),
+        Err(err) => {
+            println!("reload failed, keeping the current backends: {}", err);
+            None
+        }
+    }
+}
+
+
+fn rebuild_routes(routes: &consistenthash::Routes)
+                  -> Result<(consistenthash::Routes, Vec<IpAddr>), BrokenRail> {
+    let config = try!(Config::new(try!(configuration::settings(env::vars())).into_iter()));
+    let (routes, disruption) = try!(config.update_routes(routes));
+    println!("reloaded {} backends, moving {:.1}% of the lookup table",
+             config.target_ips.len(),
+             disruption.fraction * 100.0);
+    Ok((routes, config.target_ips))
+}
+
+
+/// Publish the routes again whenever SIGHUP asks for them to be reloaded, off the forwarding
+/// path.
+fn republish(mut published: consistenthash::Published, mut routes: consistenthash::Routes) {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        if let Some((next, _)) = reload(&routes) {
+            published.publish(next.clone());
+            routes = next;
+        }
+    }
+}
+
+
 fn pollfd(fd: i32) -> libc::pollfd {
     libc::pollfd {
         fd: fd,
@@ -80,21 +158,181 @@ fn device_name(device: &String, suffix: &str) -> String {
 }
 
 
-fn extract_ipv4(interface: &NetworkInterface) -> Result<Ipv4Addr, BrokenRail> {
-
+/// The interface's addresses to forward from, which must include one of each family in targets.
+fn extract_addresses(interface: &NetworkInterface,
+                     targets: &[IpAddr])
+                     -> Result<Addresses, BrokenRail> {
+    let mut addresses = Addresses::default();
     for ip in &interface.ips {
-        if let &IpNetwork::V4(netv4) = ip {
-            println!("{}", netv4);
-            return Ok(netv4.ip());
+        match *ip {
+            IpNetwork::V4(netv4) if addresses.ipv4.is_none() => {
+                println!("{}", netv4);
+                addresses.ipv4 = Some(netv4.ip());
+            }
+            // Link local addresses can't reach backends off link.
+            IpNetwork::V6(netv6) if addresses.ipv6.is_none() &&
+                                    netv6.ip().segments()[0] & 0xffc0 != 0xfe80 => {
+                println!("{}", netv6);
+                addresses.ipv6 = Some(netv6.ip());
+            }
+            _ => (),
+        }
+    }
+    if addresses.ipv4.is_none() && targets.iter().any(|t| t.is_ipv4()) {
+        return Err(BrokenRail::NoIPV4Address);
+    }
+    if addresses.ipv6.is_none() && targets.iter().any(|t| t.is_ipv6()) {
+        return Err(BrokenRail::NoIPV6Address);
+    }
+    Ok(addresses)
+}
+
+
+/// Open the wire RX, wire TX and host sides for the configured backend.
+fn open_backend(config: &Config)
+                -> Result<(Box<PacketIo>, Box<PacketIo>, Box<PacketIo>), BrokenRail> {
+    match config.backend {
+        IoBackend::Netmap => {
+            // netmap-rs iterators lock the whole NetmapDescriptor, so we open two descriptors for
+            // the adapter: one RX only, and on TX only. We open a single bidirectional descriptor
+            // for the host side as we have no use case today for looping packets back to the host
+            // side.
+            //
+            // All three share one memory region so that move_packets can swap buffers between
+            // them instead of copying.
+            let nm_in = try!(NetmapIo::new(&device_name(&config.device, "/R")));
+            let nm_out = try!(NetmapIo::new_with_memory(&device_name(&config.device, "/T"),
+                                                        &nm_in));
+            let nm_host = try!(NetmapIo::new_with_memory(&device_name(&config.device, "^"),
+                                                         &nm_in));
+            Ok((Box::new(nm_in), Box::new(nm_out), Box::new(nm_host)))
+        }
+        IoBackend::AfPacket => {
+            // The kernel keeps receiving everything on the device itself, so the host side is a
+            // sink.
+            let rx = try!(AfPacketRx::new(&config.device));
+            let tx = try!(AfPacketTx::new(&config.device));
+            Ok((Box::new(rx), Box::new(tx), Box::new(KernelPassthrough::new())))
+        }
+        IoBackend::AfXdp => {
+            // Only GRE is redirected to us; the kernel gets everything else directly.
+            let (rx, tx) = try!(afxdp::open(&config.device, xdp_flags(config)));
+            Ok((Box::new(rx), Box::new(tx), Box::new(KernelPassthrough::new())))
         }
+        IoBackend::Tap => {
+            // Whatever the kernel routes into the TAP is the wire; there is no host stack behind
+            // rusty rail to pass anything else to.
+            let (rx, tx) = try!(tap::open(&config.device));
+            Ok((Box::new(rx), Box::new(tx), Box::new(KernelPassthrough::new())))
+        }
+        IoBackend::Xdp => Err(BrokenRail::Configuration("xdp has no userspace rings".to_string())),
+    }
+}
+
+
+fn xdp_flags(config: &Config) -> u32 {
+    match config.xdp_mode {
+        XdpMode::Generic => bpf::XDP_FLAGS_SKB_MODE,
+        XdpMode::Native => bpf::XDP_FLAGS_DRV_MODE,
     }
-    return Err(BrokenRail::NoIPV4Address);
 }
 
 
-fn stuff() -> Result<(), BrokenRail> {
-    let mut pollfds: Vec<libc::pollfd> = Vec::with_capacity(2);
-    let config = try!(Config::new(env::vars()));
+/// Forward in the kernel: keep the XDP program's tables up to date with the routes, reloaded on
+/// SIGHUP, and ARP.
+fn run_xdp(config: &Config,
+           interface_ips: &Addresses,
+           interface_mac: &MacAddr,
+           arp_cache: &mut arpcache::Cache)
+           -> Result<(), BrokenRail> {
+    let source_ipv4 = match interface_ips.ipv4 {
+        Some(source_ipv4) => source_ipv4,
+        None => return Err(BrokenRail::NoIPV4Address),
+    };
+    let mut forwarder = try!(XdpForwarder::new(config.flow_key.0, config.flow_key.1));
+    let mut routes = config.lookup_tables();
+    try!(forwarder.publish(&routes.current,
+                           &source_ipv4,
+                           interface_mac,
+                           &config.vip_sources,
+                           &mut |addr| arp_cache.lookup(addr)));
+    try!(forwarder.attach(try!(afpacket::ifindex(&config.device)), xdp_flags(config)));
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        arp_cache.expire();
+        if let Some((next, _)) = reload(&routes) {
+            routes = next;
+        }
+        try!(forwarder.publish(&routes.current,
+                               &source_ipv4,
+                               interface_mac,
+                               &config.vip_sources,
+                               &mut |addr| arp_cache.lookup(addr)));
+    }
+}
+
+
+/// Forward the packets in a pcap as though received from the wire, capturing the results.
+fn replay(input: &str,
+          prefix: &str,
+          interface_ips: &Addresses,
+          interface_mac: &MacAddr,
+          config: &Config,
+          arp_cache: &mut arpcache::Cache)
+          -> Result<(), BrokenRail> {
+    let create = |suffix: &str| File::create(format!("{}-{}.pcap", prefix, suffix)).map(BufWriter::new);
+    let (mut wire_in, mut wire_out, mut host) =
+        try!(pcapio::replay(BufReader::new(try!(File::open(input))),
+                            try!(create("wire")),
+                            try!(create("host")),
+                            try!(create("dropped"))));
+    let mut unparseable = 0;
+    let mut tables = Tables::new(consistenthash::Published::new(config.lookup_tables()).view());
+    let mut counters = Counters::default();
+    loop {
+        // The captures always have room, so the only way to finish is running out of input.
+        match move_packets(&mut wire_in,
+                           &mut host,
+                           Some(&mut wire_out),
+                           interface_ips,
+                           interface_mac,
+                           config,
+                           arp_cache,
+                           &mut tables,
+                           &mut counters) {
+            Ok(TransferStatus::Complete) => break,
+            Ok(_) => (),
+            Err(BrokenRail::BadPacket) => unparseable += 1,
+            Err(err) => return Err(err),
+        }
+    }
+    let read = wire_in.read;
+    let mut outputs = vec![];
+    for (name, writer) in vec![("wire", wire_out.into_writer()),
+                               ("host", host.into_writer()),
+                               ("dropped", wire_in.into_dropped())] {
+        outputs.push(format!("{} {}", writer.written, name));
+        try!(writer.into_inner().flush());
+    }
+    println!("read {} packets ({} unparseable, {} ttl expired, {} malformed GRE, {} bad GRE \
+              checksums, {} oversized, {} without a backend, {} without a source, {} unmatched \
+              fragments): {}",
+             read,
+             unparseable,
+             counters.ttl_expired,
+             counters.gre_malformed,
+             counters.gre_bad_checksum,
+             counters.oversized,
+             counters.no_backend,
+             counters.no_source,
+             counters.fragments_unmatched,
+             outputs.join(", "));
+    Ok(())
+}
+
+
+fn stuff(args: Vec<String>) -> Result<(), BrokenRail> {
+    let config = try!(Config::new(try!(configuration::settings(env::vars())).into_iter()));
 
     let interface_names_match = {
         |iface: &NetworkInterface| iface.name == config.device
@@ -104,34 +342,114 @@ fn stuff() -> Result<(), BrokenRail> {
         .filter(interface_names_match)
         .next()
         .unwrap();
-    let interface_ipv4 = try!(extract_ipv4(&interface));
+    let interface_ips = try!(extract_addresses(&interface, &config.target_ips));
 
     let mut netlink = NetlinkConnection::new();
     let nl_link = netlink.get_link_by_name(&config.device).unwrap().unwrap();
     let mut arp_cache = arpcache::Cache::new(nl_link, netlink);
+    for &(ip, mac) in &config.static_arp {
+        arp_cache.add(&ip, &mac);
+    }
     println!("interface {}", interface.mac_address());
     let interface_mac = interface.mac_address();
-    // netmap-rs iterators lock the whole NetmapDescriptor, so we open two descriptors for the
-    // adapter: one RX only, and on TX only. We open a single bidirectional descriptor for the host
-    // side as we have no use case today for looping packets back to the host side.
-
-    let mut nm_in = try!(netmap::NetmapDescriptor::new(&device_name(&config.device, "/R")));
-    pollfds.push(pollfd(nm_in.get_fd()));
-    println!("wire RX fd {}", pollfds[0].fd);
-
-    let mut nm_out = try!(netmap::NetmapDescriptor::new(&device_name(&config.device, "/T")));
-    pollfds.push(pollfd(nm_out.get_fd()));
-    println!("wire RX fd {}", pollfds[1].fd);
+    if args.get(1).map(|a| a.as_str()) == Some("replay") {
+        if args.len() != 4 {
+            return Err(BrokenRail::Configuration("usage: rusty_rail replay INPUT.pcap PREFIX"
+                .to_string()));
+        }
+        return replay(&args[2],
+                      &args[3],
+                      &interface_ips,
+                      &interface_mac,
+                      &config,
+                      &mut arp_cache);
+    }
+    // Ask for the backends to be reloaded, rather than for rusty rail to exit.
+    unsafe {
+        libc::signal(libc::SIGHUP, hangup as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    if config.backend == IoBackend::Xdp {
+        return run_xdp(&config, &interface_ips, &interface_mac, &mut arp_cache);
+    }
+    if config.threading == Threading::PerRing {
+        return run_per_ring(config, interface_ips, interface_mac, arp_cache);
+    }
+    let (mut wire_in, mut wire_out, mut host) = try!(open_backend(&config));
+    let published_routes = consistenthash::Published::new(config.lookup_tables());
+    let routes = published_routes.view();
+    let lookup_tables = config.lookup_tables();
+    try!(thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || republish(published_routes, lookup_tables)));
+    forward("",
+            &mut *wire_in,
+            &mut *wire_out,
+            &mut *host,
+            None,
+            &interface_ips,
+            &interface_mac,
+            &config,
+            routes,
+            &mut arp_cache)
+}
 
 
-    let mut nm_host = try!(netmap::NetmapDescriptor::new(&device_name(&config.device, "^")));
-    pollfds.push(pollfd(nm_host.get_fd()));
-    println!("host fd {}", pollfds[2].fd);
+/// Forward between one set of rings, returning only on error.
+///
+/// handoff, if given, is a further source of packets for the host, passed on by other threads.
+/// Backends are chosen from whatever routes were last published, picked up between batches.
+fn forward(name: &str,
+           wire_in: &mut PacketIo,
+           wire_out: &mut PacketIo,
+           host: &mut PacketIo,
+           mut handoff: Option<&mut PacketIo>,
+           interface_ips: &Addresses,
+           interface_mac: &MacAddr,
+           config: &Config,
+           routes: consistenthash::View,
+           arp: &mut arpcache::Resolver)
+           -> Result<(), BrokenRail> {
+    let mut pollfds: Vec<libc::pollfd> = Vec::with_capacity(4);
+    pollfds.push(pollfd(wire_in.fd()));
+    println!("{}wire RX fd {}", name, pollfds[0].fd);
+    pollfds.push(pollfd(wire_out.fd()));
+    println!("{}wire TX fd {}", name, pollfds[1].fd);
+    pollfds.push(pollfd(host.fd()));
+    println!("{}host fd {}", name, pollfds[2].fd);
+    if let Some(ref handoff) = handoff {
+        pollfds.push(pollfd(handoff.fd()));
+        println!("{}handoff fd {}", name, pollfds[3].fd);
+    }
 
     let mut host_read = true;
     let mut wire_read = true;
+    let mut tables = Tables::new(routes);
+    let mut counters = Counters::default();
+    let mut reported = Instant::now();
 
     loop {
+        if reported.elapsed() >= Duration::from_secs(REPORT_INTERVAL_SECS) {
+            println!("{}swapped {} copied {} ttl expired {} GRE malformed {} bad checksum {} \
+                      oversized {} no backend {} no source {} fragments unmatched {} expired {} \
+                      overflowed {} connections overflowed {}",
+                     name,
+                     counters.swapped,
+                     counters.copied,
+                     counters.ttl_expired,
+                     counters.gre_malformed,
+                     counters.gre_bad_checksum,
+                     counters.oversized,
+                     counters.no_backend,
+                     counters.no_source,
+                     counters.fragments_unmatched,
+                     counters.fragments_expired,
+                     counters.fragments_overflowed,
+                     counters.connections_overflowed);
+            reported = Instant::now();
+        }
+        // Refresh even when idle, so that tables retired meanwhile can be freed.
+        arp.refresh();
+        tables.routes.refresh();
         if 0 == try!(poll(&mut pollfds, wire_read, host_read)) {
             //       println!("Poll timeout");
             continue;
@@ -145,13 +463,15 @@ fn stuff() -> Result<(), BrokenRail> {
             }
         }
         // println!("Host -> out queue");
-        match try!(move_packets(&mut nm_host,
-                                &mut nm_out,
+        match try!(move_packets(host,
+                                wire_out,
                                 None,
-                                &interface_ipv4,
-                                &interface_mac,
-                                &config.routes,
-                                &mut arp_cache)) {
+                                interface_ips,
+                                interface_mac,
+                                config,
+                                arp,
+                                &mut tables,
+                                &mut counters)) {
             TransferStatus::BlockedDestination |
             TransferStatus::BlockedWire => {
                 host_read = false;
@@ -160,22 +480,172 @@ fn stuff() -> Result<(), BrokenRail> {
             TransferStatus::Complete => (),
         }
         // println!("Wire -> Host or out queue");
-        match try!(move_packets(&mut nm_in,
-                                &mut nm_host,
-                                Some(&mut nm_out),
-                                &interface_ipv4,
-                                &interface_mac,
-                                &config.routes,
-                                &mut arp_cache)) {
+        match try!(move_packets(wire_in,
+                                host,
+                                Some(wire_out),
+                                interface_ips,
+                                interface_mac,
+                                config,
+                                arp,
+                                &mut tables,
+                                &mut counters)) {
             TransferStatus::BlockedDestination => wire_read = false,
             TransferStatus::BlockedWire => host_read = false,
             TransferStatus::Complete => (),
         }
+        if let Some(ref mut handoff) = handoff {
+            // Anything left behind by a full host ring is picked up on a later pass.
+            try!(move_packets(*handoff,
+                              host,
+                              None,
+                              interface_ips,
+                              interface_mac,
+                              config,
+                              arp,
+                              &mut tables,
+                              &mut counters));
+        }
+    }
+}
+
+
+/// How a per-ring worker reaches the host stack.
+enum HostPath {
+    /// Own the host rings, and receive host bound packets from the other workers.
+    Rings(HandoffRx),
+    /// Pass host bound packets to the worker owning the host rings.
+    Handoff(HandoffTx),
+}
+
+
+fn pin_to_cpu(cpu: usize) -> Result<(), BrokenRail> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) < 0 {
+            return Err(BrokenRail::IO(io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+
+/// Forward the packets of one NIC ring pair.
+fn run_ring(ring: u16,
+            config: &Config,
+            interface_ips: &Addresses,
+            interface_mac: &MacAddr,
+            routes: consistenthash::View,
+            published: Arc<arpcache::Published>,
+            host_path: HostPath)
+            -> Result<(), BrokenRail> {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } as usize;
+    try!(pin_to_cpu(ring as usize % cpus));
+    let mut arp = arpcache::View::new(published);
+    let ring_name = format!("{}-{}", config.device, ring);
+    let mut wire_in = try!(NetmapIo::new(&device_name(&ring_name, "/R")));
+    let mut wire_out = try!(NetmapIo::new_with_memory(&device_name(&ring_name, "/T"), &wire_in));
+    let name = format!("ring {}: ", ring);
+    match host_path {
+        HostPath::Rings(mut handoff) => {
+            let mut host = try!(NetmapIo::new_with_memory(&device_name(&config.device, "^"),
+                                                          &wire_in));
+            forward(&name,
+                    &mut wire_in,
+                    &mut wire_out,
+                    &mut host,
+                    Some(&mut handoff),
+                    interface_ips,
+                    interface_mac,
+                    config,
+                    routes,
+                    &mut arp)
+        }
+        HostPath::Handoff(mut host) => {
+            forward(&name,
+                    &mut wire_in,
+                    &mut wire_out,
+                    &mut host,
+                    None,
+                    interface_ips,
+                    interface_mac,
+                    config,
+                    routes,
+                    &mut arp)
+        }
+    }
+}
+
+
+/// Forward with one pinned thread per NIC ring pair.
+///
+/// The threads read the routes published to them; this thread reloads the backends on SIGHUP,
+/// and resolves their MAC addresses and publishes them to the workers every second.
+fn run_per_ring(config: Config,
+                interface_ips: Addresses,
+                interface_mac: MacAddr,
+                mut arp_cache: arpcache::Cache)
+                -> Result<(), BrokenRail> {
+    let rings = {
+        let probe = try!(NetmapIo::new(&device_name(&config.device, "/R")));
+        let (first, last) = probe.descriptor.get_rx_rings();
+        last - first + 1
+    };
+    println!("{} rings", rings);
+    let mut routes = config.lookup_tables();
+    let mut published_routes = consistenthash::Published::new(routes.clone());
+    let mut target_ips = config.target_ips.clone();
+    let config = Arc::new(config);
+    let published = Arc::new(arpcache::Published::new());
+    published.publish(&mut arp_cache, target_ips.iter());
+    let (handoff_tx, handoff_rx) = try!(handoff::handoff());
+    let mut handoff_rx = Some(handoff_rx);
+    let (failed_tx, failed) = mpsc::channel();
+    for ring in 0..rings {
+        let host_path = match handoff_rx.take() {
+            Some(handoff_rx) => HostPath::Rings(handoff_rx),
+            None => HostPath::Handoff(try!(handoff_tx.try_clone())),
+        };
+        let config = config.clone();
+        let routes = published_routes.view();
+        let published = published.clone();
+        let failed_tx = failed_tx.clone();
+        try!(thread::Builder::new()
+            .name(format!("ring-{}", ring))
+            .spawn(move || {
+                let err = match run_ring(ring,
+                                         &config,
+                                         &interface_ips,
+                                         &interface_mac,
+                                         routes,
+                                         published,
+                                         host_path) {
+                    Ok(()) => return,
+                    Err(err) => err,
+                };
+                let _ = failed_tx.send((ring, err));
+            }));
+    }
+    loop {
+        match failed.recv_timeout(Duration::from_secs(1)) {
+            Ok((ring, err)) => {
+                println!("ring {} failed", ring);
+                return Err(err);
+            }
+            Err(_) => (),
+        }
+        if let Some((next, targets)) = reload(&routes) {
+            published_routes.publish(next.clone());
+            routes = next;
+            target_ips = targets;
+        }
+        arp_cache.expire();
+        published.publish(&mut arp_cache, target_ips.iter());
     }
 }
 
 fn main() {
-    match stuff() {
+    match stuff(env::args().collect()) {
         Ok(()) => println!("Actual cannibal unreachable code"),
         Err(err) => panic!("{}", err),
     };
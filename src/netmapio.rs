@@ -0,0 +1,190 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// PacketIo backend for netmap descriptors.
+
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use netmap;
+use netmap::{NetmapSlot, NetmapRing};
+
+use super::error;
+use super::packetio::{PacketIo, RxSlot, TxSlot};
+
+// Descriptors opened without a parent each get their own memory region id. We only know two
+// descriptors share an allocator when we asked for it, so anything else is assumed distinct.
+static NEXT_REGION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The netmap slot of the last packet handed out by rx_next.
+pub struct NetmapRxSlot {
+    slot: *mut netmap::RxSlot,
+    region: usize,
+}
+
+impl RxSlot for NetmapRxSlot {
+    fn region(&self) -> usize {
+        self.region
+    }
+
+    fn buf_idx(&self) -> u32 {
+        unsafe { (*self.slot).get_buf_idx() }
+    }
+
+    fn set_buf_idx(&mut self, idx: u32) {
+        unsafe {
+            (*self.slot).set_buf_idx(idx);
+            let flags = (*self.slot).get_flags();
+            (*self.slot).set_flags(flags | netmap::NS_BUF_CHANGED);
+        }
+    }
+}
+
+/// The netmap slot last handed out by tx_next.
+pub struct NetmapTxSlot {
+    slot: *mut netmap::TxSlot,
+    region: usize,
+}
+
+impl TxSlot for NetmapTxSlot {
+    fn set_len(&mut self, len: usize) {
+        unsafe { (*self.slot).set_len(len as u16) }
+    }
+
+    fn swap_buffer(&mut self, rx: &mut RxSlot, len: usize) -> bool {
+        if rx.region() != self.region {
+            return false;
+        }
+        unsafe {
+            let ours = (*self.slot).get_buf_idx();
+            (*self.slot).set_buf_idx(rx.buf_idx());
+            let flags = (*self.slot).get_flags();
+            (*self.slot).set_flags(flags | netmap::NS_BUF_CHANGED);
+            (*self.slot).set_len(len as u16);
+            rx.set_buf_idx(ours);
+        }
+        true
+    }
+}
+
+/// A netmap descriptor (e.g. "eth0/R", "eth0/T" or "eth0^") driven through PacketIo.
+///
+/// netmap-rs iterators borrow the whole descriptor, so rather than holding them open we re-create
+/// them per call: the ring cur pointer persists in the shared ring, so iteration resumes where it
+/// left off.
+pub struct NetmapIo {
+    pub descriptor: netmap::NetmapDescriptor,
+    rx_slot: NetmapRxSlot,
+    tx_slot: NetmapTxSlot,
+    // The ring currently being read / written.
+    rx_ring: usize,
+    tx_ring: usize,
+    // A received packet has been handed out but cur not yet advanced past it.
+    rx_claimed: bool,
+}
+
+impl NetmapIo {
+    pub fn new(name: &str) -> Result<NetmapIo, error::BrokenRail> {
+        Ok(NetmapIo::from_descriptor(try!(netmap::NetmapDescriptor::new(name))))
+    }
+
+    /// Open name in the same memory region as parent, so that buffers can be swapped between
+    /// their rings rather than copied.
+    pub fn new_with_memory(name: &str, parent: &NetmapIo) -> Result<NetmapIo, error::BrokenRail> {
+        let descriptor = try!(netmap::NetmapDescriptor::new_with_memory(name, &parent.descriptor));
+        Ok(NetmapIo::from_descriptor_in_region(descriptor, parent.rx_slot.region))
+    }
+
+    pub fn from_descriptor(descriptor: netmap::NetmapDescriptor) -> NetmapIo {
+        NetmapIo::from_descriptor_in_region(descriptor, NEXT_REGION.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn from_descriptor_in_region(descriptor: netmap::NetmapDescriptor, region: usize) -> NetmapIo {
+        NetmapIo {
+            descriptor: descriptor,
+            rx_slot: NetmapRxSlot {
+                slot: ptr::null_mut(),
+                region: region,
+            },
+            tx_slot: NetmapTxSlot {
+                slot: ptr::null_mut(),
+                region: region,
+            },
+            rx_ring: 0,
+            tx_ring: 0,
+            rx_claimed: false,
+        }
+    }
+
+    /// Advance cur past a claimed packet.
+    fn consume_claimed(&mut self) {
+        if self.rx_claimed {
+            if let Some(ring) = self.descriptor.rx_iter().nth(self.rx_ring) {
+                ring.iter().next();
+            }
+            self.rx_claimed = false;
+        }
+    }
+}
+
+impl PacketIo for NetmapIo {
+    fn rx_next(&mut self) -> Option<&mut [u8]> {
+        self.rx_next_slot().map(|(_, buf)| buf)
+    }
+
+    fn rx_next_slot(&mut self) -> Option<(Option<&mut RxSlot>, &mut [u8])> {
+        self.consume_claimed();
+        let start = self.rx_ring;
+        for (i, ring) in self.descriptor.rx_iter().enumerate().skip(start) {
+            let mut slots = ring.iter();
+            if let Some((slot, buf)) = slots.next() {
+                // Leave cur on this slot until the next call, so that give back is free.
+                slots.give_back();
+                self.rx_ring = i;
+                self.rx_claimed = true;
+                self.rx_slot.slot = slot;
+                return Some((Some(&mut self.rx_slot), buf));
+            }
+        }
+        None
+    }
+
+    fn rx_give_back(&mut self) {
+        self.rx_claimed = false;
+    }
+
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])> {
+        let start = self.tx_ring;
+        for (i, ring) in self.descriptor.tx_iter().enumerate().skip(start) {
+            if let Some((slot, buf)) = ring.iter_mut().next() {
+                self.tx_ring = i;
+                self.tx_slot.slot = slot;
+                return Some((&mut self.tx_slot, buf));
+            }
+        }
+        None
+    }
+
+    fn tx_give_back(&mut self) {
+        if let Some(ring) = self.descriptor.tx_iter().nth(self.tx_ring) {
+            ring.iter_mut().give_back();
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), error::BrokenRail> {
+        self.consume_claimed();
+        for ring in self.descriptor.rx_iter() {
+            ring.head_from_cur();
+        }
+        for ring in self.descriptor.tx_iter() {
+            ring.head_from_cur();
+        }
+        self.rx_ring = 0;
+        self.tx_ring = 0;
+        Ok(())
+    }
+
+    fn fd(&self) -> RawFd {
+        self.descriptor.get_fd()
+    }
+}
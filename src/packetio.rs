@@ -0,0 +1,177 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// Abstraction over the packet rings that the forwarding loop reads and writes.
+
+use std::collections::VecDeque;
+use std::os::unix::io::RawFd;
+
+use super::error;
+
+/// The slot of a claimed received packet, for backends whose buffers can be handed between
+/// rings without copying.
+pub trait RxSlot {
+    /// The memory region holding the slot's buffer. Buffers only move between slots in the same
+    /// region.
+    fn region(&self) -> usize;
+
+    /// The index of the slot's buffer within its region.
+    fn buf_idx(&self) -> u32;
+
+    /// Replace the slot's buffer, which has been given to a transmit slot.
+    fn set_buf_idx(&mut self, idx: u32);
+}
+
+/// A claimed transmit slot.
+pub trait TxSlot {
+    /// Set the length of the packet placed in the slot's buffer.
+    fn set_len(&mut self, len: usize);
+
+    /// Send the len bytes in rx's buffer by exchanging it with this slot's buffer, rather than
+    /// copying. Returns false, having changed nothing, if the buffers cannot be exchanged.
+    fn swap_buffer(&mut self, _rx: &mut RxSlot, _len: usize) -> bool {
+        false
+    }
+}
+
+/// A ring-like source and/or sink of ethernet frames.
+///
+/// Packets claimed with rx_next and tx_next between two calls to sync form a batch: received
+/// packets are released back to the backend, and transmit slots published for sending, when sync
+/// is called. This mirrors netmap's cur/head model, which other backends emulate.
+pub trait PacketIo {
+    /// Claim the next received packet, or None if there are no more packets ready.
+    fn rx_next(&mut self) -> Option<&mut [u8]>;
+
+    /// Claim the next received packet as rx_next does, along with its slot if the backend
+    /// supports swapping buffers into transmit slots.
+    fn rx_next_slot(&mut self) -> Option<(Option<&mut RxSlot>, &mut [u8])> {
+        self.rx_next().map(|buf| (None, buf))
+    }
+
+    /// Return the most recently claimed packet: the next call to rx_next will see it again.
+    fn rx_give_back(&mut self);
+
+    /// Claim the next free transmit slot and its buffer, or None if the transmit side is full.
+    ///
+    /// The buffer is the full capacity of the slot; set_len must be called on the slot once the
+    /// packet has been written into it.
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])>;
+
+    /// Return the most recently claimed transmit slot without setting its length, for a packet
+    /// that turned out not to fit: nothing is sent from it.
+    fn tx_give_back(&mut self);
+
+    /// Release consumed received packets and publish filled transmit slots.
+    fn sync(&mut self) -> Result<(), error::BrokenRail>;
+
+    /// A file descriptor to poll for readiness, or -1 if the backend is always ready.
+    fn fd(&self) -> RawFd;
+}
+
+pub struct MemoryTxSlot {
+    len: usize,
+}
+
+impl TxSlot for MemoryTxSlot {
+    fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
+}
+
+/// An in-memory backend, useful for exercising the forwarding logic without a kernel.
+///
+/// ```
+/// use rusty_rail::packetio::{MemoryIo, PacketIo};
+///
+/// let mut io = MemoryIo::new(1);
+/// io.rx.push_back(vec![1, 2, 3]);
+/// assert_eq!(io.rx_next().unwrap(), &[1, 2, 3]);
+/// io.rx_give_back();
+/// let len = io.rx_next().unwrap().len();
+/// {
+///     let (slot, buf) = io.tx_next().unwrap();
+///     buf[0] = 4;
+///     slot.set_len(len);
+/// }
+/// assert!(io.tx_next().is_none());
+/// io.sync().unwrap();
+/// assert!(io.rx_next().is_none());
+/// assert_eq!(io.tx, vec![vec![4, 0, 0]]);
+/// ```
+pub struct MemoryIo {
+    /// Packets waiting to be received.
+    pub rx: VecDeque<Vec<u8>>,
+    /// Packets that have been transmitted, in order.
+    pub tx: Vec<Vec<u8>>,
+    /// How many transmit slots are available in each batch.
+    pub tx_slots: usize,
+    rx_claimed: Option<Vec<u8>>,
+    tx_slot: MemoryTxSlot,
+    tx_buf: Vec<u8>,
+    tx_used: usize,
+}
+
+impl MemoryIo {
+    pub fn new(tx_slots: usize) -> MemoryIo {
+        MemoryIo {
+            rx: VecDeque::new(),
+            tx: vec![],
+            tx_slots: tx_slots,
+            rx_claimed: None,
+            tx_slot: MemoryTxSlot { len: 0 },
+            tx_buf: vec![0; 2048],
+            tx_used: 0,
+        }
+    }
+
+    fn flush_tx(&mut self) {
+        if self.tx_slot.len != 0 {
+            self.tx.push(self.tx_buf[0..self.tx_slot.len].to_vec());
+            self.tx_slot.len = 0;
+        }
+    }
+}
+
+impl PacketIo for MemoryIo {
+    fn rx_next(&mut self) -> Option<&mut [u8]> {
+        self.rx_claimed = self.rx.pop_front();
+        match self.rx_claimed {
+            Some(ref mut packet) => Some(&mut packet[..]),
+            None => None,
+        }
+    }
+
+    fn rx_give_back(&mut self) {
+        if let Some(packet) = self.rx_claimed.take() {
+            self.rx.push_front(packet);
+        }
+    }
+
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])> {
+        self.flush_tx();
+        if self.tx_used == self.tx_slots {
+            return None;
+        }
+        self.tx_used += 1;
+        for b in self.tx_buf.iter_mut() {
+            *b = 0;
+        }
+        Some((&mut self.tx_slot, &mut self.tx_buf[..]))
+    }
+
+    fn tx_give_back(&mut self) {
+        self.tx_slot.len = 0;
+        self.tx_used -= 1;
+    }
+
+    fn sync(&mut self) -> Result<(), error::BrokenRail> {
+        self.flush_tx();
+        self.rx_claimed = None;
+        self.tx_used = 0;
+        Ok(())
+    }
+
+    fn fd(&self) -> RawFd {
+        -1
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// PacketIo backends reading and writing pcap files, for replaying captured traffic through the
+// forwarding logic offline.
+//
+// A replay is one PcapReplay standing in for the wire RX ring, and PcapCaptures standing in for
+// the wire TX and host rings. They share a little state: the timestamp of the packet being
+// forwarded, so that output records carry their input's time, and a count of transmitted
+// packets, so that the replay can tell when a packet was dropped and record it.
+
+use std::cell::Cell;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+
+use super::error;
+use super::packetio::{PacketIo, TxSlot};
+
+const MAGIC: u32 = 0xa1b2c3d4;
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+const LINKTYPE_ETHERNET: u32 = 1;
+const SNAPLEN: usize = 65535;
+
+struct Shared {
+    // Seconds and micro (or nano) seconds of the current input packet.
+    timestamp: Cell<(u32, u32)>,
+    transmitted: Cell<u64>,
+}
+
+fn read_u32(buf: &[u8], swapped: bool) -> u32 {
+    let v = (buf[0] as u32) | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24;
+    if swapped { v.swap_bytes() } else { v }
+}
+
+fn le_u32(v: u32) -> [u8; 4] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+}
+
+/// Reads Ethernet frames from a pcap stream.
+pub struct PcapReader<R: Read> {
+    input: R,
+    swapped: bool,
+    pub nanos: bool,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut input: R) -> Result<PcapReader<R>, error::BrokenRail> {
+        let mut header = [0u8; 24];
+        try!(input.read_exact(&mut header));
+        let (swapped, nanos) = match read_u32(&header, false) {
+            MAGIC => (false, false),
+            MAGIC_NANOS => (false, true),
+            m if m.swap_bytes() == MAGIC => (true, false),
+            m if m.swap_bytes() == MAGIC_NANOS => (true, true),
+            _ => return Err(error::BrokenRail::Configuration("not a pcap file".to_string())),
+        };
+        if read_u32(&header[20..], swapped) & 0xffff != LINKTYPE_ETHERNET {
+            return Err(error::BrokenRail::Configuration("pcap is not ethernet".to_string()));
+        }
+        Ok(PcapReader {
+            input: input,
+            swapped: swapped,
+            nanos: nanos,
+        })
+    }
+
+    /// Read the next record into buf, returning its timestamp, or None at the end of the file.
+    pub fn next(&mut self, buf: &mut Vec<u8>) -> Result<Option<(u32, u32)>, error::BrokenRail> {
+        let mut header = [0u8; 16];
+        let mut got = 0;
+        while got < header.len() {
+            match try!(self.input.read(&mut header[got..])) {
+                0 if got == 0 => return Ok(None),
+                0 => return Err(error::BrokenRail::IO(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                                     "truncated pcap record"))),
+                n => got += n,
+            }
+        }
+        let len = read_u32(&header[8..], self.swapped) as usize;
+        if len > SNAPLEN {
+            return Err(error::BrokenRail::BadPacket);
+        }
+        buf.resize(len, 0);
+        try!(self.input.read_exact(&mut buf[..]));
+        Ok(Some((read_u32(&header, self.swapped), read_u32(&header[4..], self.swapped))))
+    }
+}
+
+/// Writes Ethernet frames to a pcap stream.
+pub struct PcapWriter<W: Write> {
+    output: W,
+    pub written: u64,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut output: W, nanos: bool) -> Result<PcapWriter<W>, error::BrokenRail> {
+        let mut header = vec![];
+        header.extend_from_slice(&le_u32(if nanos { MAGIC_NANOS } else { MAGIC }));
+        // Version 2.4, no timezone offset or accuracy.
+        header.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        header.extend_from_slice(&le_u32(SNAPLEN as u32));
+        header.extend_from_slice(&le_u32(LINKTYPE_ETHERNET));
+        try!(output.write_all(&header));
+        Ok(PcapWriter {
+            output: output,
+            written: 0,
+        })
+    }
+
+    pub fn write(&mut self, timestamp: (u32, u32), packet: &[u8]) -> Result<(), error::BrokenRail> {
+        let mut header = vec![];
+        header.extend_from_slice(&le_u32(timestamp.0));
+        header.extend_from_slice(&le_u32(timestamp.1));
+        header.extend_from_slice(&le_u32(packet.len() as u32));
+        header.extend_from_slice(&le_u32(packet.len() as u32));
+        try!(self.output.write_all(&header));
+        try!(self.output.write_all(packet));
+        self.written += 1;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+/// Replays a pcap as received packets, recording those that are not transmitted.
+pub struct PcapReplay<R: Read, W: Write> {
+    reader: PcapReader<R>,
+    shared: Rc<Shared>,
+    dropped: PcapWriter<W>,
+    // The packet handed out by rx_next, as handed out and as read.
+    packet: Vec<u8>,
+    original: Vec<u8>,
+    timestamp: (u32, u32),
+    // Whether packet is claimed, given back, or consumed.
+    claimed: bool,
+    given_back: bool,
+    transmitted_at_claim: u64,
+    error: Option<error::BrokenRail>,
+    pub read: u64,
+}
+
+impl<R: Read, W: Write> PcapReplay<R, W> {
+    /// Finish with the claimed packet, recording it if nothing was sent since it was claimed.
+    fn settle(&mut self) {
+        if !self.claimed {
+            return;
+        }
+        self.claimed = false;
+        if self.shared.transmitted.get() == self.transmitted_at_claim {
+            if let Err(err) = self.dropped.write(self.timestamp, &self.original) {
+                self.error = Some(err);
+            }
+        }
+    }
+
+    pub fn into_dropped(self) -> PcapWriter<W> {
+        self.dropped
+    }
+}
+
+impl<R: Read, W: Write> PacketIo for PcapReplay<R, W> {
+    fn rx_next(&mut self) -> Option<&mut [u8]> {
+        self.settle();
+        if self.error.is_some() {
+            return None;
+        }
+        if self.given_back {
+            self.given_back = false;
+        } else {
+            match self.reader.next(&mut self.original) {
+                Ok(Some(timestamp)) => {
+                    self.timestamp = timestamp;
+                    self.read += 1;
+                }
+                Ok(None) => return None,
+                Err(err) => {
+                    self.error = Some(err);
+                    return None;
+                }
+            }
+        }
+        self.packet.clear();
+        self.packet.extend_from_slice(&self.original);
+        self.shared.timestamp.set(self.timestamp);
+        self.transmitted_at_claim = self.shared.transmitted.get();
+        self.claimed = true;
+        Some(&mut self.packet[..])
+    }
+
+    fn rx_give_back(&mut self) {
+        self.claimed = false;
+        self.given_back = true;
+    }
+
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])> {
+        None
+    }
+
+    fn tx_give_back(&mut self) {}
+
+    fn sync(&mut self) -> Result<(), error::BrokenRail> {
+        self.settle();
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn fd(&self) -> RawFd {
+        -1
+    }
+}
+
+pub struct CaptureSlot {
+    len: usize,
+    shared: Rc<Shared>,
+}
+
+impl TxSlot for CaptureSlot {
+    fn set_len(&mut self, len: usize) {
+        self.len = len;
+        self.shared.transmitted.set(self.shared.transmitted.get() + 1);
+    }
+}
+
+/// Records transmitted packets to a pcap. Never receives anything.
+pub struct PcapCapture<W: Write> {
+    writer: PcapWriter<W>,
+    slot: CaptureSlot,
+    buf: Vec<u8>,
+    timestamp: (u32, u32),
+    error: Option<error::BrokenRail>,
+}
+
+impl<W: Write> PcapCapture<W> {
+    fn flush(&mut self) {
+        if self.slot.len != 0 {
+            if let Err(err) = self.writer.write(self.timestamp, &self.buf[..self.slot.len]) {
+                self.error = Some(err);
+            }
+            self.slot.len = 0;
+        }
+    }
+
+    pub fn into_writer(mut self) -> PcapWriter<W> {
+        self.flush();
+        self.writer
+    }
+}
+
+impl<W: Write> PacketIo for PcapCapture<W> {
+    fn rx_next(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn rx_give_back(&mut self) {}
+
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])> {
+        self.flush();
+        self.timestamp = self.slot.shared.timestamp.get();
+        Some((&mut self.slot, &mut self.buf[..]))
+    }
+
+    fn tx_give_back(&mut self) {
+        self.slot.len = 0;
+    }
+
+    fn sync(&mut self) -> Result<(), error::BrokenRail> {
+        self.flush();
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn fd(&self) -> RawFd {
+        -1
+    }
+}
+
+/// Set up a replay of input, returning the replay (to use as the wire RX side) and captures for
+/// the wire TX and host sides. Packets the forwarding logic drops are written to dropped.
+pub fn replay<R: Read, W: Write>(input: R,
+                                 wire: W,
+                                 host: W,
+                                 dropped: W)
+                                 -> Result<(PcapReplay<R, W>, PcapCapture<W>, PcapCapture<W>),
+                                           error::BrokenRail> {
+    let reader = try!(PcapReader::new(input));
+    let nanos = reader.nanos;
+    let shared = Rc::new(Shared {
+        timestamp: Cell::new((0, 0)),
+        transmitted: Cell::new(0),
+    });
+    let capture = |output: W| -> Result<PcapCapture<W>, error::BrokenRail> {
+        Ok(PcapCapture {
+            writer: try!(PcapWriter::new(output, nanos)),
+            slot: CaptureSlot {
+                len: 0,
+                shared: shared.clone(),
+            },
+            buf: vec![0; SNAPLEN],
+            timestamp: (0, 0),
+            error: None,
+        })
+    };
+    let wire = try!(capture(wire));
+    let host = try!(capture(host));
+    Ok((PcapReplay {
+        reader: reader,
+        shared: shared.clone(),
+        dropped: try!(PcapWriter::new(dropped, nanos)),
+        packet: vec![],
+        original: vec![],
+        timestamp: (0, 0),
+        claimed: false,
+        given_back: false,
+        transmitted_at_claim: 0,
+        error: None,
+        read: 0,
+    },
+        wire,
+        host))
+}
+
+#[test]
+fn replay_splits_by_direction() {
+    use std::net::Ipv4Addr;
+
+    let mut input = PcapWriter::new(vec![], false).unwrap();
+    input.write((1, 0), &[0u8; 60]).unwrap();
+    input.write((2, 0), &[1u8; 60]).unwrap();
+    let gre = ::tests::gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+    input.write((3, 0), &gre).unwrap();
+    let input = input.into_inner();
+    let (mut replay, mut wire, mut host) = replay(&input[..], vec![], vec![], vec![]).unwrap();
+    let mut n = 0;
+    loop {
+        match replay.rx_next() {
+            None => break,
+            Some(packet) => n += packet.len(),
+        }
+        // Pass the first through, drop the rest.
+        if replay.read == 1 {
+            let (slot, _) = host.tx_next().unwrap();
+            slot.set_len(60);
+        }
+    }
+    replay.sync().unwrap();
+    assert_eq!(n, 60 + 60 + gre.len());
+    assert_eq!(replay.read, 3);
+    assert_eq!(wire.tx_next().is_some(), true);
+    let host = host.into_writer();
+    assert_eq!(host.written, 1);
+    let host = host.into_inner();
+    let mut reader = PcapReader::new(&host[..]).unwrap();
+    let mut buf = vec![];
+    assert_eq!(reader.next(&mut buf).unwrap(), Some((1, 0)));
+    let dropped = replay.into_dropped().into_inner();
+    let mut reader = PcapReader::new(&dropped[..]).unwrap();
+    assert_eq!(reader.next(&mut buf).unwrap(), Some((2, 0)));
+    assert_eq!(buf, vec![1u8; 60]);
+    assert_eq!(reader.next(&mut buf).unwrap(), Some((3, 0)));
+    assert_eq!(buf, gre);
+    assert_eq!(reader.next(&mut buf).unwrap(), None);
+}
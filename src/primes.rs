@@ -10,6 +10,12 @@ fn examples() {
     assert_eq!(primes(3), vec![2, 3]);
     assert_eq!(primes(4), vec![2, 3]);
     assert_eq!(primes(5), vec![2, 3, 5]);
+    assert_eq!(next_prime(0), 2);
+    assert_eq!(next_prime(2), 2);
+    assert_eq!(next_prime(4), 5);
+    assert_eq!(next_prime(290), 293);
+    assert_eq!(next_prime(65537), 65537);
+    assert_eq!(next_prime(655370), 655373);
 }
 
 /// Returns all primes less than or equal to limit in a vector
@@ -33,3 +39,12 @@ pub fn primes(limit: usize) -> Vec<u32> {
     }
     res
 }
+
+/// Returns the smallest prime greater than or equal to n.
+pub fn next_prime(n: usize) -> u32 {
+    let mut candidate = if n < 2 { 2 } else { n };
+    while (2..).take_while(|d| d * d <= candidate).any(|d| candidate % d == 0) {
+        candidate += 1;
+    }
+    candidate as u32
+}
@@ -0,0 +1,231 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// PacketIo backend using a Linux TAP device.
+//
+// Frames the kernel transmits on the TAP interface are read from its file descriptor, and frames
+// written to the descriptor are received by the kernel on the interface. That makes the kernel
+// side of the TAP the wire: with the client and servers in network namespaces routed via the
+// TAP, the whole GRE path can be exercised on one machine without a netmap capable NIC.
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+use libc;
+
+use super::error;
+use super::packetio::{PacketIo, TxSlot};
+
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+// Large enough for any frame on a TAP with the maximum MTU.
+const FRAME_SIZE: usize = 65536;
+const TX_SLOTS: usize = 64;
+
+#[repr(C)]
+struct IfReq {
+    ifr_name: [u8; libc::IFNAMSIZ],
+    ifr_flags: libc::c_short,
+    // The rest of the union.
+    _pad: [u8; 22],
+}
+
+struct Fd(RawFd);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Attach to the TAP device, creating it if needed. The device is only usable by the rest of
+/// rusty rail (for addresses and neighbours) if it already exists, e.g. from
+/// ``ip tuntap add dev rr0 mode tap``.
+pub fn open(device: &str) -> Result<(TapRx, TapTx), error::BrokenRail> {
+    let mut req = IfReq {
+        ifr_name: [0; libc::IFNAMSIZ],
+        ifr_flags: IFF_TAP | IFF_NO_PI,
+        _pad: [0; 22],
+    };
+    if device.len() >= req.ifr_name.len() {
+        return Err(error::BrokenRail::Configuration(format!("bad device {}", device)));
+    }
+    req.ifr_name[..device.len()].copy_from_slice(device.as_bytes());
+    let path = b"/dev/net/tun\0";
+    let fd = unsafe {
+        libc::open(path.as_ptr() as *const libc::c_char,
+                   libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC)
+    };
+    if fd < 0 {
+        return Err(error::BrokenRail::IO(io::Error::last_os_error()));
+    }
+    let rx_fd = Fd(fd);
+    if unsafe { libc::ioctl(fd, TUNSETIFF, &mut req as *mut IfReq) } < 0 {
+        return Err(error::BrokenRail::IO(io::Error::last_os_error()));
+    }
+    // A second descriptor for the same queue, so that each side can be polled on its own.
+    let tx_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if tx_fd < 0 {
+        return Err(error::BrokenRail::IO(io::Error::last_os_error()));
+    }
+    Ok((TapRx {
+        fd: rx_fd,
+        buf: vec![0; FRAME_SIZE],
+        len: 0,
+        claimed: false,
+    },
+        TapTx {
+        fd: Fd(tx_fd),
+        bufs: vec![vec![0; FRAME_SIZE]; TX_SLOTS],
+        slots: vec![TapTxSlot { len: 0 }; TX_SLOTS],
+        queued: 0,
+    }))
+}
+
+/// Receive side of a TAP device. Frames are read one at a time into a single buffer.
+pub struct TapRx {
+    fd: Fd,
+    buf: Vec<u8>,
+    // Length of the frame in buf; 0 when buf holds nothing.
+    len: usize,
+    // The frame in buf has been handed out but not consumed.
+    claimed: bool,
+}
+
+impl PacketIo for TapRx {
+    fn rx_next(&mut self) -> Option<&mut [u8]> {
+        if self.claimed {
+            self.len = 0;
+            self.claimed = false;
+        }
+        if self.len == 0 {
+            let rv = unsafe {
+                libc::read(self.fd.0,
+                           self.buf.as_mut_ptr() as *mut libc::c_void,
+                           self.buf.len())
+            };
+            if rv <= 0 {
+                // Nothing waiting (EAGAIN), or the device went away; either way, nothing to do
+                // until poll says otherwise.
+                return None;
+            }
+            self.len = rv as usize;
+        }
+        self.claimed = true;
+        Some(&mut self.buf[..self.len])
+    }
+
+    fn rx_give_back(&mut self) {
+        self.claimed = false;
+    }
+
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])> {
+        None
+    }
+
+    fn tx_give_back(&mut self) {}
+
+    fn sync(&mut self) -> Result<(), error::BrokenRail> {
+        if self.claimed {
+            self.len = 0;
+            self.claimed = false;
+        }
+        Ok(())
+    }
+
+    fn fd(&self) -> RawFd {
+        self.fd.0
+    }
+}
+
+#[derive(Clone)]
+pub struct TapTxSlot {
+    len: usize,
+}
+
+impl TxSlot for TapTxSlot {
+    fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
+}
+
+/// Transmit side of a TAP device. Frames are queued and written on sync.
+pub struct TapTx {
+    fd: Fd,
+    bufs: Vec<Vec<u8>>,
+    slots: Vec<TapTxSlot>,
+    // Slots handed out since the last sync.
+    queued: usize,
+}
+
+impl PacketIo for TapTx {
+    fn rx_next(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn rx_give_back(&mut self) {}
+
+    fn tx_next(&mut self) -> Option<(&mut TxSlot, &mut [u8])> {
+        if self.queued == TX_SLOTS {
+            return None;
+        }
+        let slot = &mut self.slots[self.queued];
+        slot.len = 0;
+        self.queued += 1;
+        Some((slot, &mut self.bufs[self.queued - 1][..]))
+    }
+
+    fn tx_give_back(&mut self) {
+        self.queued -= 1;
+    }
+
+    fn sync(&mut self) -> Result<(), error::BrokenRail> {
+        for i in 0..self.queued {
+            let len = self.slots[i].len;
+            if len == 0 {
+                continue;
+            }
+            let rv = unsafe {
+                libc::write(self.fd.0,
+                            self.bufs[i].as_ptr() as *const libc::c_void,
+                            len)
+            };
+            if rv < 0 {
+                let err = io::Error::last_os_error();
+                // EIO means the interface is down, like a wire with no carrier; a full backlog
+                // drops rather than blocks, but be safe. Either way the frame is lost as it
+                // could be on a real wire.
+                if err.kind() != io::ErrorKind::WouldBlock && err.raw_os_error() != Some(libc::EIO) {
+                    return Err(error::BrokenRail::IO(err));
+                }
+            }
+        }
+        self.queued = 0;
+        Ok(())
+    }
+
+    fn fd(&self) -> RawFd {
+        self.fd.0
+    }
+}
+
+#[test]
+fn open_tap() {
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let (mut rx, mut tx) = open("rrtaptest0").unwrap();
+    assert!(rx.fd() >= 0);
+    assert!(tx.fd() != rx.fd());
+    // The interface is down: nothing arrives, and nothing is sent without an error.
+    assert!(rx.rx_next().is_none());
+    {
+        let (slot, _) = tx.tx_next().unwrap();
+        slot.set_len(60);
+    }
+    tx.sync().unwrap();
+    assert!(rx.rx_next().is_none());
+}
@@ -0,0 +1,514 @@
+// Copyright (c) 2017 Robert Collins. Licensed under the Apache-2.0 license.
+//
+// Forwarding entirely within XDP.
+//
+// The XDP program does the per packet work of examine_one and move_packets - GRE parsing, the
+// inner flow hash, the lookup table index and the outer header rewrite - and bounces the packet
+// back out with XDP_TX. Userspace keeps the control plane: it builds the ConsistentHash, resolves
+// backend MAC addresses, and publishes them along with the outer source addresses into maps:
+//
+// - config: one entry, the lookup table length and the outer source addresses. A length of 0
+//   means not yet configured, and GRE is passed to the kernel.
+// - lookup: the ConsistentHash lookup table.
+// - backends: by backend index, the target address and resolved MAC address.
+// - sources: outer source address overrides, by inner destination.
+//
+// Only IPv4 inner packets are forwarded: GRE carrying IPv6 is passed to the kernel.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+
+use pnet::util::MacAddr;
+
+use super::bpf;
+use super::bpf::{Asm, Insn, R0, R1, R2, R3, R4, R5, R6, R7, R8, R9, R10, B, DW, H, W};
+use super::bpf::{ADD, AND, JEQ, JGT, JNE, JSET, LSH, MOD, OR, RSH, XOR};
+use super::consistenthash::ConsistentHash;
+use super::error;
+
+/// Capacity of the lookup map.
+pub const MAX_LOOKUP: usize = 1 << 20;
+/// Capacity of the backends map.
+pub const MAX_BACKENDS: usize = 4096;
+/// Capacity of the sources map.
+pub const MAX_SOURCES: usize = 1024;
+
+// Config value layout: lookup table length, source IPv4 address in network order, source MAC
+// address, padding.
+const CONFIG_SIZE: usize = 16;
+const CONFIG_SOURCE: i16 = 4;
+const CONFIG_MAC: i16 = 8;
+
+// Stack slots, below the 4 byte map key at r10 - 4.
+const STACK_SOURCE: i16 = -8;
+const STACK_MAC: i16 = -16;
+
+// Backend value layout: IPv4 address in network order, MAC address, resolved flag, padding.
+const BACKEND_SIZE: usize = 12;
+const BACKEND_MAC: i16 = 4;
+const BACKEND_RESOLVED: i16 = 10;
+
+// Packet offsets (outer headers, without IPv4 options).
+const ETH_DST: i16 = 0;
+const ETH_SRC: i16 = 6;
+const ETH_TYPE: i16 = 12;
+const IP: i16 = 14;
+const IP_TTL: i16 = IP + 8;
+const IP_PROTO: i16 = IP + 9;
+const IP_CHECKSUM: i16 = IP + 10;
+const IP_SRC: i16 = IP + 12;
+const IP_DST: i16 = IP + 16;
+const INNER_DST: i16 = 16;
+const GRE: i16 = IP + 20;
+const GRE_PROTO: i16 = GRE + 2;
+
+/// rotate dst left by bits, using tmp as scratch.
+fn rotl(asm: &mut Asm, dst: u8, bits: i32, tmp: u8) {
+    asm.extend(&[bpf::mov64_reg(tmp, dst),
+                 bpf::alu64_imm(LSH, dst, bits),
+                 bpf::alu64_imm(RSH, tmp, 64 - bits),
+                 bpf::alu64_reg(OR, dst, tmp)]);
+}
+
+/// One SipHash round over v0-v3 in r0-r3, with r5 as scratch.
+fn sipround(asm: &mut Asm) {
+    asm.push(bpf::alu64_reg(ADD, R0, R1));
+    rotl(asm, R1, 13, R5);
+    asm.push(bpf::alu64_reg(XOR, R1, R0));
+    rotl(asm, R0, 32, R5);
+    asm.push(bpf::alu64_reg(ADD, R2, R3));
+    rotl(asm, R3, 16, R5);
+    asm.push(bpf::alu64_reg(XOR, R3, R2));
+    asm.push(bpf::alu64_reg(ADD, R0, R3));
+    rotl(asm, R3, 21, R5);
+    asm.push(bpf::alu64_reg(XOR, R3, R0));
+    asm.push(bpf::alu64_reg(ADD, R2, R1));
+    rotl(asm, R1, 17, R5);
+    asm.push(bpf::alu64_reg(XOR, R1, R2));
+    rotl(asm, R2, 32, R5);
+}
+
+/// Absorb the message word in r4.
+fn sipcompress(asm: &mut Asm, rounds: usize) {
+    asm.push(bpf::alu64_reg(XOR, R3, R4));
+    for _ in 0..rounds {
+        sipround(asm);
+    }
+    asm.push(bpf::alu64_reg(XOR, R0, R4));
+}
+
+/// SipHash-2-4 of the 9 bytes hash_ipv4_packet hashes for a 3-tuple - source, destination,
+/// protocol - from the IPv4 header at r8, leaving the result in r0. Clobbers r1-r5.
+fn hash_ipv4(asm: &mut Asm, k0: u64, k1: u64) {
+    asm.extend(&bpf::ld_imm64(R0, 0x736f6d6570736575 ^ k0));
+    asm.extend(&bpf::ld_imm64(R1, 0x646f72616e646f6d ^ k1));
+    asm.extend(&bpf::ld_imm64(R2, 0x6c7967656e657261 ^ k0));
+    asm.extend(&bpf::ld_imm64(R3, 0x7465646279746573 ^ k1));
+    // Source and destination are adjacent: together they are the first word.
+    asm.extend(&[bpf::ldx(DW, R4, R8, 12), bpf::le(R4, 64)]);
+    sipcompress(asm, 2);
+    // The final word holds the remaining byte and the total length.
+    asm.push(bpf::ldx(B, R4, R8, 9));
+    asm.extend(&bpf::ld_imm64(R5, 9 << 56));
+    asm.push(bpf::alu64_reg(OR, R4, R5));
+    sipcompress(asm, 2);
+    asm.push(bpf::alu64_imm(XOR, R2, 0xff));
+    for _ in 0..4 {
+        sipround(asm);
+    }
+    asm.extend(&[bpf::alu64_reg(XOR, R0, R1),
+                 bpf::alu64_reg(XOR, R0, R2),
+                 bpf::alu64_reg(XOR, R0, R3)]);
+}
+
+/// r0 = map[*(u32 *)(r10 - 4)], jumping to missing if there is no such entry.
+fn map_lookup(asm: &mut Asm, map: &bpf::Map, missing: &'static str) {
+    asm.extend(&[bpf::mov64_reg(R2, R10), bpf::add64_imm(R2, -4)]);
+    asm.extend(&bpf::ld_map_fd(R1, map.fd.0));
+    asm.push(bpf::call(bpf::BPF_FUNC_MAP_LOOKUP_ELEM));
+    asm.jump(bpf::jmp_imm(JEQ, R0, 0, 0), missing);
+}
+
+/// Add the change of the 16 bit word at old from r6 to the word at new from base to the one's
+/// complement sum in r8, as checksum::adjust does. The sum is order independent, so the words are
+/// used as loaded.
+fn adjust_checksum(asm: &mut Asm, old: i16, base: u8, new: i16) {
+    asm.extend(&[bpf::ldx(H, R1, R6, old),
+                 bpf::alu64_imm(XOR, R1, 0xffff),
+                 bpf::alu64_reg(ADD, R8, R1),
+                 bpf::ldx(H, R1, base, new),
+                 bpf::alu64_reg(ADD, R8, R1)]);
+}
+
+/// The forwarding program.
+///
+/// k0 and k1 are the flow hash key.
+pub fn forwarding_program(config: &bpf::Map,
+                          lookup: &bpf::Map,
+                          backends: &bpf::Map,
+                          sources: &bpf::Map,
+                          k0: u64,
+                          k1: u64)
+                          -> Vec<Insn> {
+    let mut asm = Asm::new();
+    // r9: ctx, r6: data, r7: data_end.
+    asm.extend(&[bpf::mov64_reg(R9, R1),
+                 bpf::ldx(W, R6, R9, bpf::XDP_MD_DATA),
+                 bpf::ldx(W, R7, R9, bpf::XDP_MD_DATA_END),
+                 bpf::mov64_reg(R5, R6),
+                 bpf::add64_imm(R5, GRE as i32 + 4)]);
+    asm.jump(bpf::jmp_reg(JGT, R5, R7, 0), "pass");
+    // Anything but GRE in IPv4 belongs to the host. IPv4 options are left to the kernel too.
+    asm.push(bpf::ldx(H, R1, R6, ETH_TYPE));
+    asm.jump(bpf::jmp_imm(JNE, R1, 0x0800u16.to_be() as i32, 0), "pass");
+    asm.push(bpf::ldx(B, R1, R6, IP));
+    asm.jump(bpf::jmp_imm(JNE, R1, 0x45, 0), "pass");
+    asm.push(bpf::ldx(B, R1, R6, IP_PROTO));
+    asm.jump(bpf::jmp_imm(JNE, R1, 47, 0), "pass");
+    // Other GRE payloads are noise, as in examine_one.
+    asm.push(bpf::ldx(H, R1, R6, GRE_PROTO));
+    asm.jump(bpf::jmp_imm(JEQ, R1, 0x86DDu16.to_be() as i32, 0), "pass");
+    asm.jump(bpf::jmp_imm(JNE, R1, 0x0800u16.to_be() as i32, 0), "drop");
+    // Skip the optional key and sequence fields. Checksums are not verified here, so checksummed
+    // GRE goes to the kernel rather than being forwarded unchecked, as do source routes.
+    asm.push(bpf::ldx(B, R1, R6, GRE));
+    asm.jump(bpf::jmp_imm(JSET, R1, 0x80 | 0x40, 0), "pass");
+    asm.extend(&[bpf::mov64_reg(R3, R1),
+                 bpf::alu64_imm(RSH, R3, 7),
+                 bpf::mov64_reg(R4, R1),
+                 bpf::alu64_imm(RSH, R4, 5),
+                 bpf::alu64_imm(AND, R4, 1),
+                 bpf::alu64_reg(ADD, R3, R4),
+                 bpf::mov64_reg(R4, R1),
+                 bpf::alu64_imm(RSH, R4, 4),
+                 bpf::alu64_imm(AND, R4, 1),
+                 bpf::alu64_reg(ADD, R3, R4),
+                 bpf::alu64_imm(LSH, R3, 2),
+                 // r8: the inner IPv4 header, which must be complete.
+                 bpf::mov64_reg(R8, R6),
+                 bpf::alu64_reg(ADD, R8, R3),
+                 bpf::add64_imm(R8, GRE as i32 + 4),
+                 bpf::mov64_reg(R5, R8),
+                 bpf::add64_imm(R5, 20)]);
+    asm.jump(bpf::jmp_reg(JGT, R5, R7, 0), "drop");
+
+    // r7: the flow hash, then the lookup table index.
+    hash_ipv4(&mut asm, k0, k1);
+    asm.extend(&[bpf::mov64_reg(R7, R0), bpf::st_imm(W, R10, -4, 0)]);
+    map_lookup(&mut asm, config, "pass");
+    asm.push(bpf::ldx(W, R1, R0, 0));
+    asm.jump(bpf::jmp_imm(JEQ, R1, 0, 0), "pass");
+    // The outer source: the interface, unless the inner destination has its own.
+    asm.extend(&[bpf::alu64_reg(MOD, R7, R1),
+                 bpf::ldx(W, R1, R0, CONFIG_SOURCE),
+                 bpf::stx(W, R10, STACK_SOURCE, R1),
+                 bpf::ldx(W, R1, R0, CONFIG_MAC),
+                 bpf::stx(W, R10, STACK_MAC, R1),
+                 bpf::ldx(H, R1, R0, CONFIG_MAC + 4),
+                 bpf::stx(H, R10, STACK_MAC + 4, R1),
+                 bpf::ldx(W, R1, R8, INNER_DST),
+                 bpf::stx(W, R10, -4, R1)]);
+    map_lookup(&mut asm, sources, "interface_source");
+    asm.extend(&[bpf::ldx(W, R1, R0, 0), bpf::stx(W, R10, STACK_SOURCE, R1)]);
+    asm.label("interface_source");
+    asm.push(bpf::stx(W, R10, -4, R7));
+    map_lookup(&mut asm, lookup, "drop");
+    asm.extend(&[bpf::ldx(W, R1, R0, 0), bpf::stx(W, R10, -4, R1)]);
+    map_lookup(&mut asm, backends, "drop");
+    // Without a MAC address for the backend there is nowhere to send it.
+    asm.push(bpf::ldx(B, R1, R0, BACKEND_RESOLVED));
+    asm.jump(bpf::jmp_imm(JEQ, R1, 0, 0), "drop");
+    // Expiring packets are dropped, as in move_packets; ICMP errors are left to userspace.
+    asm.extend(&[bpf::ldx(B, R1, R6, IP_TTL), bpf::mov64_imm(R2, 2)]);
+    asm.jump(bpf::jmp_reg(JGT, R2, R1, 0), "drop");
+
+    // r8: the outer checksum, adjusted for the address and TTL changes before they are made.
+    asm.extend(&[bpf::ldx(H, R8, R6, IP_CHECKSUM), bpf::alu64_imm(XOR, R8, 0xffff)]);
+    adjust_checksum(&mut asm, IP_SRC, R10, STACK_SOURCE);
+    adjust_checksum(&mut asm, IP_SRC + 2, R10, STACK_SOURCE + 2);
+    adjust_checksum(&mut asm, IP_DST, R0, 0);
+    adjust_checksum(&mut asm, IP_DST + 2, R0, 2);
+    // The TTL shares its word with the protocol, and is decremented in place.
+    asm.extend(&[bpf::ldx(H, R1, R6, IP_TTL),
+                 bpf::mov64_reg(R2, R1),
+                 bpf::alu64_imm(XOR, R1, 0xffff),
+                 bpf::alu64_reg(ADD, R8, R1),
+                 bpf::add64_imm(R2, -(0x0100u16.to_be() as i32)),
+                 bpf::alu64_reg(ADD, R8, R2),
+                 bpf::stx(H, R6, IP_TTL, R2)]);
+    for _ in 0..2 {
+        asm.extend(&[bpf::mov64_reg(R1, R8),
+                     bpf::alu64_imm(RSH, R1, 16),
+                     bpf::alu64_imm(AND, R8, 0xffff),
+                     bpf::alu64_reg(ADD, R8, R1)]);
+    }
+    asm.extend(&[bpf::alu64_imm(XOR, R8, 0xffff), bpf::stx(H, R6, IP_CHECKSUM, R8)]);
+
+    // Rewrite the outer headers as move_packets does: we received it, now we're sending it.
+    asm.extend(&[bpf::ldx(W, R1, R10, STACK_MAC),
+                 bpf::stx(W, R6, ETH_SRC, R1),
+                 bpf::ldx(H, R1, R10, STACK_MAC + 4),
+                 bpf::stx(H, R6, ETH_SRC + 4, R1),
+                 bpf::ldx(W, R1, R0, BACKEND_MAC),
+                 bpf::stx(W, R6, ETH_DST, R1),
+                 bpf::ldx(H, R1, R0, BACKEND_MAC + 4),
+                 bpf::stx(H, R6, ETH_DST + 4, R1),
+                 bpf::ldx(W, R1, R10, STACK_SOURCE),
+                 bpf::stx(W, R6, IP_SRC, R1),
+                 bpf::ldx(W, R1, R0, 0),
+                 bpf::stx(W, R6, IP_DST, R1),
+                 bpf::mov64_imm(R0, bpf::XDP_TX as i32),
+                 bpf::exit()]);
+    asm.label("drop");
+    asm.extend(&[bpf::mov64_imm(R0, bpf::XDP_DROP as i32), bpf::exit()]);
+    asm.label("pass");
+    asm.extend(&[bpf::mov64_imm(R0, bpf::XDP_PASS as i32), bpf::exit()]);
+    asm.finish()
+}
+
+/// The loaded forwarding program and its maps.
+pub struct XdpForwarder {
+    pub program: bpf::Program,
+    config: bpf::Map,
+    lookup: bpf::Map,
+    backends: bpf::Map,
+    sources: bpf::Map,
+    link: Option<bpf::Fd>,
+    // What has been written to the maps, so that republishing only writes changes.
+    published_lookup: Vec<u32>,
+    published_backends: Vec<[u8; BACKEND_SIZE]>,
+    published_sources: BTreeMap<Ipv4Addr, Ipv4Addr>,
+}
+
+impl XdpForwarder {
+    /// Create the maps and load the program. Nothing is forwarded until attach and publish have
+    /// been called.
+    pub fn new(k0: u64, k1: u64) -> Result<XdpForwarder, error::BrokenRail> {
+        let config = try!(bpf::Map::new(bpf::BPF_MAP_TYPE_ARRAY, 4, CONFIG_SIZE, 1));
+        let lookup = try!(bpf::Map::new(bpf::BPF_MAP_TYPE_ARRAY, 4, 4, MAX_LOOKUP as u32));
+        let backends = try!(bpf::Map::new(bpf::BPF_MAP_TYPE_ARRAY,
+                                          4,
+                                          BACKEND_SIZE,
+                                          MAX_BACKENDS as u32));
+        let sources = try!(bpf::Map::new(bpf::BPF_MAP_TYPE_HASH, 4, 4, MAX_SOURCES as u32));
+        let program = try!(bpf::Program::load_xdp("rr_forward",
+                                                  &forwarding_program(&config,
+                                                                      &lookup,
+                                                                      &backends,
+                                                                      &sources,
+                                                                      k0,
+                                                                      k1)));
+        Ok(XdpForwarder {
+            program: program,
+            config: config,
+            lookup: lookup,
+            backends: backends,
+            sources: sources,
+            link: None,
+            published_lookup: vec![],
+            published_backends: vec![],
+            published_sources: BTreeMap::new(),
+        })
+    }
+
+    /// Attach to an interface until dropped.
+    pub fn attach(&mut self, ifindex: u32, flags: u32) -> Result<(), error::BrokenRail> {
+        self.link = Some(try!(self.program.attach(ifindex, flags)));
+        Ok(())
+    }
+
+    /// Push the lookup table, backend details and outer source addresses to the kernel.
+    ///
+    /// source_ipv4 and source_mac are the egress interface's; vip_sources overrides source_ipv4
+    /// by inner destination, and can only grow between calls. IPv6 VIPs, sources and backends are
+    /// skipped: the program passes IPv6 to the kernel. resolve maps backend addresses to
+    /// MAC addresses; packets for unresolved backends are dropped, as move_packets does.
+    pub fn publish(&mut self,
+                   routes: &ConsistentHash,
+                   source_ipv4: &Ipv4Addr,
+                   source_mac: &MacAddr,
+                   vip_sources: &BTreeMap<IpAddr, IpAddr>,
+                   resolve: &mut FnMut(&IpAddr) -> Option<MacAddr>)
+                   -> Result<(), error::BrokenRail> {
+        if vip_sources.len() > MAX_SOURCES {
+            let msg = format!("too many VIP sources for XDP: {}", vip_sources.len());
+            return Err(error::BrokenRail::Configuration(msg));
+        }
+        for (vip, source) in vip_sources {
+            let (vip, source) = match (*vip, *source) {
+                (IpAddr::V4(vip), IpAddr::V4(source)) => (vip, source),
+                _ => continue,
+            };
+            if self.published_sources.get(&vip) == Some(&source) {
+                continue;
+            }
+            try!(self.sources.update(&vip.octets(), &source.octets()));
+            self.published_sources.insert(vip, source);
+        }
+        if routes.lookup().len() > MAX_LOOKUP || routes.backends().len() > MAX_BACKENDS {
+            return Err(error::BrokenRail::Configuration(format!("too many backends or lookup \
+                                                                 entries for XDP: {} {}",
+                                                                routes.backends().len(),
+                                                                routes.lookup().len())));
+        }
+        for (i, backend) in routes.backends().iter().enumerate() {
+            let mut value = [0u8; BACKEND_SIZE];
+            // Free slots are left unresolved, though nothing looks them up.
+            if let Some(IpAddr::V4(target)) = backend.as_ref().map(|b| b.target()) {
+                value[0..4].copy_from_slice(&target.octets());
+                if let Some(mac) = resolve(&IpAddr::V4(target)) {
+                    value[4..10].copy_from_slice(&[mac.0, mac.1, mac.2, mac.3, mac.4, mac.5]);
+                    value[BACKEND_RESOLVED as usize] = 1;
+                }
+            }
+            if self.published_backends.get(i) == Some(&value) {
+                continue;
+            }
+            try!(self.backends.update(&(i as u32).to_ne_bytes(), &value));
+            if i < self.published_backends.len() {
+                self.published_backends[i] = value;
+            } else {
+                self.published_backends.push(value);
+            }
+        }
+        // Entries first, then the length, so the program never indexes unwritten entries.
+        for (i, backend_idx) in routes.lookup().iter().enumerate() {
+            if self.published_lookup.get(i) == Some(backend_idx) {
+                continue;
+            }
+            try!(self.lookup.update(&(i as u32).to_ne_bytes(), &backend_idx.to_ne_bytes()));
+            if i < self.published_lookup.len() {
+                self.published_lookup[i] = *backend_idx;
+            } else {
+                self.published_lookup.push(*backend_idx);
+            }
+        }
+        let mut value = [0u8; CONFIG_SIZE];
+        value[0..4].copy_from_slice(&(routes.lookup().len() as u32).to_ne_bytes());
+        value[4..8].copy_from_slice(&source_ipv4.octets());
+        value[8..14].copy_from_slice(&[source_mac.0,
+                                       source_mac.1,
+                                       source_mac.2,
+                                       source_mac.3,
+                                       source_mac.4,
+                                       source_mac.5]);
+        self.config.update(&0u32.to_ne_bytes(), &value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use libc;
+    use pnet::packet::Packet;
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::packet::gre::GrePacket;
+    use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+    use pnet::util::MacAddr;
+
+    use bpf;
+    use checksum;
+    use consistenthash::{Backend, ConsistentHash};
+    use select_destination;
+    use super::XdpForwarder;
+
+    fn routes() -> ConsistentHash {
+        let mut routes = ConsistentHash::new();
+        for i in 1..4 {
+            routes.add_backend(Backend::new(&format!("server-{}", i),
+                                            Ipv4Addr::new(192, 0, 2, 10 + i)))
+                .unwrap();
+        }
+        routes.populate();
+        routes
+    }
+
+    fn mac_for(addr: &IpAddr) -> MacAddr {
+        match *addr {
+            IpAddr::V4(addr) => MacAddr::new(2, 0, 0, 0, 0, addr.octets()[3]),
+            IpAddr::V6(_) => panic!("IPv6 backend"),
+        }
+    }
+
+    fn publish(forwarder: &mut XdpForwarder,
+               routes: &ConsistentHash,
+               resolve: &mut FnMut(&IpAddr) -> Option<MacAddr>) {
+        let mut vip_sources = BTreeMap::new();
+        vip_sources.insert(IpAddr::V4(Ipv4Addr::new(10, 1, 0, 2)),
+                           IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7)));
+        forwarder.publish(routes,
+                          &Ipv4Addr::new(192, 0, 2, 1),
+                          &MacAddr::new(2, 0, 0, 0, 0, 1),
+                          &vip_sources,
+                          resolve)
+            .unwrap();
+    }
+
+    #[test]
+    fn forwards_like_userspace() {
+        // Loading programs needs privileges.
+        if unsafe { libc::geteuid() } != 0 {
+            println!("skipping forwards_like_userspace: loading XDP programs needs root");
+            return;
+        }
+        let routes = routes();
+        let key = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+        let mut forwarder = XdpForwarder::new(key.0, key.1).unwrap();
+        let frame = ::tests::gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        // Not yet configured: leave it to the kernel.
+        assert_eq!(forwarder.program.test_run(&frame).unwrap().0, bpf::XDP_PASS);
+        publish(&mut forwarder, &routes, &mut |addr| Some(mac_for(addr)));
+        for client in 1..50 {
+            let vip = Ipv4Addr::new(10, 1, 0, 1 + client % 2);
+            let frame = ::tests::gre_frame(Ipv4Addr::new(10, 0, 0, client), vip);
+            let (action, out) = forwarder.program.test_run(&frame).unwrap();
+            assert_eq!(action, bpf::XDP_TX);
+            let ether = EthernetPacket::new(&frame).unwrap();
+            let ip = Ipv4Packet::new(ether.payload()).unwrap();
+            let gre = GrePacket::new(ip.payload()).unwrap();
+            let inner = Ipv4Packet::new(gre.payload()).unwrap();
+            let expected = select_destination(&routes, key, &inner).unwrap();
+            let out_ether = EthernetPacket::new(&out).unwrap();
+            let out_ip = Ipv4Packet::new(out_ether.payload()).unwrap();
+            assert_eq!(out_ip.get_destination(), expected);
+            assert_eq!(out_ip.get_source(),
+                       if client % 2 == 1 {
+                           Ipv4Addr::new(198, 51, 100, 7)
+                       } else {
+                           Ipv4Addr::new(192, 0, 2, 1)
+                       });
+            assert_eq!(out_ether.get_destination(), mac_for(&expected));
+            assert_eq!(out_ether.get_source(), MacAddr::new(2, 0, 0, 0, 0, 1));
+            assert_eq!(out_ip.get_ttl(), ip.get_ttl() - 1);
+            assert_eq!(checksum::checksum(&out_ip.packet()[..20]), 0);
+        }
+        // Checksummed GRE is for the kernel, which can verify it.
+        let mut checksummed = frame.clone();
+        checksummed[14 + 20] |= 0x80;
+        assert_eq!(forwarder.program.test_run(&checksummed).unwrap().0, bpf::XDP_PASS);
+        let mut frame = frame;
+        MutableIpv4Packet::new(&mut frame[14..]).unwrap().set_ttl(1);
+        assert_eq!(forwarder.program.test_run(&frame).unwrap().0, bpf::XDP_DROP);
+    }
+
+    #[test]
+    fn drops_unresolved() {
+        if unsafe { libc::geteuid() } != 0 {
+            println!("skipping drops_unresolved: loading XDP programs needs root");
+            return;
+        }
+        let mut forwarder = XdpForwarder::new(0, 0).unwrap();
+        publish(&mut forwarder, &routes(), &mut |_| None);
+        let frame = ::tests::gre_frame(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        assert_eq!(forwarder.program.test_run(&frame).unwrap().0, bpf::XDP_DROP);
+        // Truncated GRE is noise; truncated outer headers are for the kernel to judge.
+        assert_eq!(forwarder.program.test_run(&frame[..40]).unwrap().0, bpf::XDP_DROP);
+        assert_eq!(forwarder.program.test_run(&frame[..30]).unwrap().0, bpf::XDP_PASS);
+        // IPv6 inside is for the kernel.
+        let mut frame = frame;
+        frame[14 + 20 + 2..14 + 20 + 4].copy_from_slice(&[0x86, 0xdd]);
+        assert_eq!(forwarder.program.test_run(&frame).unwrap().0, bpf::XDP_PASS);
+    }
+}